@@ -0,0 +1,751 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::error::AsmError;
+use crate::instruction::Instruction;
+
+const PROGRAM_START: usize = 0x200;
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+// bytes shown per listing line, longer data continues on the next lines
+const LISTING_BYTES: usize = 8;
+
+/*
+ * An assembled rom, loaded at 0x200, with a listing of every source
+ * line next to its address and bytes.
+ */
+pub struct Assembly {
+    pub rom: Vec<u8>,
+    listing: String
+}
+
+impl Assembly {
+    pub fn listing(&self) -> &str {
+        &self.listing
+    }
+}
+
+/*
+ * Assemble Cowgod style source, the dialect the disassembler writes:
+ *
+ *   SPEED := 2              ; constants
+ *   main:                   ; labels
+ *       LD V0, SPEED        ; instructions, see instruction.rs
+ *       JP main
+ *   ship:
+ *       sprite "..####.."   ; bitmaps, '#' or '1' for set pixels
+ *       db 0x3C, 0b01000010 ; bytes and 16 bit words with dw
+ *   include "font.asm"      ; more source, relative to this file
+ *   incbin "title.bin"      ; raw bytes
+ *
+ * `path` names the source in errors and is where includes are resolved from.
+ */
+pub fn assemble(source: &str, path: &Path) -> Result<Assembly, AsmError> {
+    let mut lines = Vec::new();
+    expand(source, Rc::new(path.to_path_buf()), 0, &mut lines)?;
+
+    let mut assembler = Assembler { symbols: HashMap::new() };
+    assembler.assemble(&lines)
+}
+
+/*
+ * A line of source once includes are expanded.
+ */
+struct Line {
+    file: Rc<PathBuf>,
+    number: usize,
+    text: String
+}
+
+impl Line {
+    fn error(&self, column: usize, message: impl Into<String>) -> AsmError {
+        AsmError {
+            file: self.file.display().to_string(),
+            line: self.number,
+            column,
+            message: message.into()
+        }
+    }
+
+    fn resolve(&self, name: &str) -> PathBuf {
+        match self.file.parent() {
+            Some(dir) => dir.join(name),
+            None => PathBuf::from(name),
+        }
+    }
+}
+
+/*
+ * A slice of a line that remembers its 1-based column, for errors.
+ */
+#[derive(Debug, Clone, Copy)]
+struct Span<'a> {
+    text: &'a str,
+    column: usize
+}
+
+impl<'a> Span<'a> {
+    fn trim(self) -> Span<'a> {
+        let start = self.text.len() - self.text.trim_start().len();
+        Span { text: self.text.trim(), column: self.column + start }
+    }
+
+    fn split_at(self, mid: usize) -> (Span<'a>, Span<'a>) {
+        let (left, right) = self.text.split_at(mid);
+        (Span { text: left, column: self.column }, Span { text: right, column: self.column + mid })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.text.is_empty()
+    }
+
+    /*
+     * The contents of a "quoted" string.
+     */
+    fn string(self, line: &Line) -> Result<Span<'a>, AsmError> {
+        match self.text.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+            Some(inner) => Ok(Span { text: inner, column: self.column + 1 }),
+            None => Err(line.error(self.column, "expected a quoted string")),
+        }
+    }
+}
+
+/*
+ * Everything before a ';' that isn't inside a string.
+ */
+fn strip_comment(text: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => return &text[..i],
+            _ => {}
+        }
+    }
+    text
+}
+
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+/*
+ * The rest of the span if it starts with the word `name`, e.g. the
+ * file of `include "file"`.
+ */
+fn directive<'a>(span: Span<'a>, name: &str) -> Option<Span<'a>> {
+    let (word, rest) = span.split_at(span.text.find(char::is_whitespace)?);
+    word.text.eq_ignore_ascii_case(name).then(|| rest.trim())
+}
+
+fn expand(source: &str, file: Rc<PathBuf>, depth: usize, lines: &mut Vec<Line>) -> Result<(), AsmError> {
+    for (i, text) in source.lines().enumerate() {
+        let line = Line { file: file.clone(), number: i + 1, text: text.to_string() };
+        let code = Span { text: strip_comment(text), column: 1 }.trim();
+
+        let Some(name) = directive(code, "include") else {
+            lines.push(line);
+            continue;
+        };
+
+        if depth >= MAX_INCLUDE_DEPTH {
+            return Err(line.error(code.column, "includes nested too deeply"));
+        }
+        let name = name.string(&line)?;
+        let path = line.resolve(name.text);
+        let source = fs::read_to_string(&path)
+            .map_err(|err| line.error(name.column, format!("could not read {}: {}", path.display(), err)))?;
+
+        expand(&source, Rc::new(path), depth + 1, lines)?;
+    }
+
+    Ok(())
+}
+
+enum Statement<'a> {
+    Empty,
+    Constant(Span<'a>, Span<'a>),
+    Op(Span<'a>, Vec<Span<'a>>)
+}
+
+/*
+ * Split a line into its optional label and statement.
+ */
+fn parse_line<'a>(line: &'a Line) -> Result<(Option<Span<'a>>, Statement<'a>), AsmError> {
+    let mut rest = Span { text: strip_comment(&line.text), column: 1 }.trim();
+
+    let mut label = None;
+    if let Some(colon) = rest.text.find(':') {
+        let (name, after) = rest.split_at(colon);
+        if !after.text.starts_with(":=") && is_identifier(name.text.trim()) {
+            label = Some(name.trim());
+            rest = after.split_at(1).1.trim();
+        }
+    }
+
+    if rest.is_empty() {
+        return Ok((label, Statement::Empty));
+    }
+
+    if let Some(assign) = rest.text.find(":=") {
+        let (name, value) = rest.split_at(assign);
+        let (name, value) = (name.trim(), value.split_at(2).1.trim());
+        if !is_identifier(name.text) {
+            return Err(line.error(name.column, format!("invalid constant name '{}'", name.text)));
+        }
+        if value.is_empty() {
+            return Err(line.error(value.column, "missing value"));
+        }
+        return Ok((label, Statement::Constant(name, value)));
+    }
+
+    let (mnemonic, operands) = match rest.text.find(char::is_whitespace) {
+        Some(end) => rest.split_at(end),
+        None => (rest, Span { text: "", column: rest.column + rest.text.len() }),
+    };
+
+    let mut spans = Vec::new();
+    let operands = operands.trim();
+    if !operands.is_empty() {
+        let mut start = 0;
+        let mut quoted = false;
+        for (i, c) in operands.text.char_indices().chain([(operands.text.len(), ',')]) {
+            match c {
+                '"' => quoted = !quoted,
+                ',' if !quoted => {
+                    let operand = Span { text: &operands.text[start..i], column: operands.column + start }.trim();
+                    if operand.is_empty() {
+                        return Err(line.error(operand.column, "missing operand"));
+                    }
+                    spans.push(operand);
+                    start = i + 1;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    Ok((label, Statement::Op(mnemonic, spans)))
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Operand<'a> {
+    V(usize),
+    I,
+    IndirectI,
+    Dt,
+    St,
+    K,
+    F,
+    Hf,
+    B,
+    R,
+    Long(Span<'a>),
+    Range(usize, usize),
+    Expr(Span<'a>)
+}
+
+fn register(s: &str) -> Option<usize> {
+    match s.as_bytes() {
+        [b'V' | b'v', digit] => (*digit as char).to_digit(16).map(|x| x as usize),
+        _ => None,
+    }
+}
+
+fn operand(span: Span) -> Operand {
+    if let Some(x) = register(span.text) {
+        return Operand::V(x);
+    }
+
+    match span.text.to_ascii_uppercase().as_str() {
+        "I" => return Operand::I,
+        "[I]" => return Operand::IndirectI,
+        "DT" => return Operand::Dt,
+        "ST" => return Operand::St,
+        "K" => return Operand::K,
+        "F" => return Operand::F,
+        "HF" => return Operand::Hf,
+        "B" => return Operand::B,
+        "R" => return Operand::R,
+        _ => {}
+    }
+
+    if let Some(value) = directive(span, "long") {
+        return Operand::Long(value);
+    }
+
+    // Vx - Vy for SAVE and LOAD
+    if let Some((x, y)) = span.text.split_once('-') {
+        if let (Some(x), Some(y)) = (register(x.trim()), register(y.trim())) {
+            return Operand::Range(x, y);
+        }
+    }
+
+    Operand::Expr(span)
+}
+
+struct Assembler {
+    symbols: HashMap<String, i64>
+}
+
+impl Assembler {
+
+    fn assemble(&mut self, lines: &[Line]) -> Result<Assembly, AsmError> {
+
+        // first pass, lay out every statement to learn the address of each label
+        let mut statements = Vec::new();
+        let mut addr = PROGRAM_START;
+
+        for line in lines {
+            let (label, statement) = parse_line(line)?;
+
+            if let Some(label) = label {
+                self.define(line, label, addr as i64)?;
+            }
+
+            let size = match &statement {
+                Statement::Empty => 0,
+                Statement::Constant(name, value) => {
+                    // constants can only refer to what's defined above them
+                    let value = self.eval(line, *value)?;
+                    self.define(line, *name, value)?;
+                    0
+                }
+                Statement::Op(mnemonic, operands) => self.size(line, *mnemonic, operands)?,
+            };
+
+            statements.push((line, addr, statement));
+            addr += size;
+        }
+
+        // second pass, every symbol is known
+        let mut rom = Vec::new();
+        let mut listing = String::new();
+
+        for (line, addr, statement) in statements {
+            let start = rom.len();
+            if let Statement::Op(mnemonic, operands) = statement {
+                self.emit(line, mnemonic, &operands, &mut rom)?;
+            }
+
+            let bytes = &rom[start..];
+            let mut chunks = bytes.chunks(LISTING_BYTES);
+            let first: String = chunks.next().unwrap_or_default().iter().map(|b| format!("{:02X}", b)).collect();
+            let _ = writeln!(listing, "{:04X}  {:<16}  {}", addr, first, line.text);
+            for (i, chunk) in chunks.enumerate() {
+                let hex: String = chunk.iter().map(|b| format!("{:02X}", b)).collect();
+                let _ = writeln!(listing, "{:04X}  {}", addr + (i + 1) * LISTING_BYTES, hex);
+            }
+        }
+
+        Ok(Assembly { rom, listing })
+    }
+
+    fn define(&mut self, line: &Line, name: Span, value: i64) -> Result<(), AsmError> {
+        if register(name.text).is_some() {
+            return Err(line.error(name.column, format!("'{}' is a register", name.text)));
+        }
+        if self.symbols.insert(name.text.to_string(), value).is_some() {
+            return Err(line.error(name.column, format!("'{}' is already defined", name.text)));
+        }
+        Ok(())
+    }
+
+    fn size(&self, line: &Line, mnemonic: Span, operands: &[Span]) -> Result<usize, AsmError> {
+        let size = match mnemonic.text.to_ascii_lowercase().as_str() {
+            "db" => operands.len(),
+            "dw" => operands.len() * 2,
+            "sprite" => sprite(line, operands, mnemonic)?.len(),
+            "incbin" => incbin(line, operands, mnemonic)?.len(),
+            _ => match operands {
+                [_, value] if matches!(operand(*value), Operand::Long(_)) => 4,
+                _ => 2,
+            },
+        };
+        Ok(size)
+    }
+
+    fn emit(&self, line: &Line, mnemonic: Span, operands: &[Span], rom: &mut Vec<u8>) -> Result<(), AsmError> {
+        match mnemonic.text.to_ascii_lowercase().as_str() {
+            "db" => {
+                for &value in operands {
+                    rom.push(self.byte(line, value)?);
+                }
+            }
+            "dw" => {
+                for &value in operands {
+                    let word = self.ranged(line, value, -0x8000, 0xffff)? as u16;
+                    rom.extend_from_slice(&word.to_be_bytes());
+                }
+            }
+            "sprite" => rom.extend(sprite(line, operands, mnemonic)?),
+            "incbin" => rom.extend(incbin(line, operands, mnemonic)?),
+            _ => {
+                let (instruction, long) = self.instruction(line, mnemonic, operands)?;
+                rom.extend_from_slice(&instruction.encode().to_be_bytes());
+                if let Some(long) = long {
+                    rom.extend_from_slice(&long.to_be_bytes());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn instruction(&self, line: &Line, mnemonic: Span, operands: &[Span]) -> Result<(Instruction, Option<u16>), AsmError> {
+        use Operand::*;
+
+        let ops: Vec<Operand> = operands.iter().map(|span| operand(*span)).collect();
+        let name = mnemonic.text.to_ascii_uppercase();
+
+        let instruction = match (name.as_str(), ops.as_slice()) {
+            ("CLS", []) => Instruction::Cls,
+            ("RET", []) => Instruction::Ret,
+            ("SYS", [Expr(e)]) => Instruction::Sys(self.addr(line, *e)?),
+            ("SCD", [Expr(e)]) => Instruction::ScrollDown(self.nibble(line, *e)?),
+            ("SCU", [Expr(e)]) => Instruction::ScrollUp(self.nibble(line, *e)?),
+            ("SCR", []) => Instruction::ScrollRight,
+            ("SCL", []) => Instruction::ScrollLeft,
+            ("EXIT", []) => Instruction::Exit,
+            ("LOW", []) => Instruction::Low,
+            ("HIGH", []) => Instruction::High,
+            ("JP", [Expr(e)]) => Instruction::Jp(self.addr(line, *e)?),
+            ("JP", [V(0), Expr(e)]) => Instruction::JpV0(self.addr(line, *e)?),
+            ("CALL", [Expr(e)]) => Instruction::Call(self.addr(line, *e)?),
+            ("SE", [V(x), V(y)]) => Instruction::SeReg(*x, *y),
+            ("SE", [V(x), Expr(e)]) => Instruction::SeByte(*x, self.byte(line, *e)?),
+            ("SNE", [V(x), V(y)]) => Instruction::SneReg(*x, *y),
+            ("SNE", [V(x), Expr(e)]) => Instruction::SneByte(*x, self.byte(line, *e)?),
+            ("SAVE", [Range(x, y)]) => Instruction::Save(*x, *y),
+            ("LOAD", [Range(x, y)]) => Instruction::Load(*x, *y),
+            ("LD", [V(x), V(y)]) => Instruction::LdReg(*x, *y),
+            ("LD", [V(x), Expr(e)]) => Instruction::LdByte(*x, self.byte(line, *e)?),
+            ("LD", [I, Expr(e)]) => Instruction::LdI(self.addr(line, *e)?),
+            ("LD", [I, Long(e)]) => {
+                let addr = self.ranged(line, *e, 0, 0xffff)? as u16;
+                return Ok((Instruction::LdILong, Some(addr)));
+            }
+            ("LD", [V(x), Dt]) => Instruction::LdVxDt(*x),
+            ("LD", [V(x), K]) => Instruction::LdVxK(*x),
+            ("LD", [Dt, V(x)]) => Instruction::LdDtVx(*x),
+            ("LD", [St, V(x)]) => Instruction::LdStVx(*x),
+            ("LD", [F, V(x)]) => Instruction::LdF(*x),
+            ("LD", [Hf, V(x)]) => Instruction::LdHf(*x),
+            ("LD", [B, V(x)]) => Instruction::LdB(*x),
+            ("LD", [IndirectI, V(x)]) => Instruction::LdIVx(*x),
+            ("LD", [V(x), IndirectI]) => Instruction::LdVxI(*x),
+            ("LD", [R, V(x)]) => Instruction::LdRVx(*x),
+            ("LD", [V(x), R]) => Instruction::LdVxR(*x),
+            ("ADD", [V(x), V(y)]) => Instruction::AddReg(*x, *y),
+            ("ADD", [V(x), Expr(e)]) => Instruction::AddByte(*x, self.byte(line, *e)?),
+            ("ADD", [I, V(x)]) => Instruction::AddI(*x),
+            ("OR", [V(x), V(y)]) => Instruction::Or(*x, *y),
+            ("AND", [V(x), V(y)]) => Instruction::And(*x, *y),
+            ("XOR", [V(x), V(y)]) => Instruction::Xor(*x, *y),
+            ("SUB", [V(x), V(y)]) => Instruction::Sub(*x, *y),
+            ("SUBN", [V(x), V(y)]) => Instruction::Subn(*x, *y),
+            ("SHR", [V(x)]) => Instruction::Shr(*x, *x),
+            ("SHR", [V(x), V(y)]) => Instruction::Shr(*x, *y),
+            ("SHL", [V(x)]) => Instruction::Shl(*x, *x),
+            ("SHL", [V(x), V(y)]) => Instruction::Shl(*x, *y),
+            ("RND", [V(x), Expr(e)]) => Instruction::Rnd(*x, self.byte(line, *e)?),
+            ("DRW", [V(x), V(y), Expr(e)]) => Instruction::Drw(*x, *y, self.nibble(line, *e)?),
+            ("SKP", [V(x)]) => Instruction::Skp(*x),
+            ("SKNP", [V(x)]) => Instruction::Sknp(*x),
+            ("PLANE", [Expr(e)]) => Instruction::Plane(self.ranged(line, *e, 0, 3)? as u8),
+            ("AUDIO", []) => Instruction::Audio,
+            ("PITCH", [V(x)]) => Instruction::Pitch(*x),
+            _ => {
+                let known = [
+                    "CLS", "RET", "SYS", "SCD", "SCU", "SCR", "SCL", "EXIT", "LOW", "HIGH", "JP", "CALL", "SE", "SNE",
+                    "SAVE", "LOAD", "LD", "ADD", "OR", "AND", "XOR", "SUB", "SUBN", "SHR", "SHL", "RND", "DRW", "SKP",
+                    "SKNP", "PLANE", "AUDIO", "PITCH",
+                ];
+                let message = if known.contains(&name.as_str()) {
+                    format!("invalid operands for {}", name)
+                } else {
+                    format!("unknown instruction '{}'", mnemonic.text)
+                };
+                return Err(line.error(mnemonic.column, message));
+            }
+        };
+
+        Ok((instruction, None))
+    }
+
+    fn byte(&self, line: &Line, span: Span) -> Result<u8, AsmError> {
+        // negative bytes wrap, so ADD V0, -1 decrements
+        Ok(self.ranged(line, span, -0x80, 0xff)? as u8)
+    }
+
+    fn nibble(&self, line: &Line, span: Span) -> Result<u8, AsmError> {
+        Ok(self.ranged(line, span, 0, 0xf)? as u8)
+    }
+
+    fn addr(&self, line: &Line, span: Span) -> Result<usize, AsmError> {
+        Ok(self.ranged(line, span, 0, 0xfff)? as usize)
+    }
+
+    fn ranged(&self, line: &Line, span: Span, min: i64, max: i64) -> Result<i64, AsmError> {
+        let value = self.eval(line, span)?;
+        if value < min || value > max {
+            return Err(line.error(span.column, format!("{} is out of range {}..={}", value, min, max)));
+        }
+        Ok(value)
+    }
+
+    /*
+     * Sums and differences of numbers and symbols, with parentheses.
+     */
+    fn eval(&self, line: &Line, span: Span) -> Result<i64, AsmError> {
+        let mut parser = ExprParser { line, span, pos: 0, symbols: &self.symbols };
+
+        let value = parser.sum()?;
+        parser.skip_spaces();
+        if parser.pos < span.text.len() {
+            return Err(line.error(span.column + parser.pos, format!("unexpected '{}'", &span.text[parser.pos..])));
+        }
+        Ok(value)
+    }
+}
+
+struct ExprParser<'a> {
+    line: &'a Line,
+    span: Span<'a>,
+    pos: usize,
+    symbols: &'a HashMap<String, i64>
+}
+
+impl<'a> ExprParser<'a> {
+
+    fn skip_spaces(&mut self) {
+        while let Some(c) = self.span.text[self.pos..].chars().next().filter(|c| c.is_whitespace()) {
+            self.pos += c.len_utf8();
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_spaces();
+        self.span.text[self.pos..].chars().next()
+    }
+
+    fn error(&self, message: String) -> AsmError {
+        self.line.error(self.span.column + self.pos, message)
+    }
+
+    fn sum(&mut self) -> Result<i64, AsmError> {
+        let mut value = self.term()?;
+        loop {
+            match self.peek() {
+                Some('+') => {
+                    self.pos += 1;
+                    value += self.term()?;
+                }
+                Some('-') => {
+                    self.pos += 1;
+                    value -= self.term()?;
+                }
+                _ => return Ok(value),
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<i64, AsmError> {
+        match self.peek() {
+            Some('-') => {
+                self.pos += 1;
+                Ok(-self.term()?)
+            }
+            Some('(') => {
+                self.pos += 1;
+                let value = self.sum()?;
+                if self.peek() != Some(')') {
+                    return Err(self.error(String::from("expected ')'")));
+                }
+                self.pos += 1;
+                Ok(value)
+            }
+            Some(c) if c.is_ascii_alphanumeric() || c == '_' => {
+                let rest = &self.span.text[self.pos..];
+                let len = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.')).unwrap_or(rest.len());
+                let word = &rest[..len];
+
+                let value = if c.is_ascii_digit() {
+                    parse_number(word).ok_or_else(|| self.error(format!("invalid number '{}'", word)))?
+                } else {
+                    *self.symbols.get(word).ok_or_else(|| self.error(format!("undefined symbol '{}'", word)))?
+                };
+                self.pos += len;
+                Ok(value)
+            }
+            Some(c) => Err(self.error(format!("unexpected '{}'", c))),
+            None => Err(self.error(String::from("missing value"))),
+        }
+    }
+}
+
+fn parse_number(word: &str) -> Option<i64> {
+    let lower = word.to_ascii_lowercase();
+    match (lower.strip_prefix("0x"), lower.strip_prefix("0b")) {
+        (Some(hex), _) => i64::from_str_radix(hex, 16).ok(),
+        (_, Some(bin)) => i64::from_str_radix(bin, 2).ok(),
+        _ => lower.parse().ok(),
+    }
+}
+
+/*
+ * A row of 8 or 16 pixels, '#', 'X' or '1' set a pixel and '.', '0'
+ * or ' ' leave it clear.
+ */
+fn sprite(line: &Line, operands: &[Span], mnemonic: Span) -> Result<Vec<u8>, AsmError> {
+    let [row] = operands else {
+        return Err(line.error(mnemonic.column, "sprite takes a single row of pixels"));
+    };
+    let row = row.string(line)?;
+
+    if row.text.len() != 8 && row.text.len() != 16 {
+        return Err(line.error(row.column, format!("sprite rows are 8 or 16 pixels, not {}", row.text.len())));
+    }
+
+    let mut bits: u16 = 0;
+    for (i, c) in row.text.chars().enumerate() {
+        let bit = match c {
+            '#' | 'X' | 'x' | '1' => 1,
+            '.' | '0' | ' ' => 0,
+            _ => return Err(line.error(row.column + i, format!("'{}' is not a pixel", c))),
+        };
+        bits = (bits << 1) | bit;
+    }
+
+    Ok(if row.text.len() == 8 { vec![bits as u8] } else { bits.to_be_bytes().to_vec() })
+}
+
+fn incbin(line: &Line, operands: &[Span], mnemonic: Span) -> Result<Vec<u8>, AsmError> {
+    let [name] = operands else {
+        return Err(line.error(mnemonic.column, "incbin takes a file name"));
+    };
+    let name = name.string(line)?;
+    let path = line.resolve(name.text);
+
+    fs::read(&path).map_err(|err| line.error(name.column, format!("could not read {}: {}", path.display(), err)))
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::disasm::disassemble;
+    use crate::instruction::Syntax;
+
+    fn asm(source: &str) -> Result<Vec<u8>, AsmError> {
+        assemble(source, Path::new("test.asm")).map(|assembly| assembly.rom)
+    }
+
+    fn error(source: &str) -> (usize, usize, String) {
+        let err = asm(source).unwrap_err();
+        (err.line, err.column, err.message)
+    }
+
+    #[test]
+    fn instructions() {
+        let source = "
+            CLS
+            LD VA, 0x02      ; comment
+            ld v1, va
+            LD I, 0x300
+            LD I, long 0x1234
+            DRW V0, V1, 15
+            SHR V3
+            SAVE V1 - V3
+            ADD V0, -1
+            LD [I], VF
+        ";
+        assert_eq!(asm(source), Ok(vec![
+            0x00, 0xe0, 0x6a, 0x02, 0x81, 0xa0, 0xa3, 0x00, 0xf0, 0x00, 0x12, 0x34,
+            0xd0, 0x1f, 0x83, 0x36, 0x51, 0x32, 0x70, 0xff, 0xff, 0x55,
+        ]));
+    }
+
+    #[test]
+    fn labels_and_constants() {
+        let source = "
+            SPEED := 2
+            LIMIT := SPEED + 0x10
+        main:
+            CALL sub
+            JP main
+        sub: LD V0, LIMIT - 1
+            LD I, ship
+            RET
+        ship:
+            sprite \"..####..\"
+            sprite \"#......#\"
+            db 0x3C, 0b01000010, SPEED
+            dw 0x1234
+        ";
+        assert_eq!(asm(source), Ok(vec![
+            0x22, 0x04, 0x12, 0x00, 0x60, 0x11, 0xa2, 0x0a, 0x00, 0xee,
+            0x3c, 0x81, 0x3c, 0x42, 0x02, 0x12, 0x34,
+        ]));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error("  FOO V1"), (1, 3, String::from("unknown instruction 'FOO'")));
+        assert_eq!(error("\nLD I, K"), (2, 1, String::from("invalid operands for LD")));
+        assert_eq!(error("LD V0, 0x100"), (1, 8, String::from("256 is out of range -128..=255")));
+        assert_eq!(error("JP nowhere"), (1, 4, String::from("undefined symbol 'nowhere'")));
+        assert_eq!(error("a:\na: CLS"), (2, 1, String::from("'a' is already defined")));
+        assert_eq!(error("X := Y\nY := 1"), (1, 6, String::from("undefined symbol 'Y'")));
+        assert_eq!(error("LD V0, 1 +"), (1, 11, String::from("missing value")));
+        assert_eq!(error("LD V0,, 1"), (1, 7, String::from("missing operand")));
+        assert_eq!(error("sprite \"..##..\""), (1, 9, String::from("sprite rows are 8 or 16 pixels, not 6")));
+        assert_eq!(error("sprite \"..##..?.\""), (1, 15, String::from("'?' is not a pixel")));
+        assert_eq!(error("DRW V0, V1, 16"), (1, 13, String::from("16 is out of range 0..=15")));
+        assert_eq!(error("include \"missing.asm\"").1, 10);
+    }
+
+    #[test]
+    fn unicode_operands() {
+        assert_eq!(asm("LD V0, 1\u{a0}+\u{3000}1"), Ok(vec![0x60, 0x02]));
+        assert_eq!(error("LD V0, 1 + \u{e9}"), (1, 12, String::from("unexpected '\u{e9}'")));
+    }
+
+    #[test]
+    fn include() {
+        let dir = std::env::temp_dir().join(format!("chip8-asm-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("sub.asm"), "sub:\n    RET\n").unwrap();
+        fs::write(dir.join("data.bin"), [0xaa, 0xbb]).unwrap();
+
+        let assembly = assemble("CALL sub\ninclude \"sub.asm\"\nincbin \"data.bin\"", &dir.join("main.asm"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(assembly.unwrap().rom, vec![0x22, 0x02, 0x00, 0xee, 0xaa, 0xbb]);
+    }
+
+    #[test]
+    fn listing() {
+        let assembly = assemble("start:\n    LD V0, 1\n    db 1, 2, 3, 4, 5, 6, 7, 8, 9", Path::new("test.asm")).unwrap();
+        let lines: Vec<&str> = assembly.listing().lines().map(str::trim_end).collect();
+
+        assert_eq!(lines, [
+            "0200                    start:",
+            "0200  6001                  LD V0, 1",
+            "0202  0102030405060708      db 1, 2, 3, 4, 5, 6, 7, 8, 9",
+            "020A  09",
+        ]);
+    }
+
+    #[test]
+    fn disassembly_round_trip() {
+        let rom = [0x22, 0x08, 0x30, 0x00, 0x12, 0x04, 0xff, 0xff, 0xa2, 0x0c, 0x00, 0xee, 0x3c, 0x42, 0xf0, 0x00, 0x02, 0x0c];
+        let source = disassemble(&rom, Syntax::Cowgod);
+
+        assert_eq!(asm(&source), Ok(rom.to_vec()));
+    }
+}
@@ -0,0 +1,144 @@
+/*
+ * Sample generation for the sound timer, kept free of SDL so the
+ * stream can be checked offline.
+ *
+ * Without a pattern the buzzer is a plain square wave. Once an XO-CHIP
+ * program loads a pattern with F002, its 128 bits are played back in a
+ * loop at a rate set by the pitch register (Fx3A).
+ */
+pub const PATTERN_SIZE: usize = 16;
+pub const DEFAULT_PITCH: u8 = 64;
+
+const PATTERN_BITS: f32 = (PATTERN_SIZE * 8) as f32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioPattern {
+    pub buffer: [u8; PATTERN_SIZE],
+    pub pitch: u8
+}
+
+impl AudioPattern {
+
+    /*
+     * Bits per second, 4000 * 2^((pitch - 64) / 48).
+     */
+    pub fn playback_rate(&self) -> f32 {
+        4000.0 * 2f32.powf((self.pitch as f32 - 64.0) / 48.0)
+    }
+
+    fn bit(&self, index: usize) -> bool {
+        (self.buffer[index / 8] >> (7 - index % 8)) & 1 == 1
+    }
+}
+
+pub struct Synth {
+    sample_rate: f32,
+    frequency: f32,
+    volume: f32,
+    pattern: Option<AudioPattern>,
+    phase: f32
+}
+
+impl Synth {
+
+    pub fn new(sample_rate: u32, frequency: f32, volume: f32) -> Self {
+        Synth {
+            sample_rate: sample_rate as f32,
+            frequency,
+            volume,
+            pattern: None,
+            phase: 0.0
+        }
+    }
+
+    pub fn set_pattern(&mut self, pattern: Option<AudioPattern>) {
+        if self.pattern.is_some() != pattern.is_some() {
+            self.phase = 0.0;
+        }
+        self.pattern = pattern;
+    }
+
+    pub fn fill(&mut self, out: &mut [f32]) {
+        for x in out.iter_mut() {
+            let high = match self.pattern {
+                Some(pattern) => {
+                    let high = pattern.bit(self.phase as usize);
+                    self.phase = (self.phase + pattern.playback_rate() / self.sample_rate) % PATTERN_BITS;
+                    high
+                }
+                None => {
+                    let high = self.phase < 0.5;
+                    self.phase = (self.phase + self.frequency / self.sample_rate) % 1.0;
+                    high
+                }
+            };
+
+            *x = if high { self.volume } else { -self.volume };
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn signs(samples: &[f32]) -> Vec<u8> {
+        samples.iter().map(|&s| if s > 0.0 { 1 } else { 0 }).collect()
+    }
+
+    #[test]
+    fn playback_rate() {
+        let mut pattern = AudioPattern { buffer: [0; PATTERN_SIZE], pitch: DEFAULT_PITCH };
+        assert_eq!(pattern.playback_rate(), 4000.0);
+
+        pattern.pitch = 64 + 48;
+        assert_eq!(pattern.playback_rate(), 8000.0);
+
+        pattern.pitch = 64 - 48;
+        assert_eq!(pattern.playback_rate(), 2000.0);
+    }
+
+    #[test]
+    fn square_tone() {
+        let mut synth = Synth::new(4000, 1000.0, 0.5);
+        let mut out = [0.0; 8];
+        synth.fill(&mut out);
+
+        assert_eq!(out, [0.5, 0.5, -0.5, -0.5, 0.5, 0.5, -0.5, -0.5]);
+    }
+
+    #[test]
+    fn pattern_one_sample_per_bit() {
+        let mut buffer = [0; PATTERN_SIZE];
+        buffer[0] = 0b1100_1010;
+        buffer[15] = 0b0000_0001;
+
+        let mut synth = Synth::new(4000, 440.0, 1.0);
+        synth.set_pattern(Some(AudioPattern { buffer, pitch: DEFAULT_PITCH }));
+
+        let mut out = [0.0; 130];
+        synth.fill(&mut out);
+        let bits = signs(&out);
+
+        assert_eq!(bits[0..8], [1, 1, 0, 0, 1, 0, 1, 0]);
+        assert!(bits[8..127].iter().all(|&b| b == 0));
+        assert_eq!(bits[127], 1);
+        // the pattern loops
+        assert_eq!(bits[128..130], [1, 1]);
+    }
+
+    #[test]
+    fn pattern_pitch_halves_rate() {
+        let mut buffer = [0; PATTERN_SIZE];
+        buffer[0] = 0b1010_0000;
+
+        let mut synth = Synth::new(4000, 440.0, 1.0);
+        synth.set_pattern(Some(AudioPattern { buffer, pitch: 64 - 48 }));
+
+        let mut out = [0.0; 8];
+        synth.fill(&mut out);
+
+        assert_eq!(signs(&out), [1, 1, 0, 0, 1, 1, 0, 0]);
+    }
+}
@@ -0,0 +1,121 @@
+use super::expr::Expr;
+use super::WatchKind;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DebugCommand {
+    Pause,
+    Continue,
+    Step,
+    StepOver,
+    StepOut,
+    Break(usize),
+    Delete(usize),
+    BreakIf(Expr),
+    DeleteCondition(usize),
+    Watch(usize, usize, WatchKind),
+    Unwatch(usize),
+    Breakpoints,
+    Registers,
+    Stack,
+    Memory(usize, usize),
+    Help
+}
+
+pub const HELP: &str = "\
+commands:
+  p, pause             pause execution
+  c, continue          continue execution
+  s, step              execute one instruction
+  n, next              step over a subroutine call
+  o, out               run until the current subroutine returns
+  b, break <addr>      set a breakpoint
+  d, delete <addr>     remove a breakpoint
+  b if <expr>          break when an expression becomes true, e.g. V3 == 0x10 && I > 0x300
+  dc <n>               remove condition n
+  watch <addr> [len]   break after memory is written
+  rwatch <addr> [len]  break after memory is read
+  awatch <addr> [len]  break after memory is read or written
+  unwatch <addr>       remove the watchpoints starting at addr
+  bl, breakpoints      list breakpoints, conditions and watchpoints
+  r, regs              show registers and timers
+  bt, stack            show the call stack
+  x <addr> [len]       dump memory
+  h, help              show this help";
+
+impl DebugCommand {
+
+    /*
+     * Parse a line typed at the debugger prompt. Addresses are hex,
+     * with or without a 0x prefix.
+     */
+    pub fn parse(line: &str) -> Result<DebugCommand, String> {
+        let mut words = line.split_whitespace();
+        let name = words.next().ok_or_else(|| "empty command".to_string())?;
+        let args: Vec<&str> = words.collect();
+
+        let command = match (name, args.as_slice()) {
+            ("p" | "pause", []) => DebugCommand::Pause,
+            ("c" | "continue", []) => DebugCommand::Continue,
+            ("s" | "step", []) => DebugCommand::Step,
+            ("n" | "next", []) => DebugCommand::StepOver,
+            ("o" | "out", []) => DebugCommand::StepOut,
+            ("b" | "break", ["if", expr @ ..]) => DebugCommand::BreakIf(Expr::parse(&expr.join(" "))?),
+            ("b" | "break", [addr]) => DebugCommand::Break(parse_addr(addr)?),
+            ("d" | "delete", [addr]) => DebugCommand::Delete(parse_addr(addr)?),
+            ("dc", [n]) => DebugCommand::DeleteCondition(n.parse().map_err(|_| format!("invalid condition number '{}'", n))?),
+            ("watch" | "rwatch" | "awatch", [addr, len @ ..]) if len.len() <= 1 => {
+                let kind = match name {
+                    "watch" => WatchKind::Write,
+                    "rwatch" => WatchKind::Read,
+                    _ => WatchKind::Access,
+                };
+                let len = match len.first() {
+                    Some(len) => parse_addr(len)?,
+                    None => 1,
+                };
+                DebugCommand::Watch(parse_addr(addr)?, len, kind)
+            }
+            ("unwatch", [addr]) => DebugCommand::Unwatch(parse_addr(addr)?),
+            ("bl" | "breakpoints", []) => DebugCommand::Breakpoints,
+            ("r" | "regs", []) => DebugCommand::Registers,
+            ("bt" | "stack", []) => DebugCommand::Stack,
+            ("x", [addr]) => DebugCommand::Memory(parse_addr(addr)?, 16),
+            ("x", [addr, len]) => DebugCommand::Memory(parse_addr(addr)?, parse_addr(len)?),
+            ("h" | "help", []) => DebugCommand::Help,
+            _ => return Err(format!("unknown command '{}', type help for a list", line.trim())),
+        };
+
+        Ok(command)
+    }
+}
+
+pub fn parse_addr(s: &str) -> Result<usize, String> {
+    let digits = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")).unwrap_or(s);
+    usize::from_str_radix(digits, 16).map_err(|_| format!("invalid address '{}'", s))
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(DebugCommand::parse("c"), Ok(DebugCommand::Continue));
+        assert_eq!(DebugCommand::parse("  next "), Ok(DebugCommand::StepOver));
+        assert_eq!(DebugCommand::parse("b 0x2a4"), Ok(DebugCommand::Break(0x2a4)));
+        assert_eq!(DebugCommand::parse("delete 2A4"), Ok(DebugCommand::Delete(0x2a4)));
+        assert_eq!(DebugCommand::parse("x 300"), Ok(DebugCommand::Memory(0x300, 16)));
+        assert_eq!(DebugCommand::parse("x 300 20"), Ok(DebugCommand::Memory(0x300, 0x20)));
+        assert_eq!(DebugCommand::parse("watch 0x300"), Ok(DebugCommand::Watch(0x300, 1, WatchKind::Write)));
+        assert_eq!(DebugCommand::parse("awatch 300 3"), Ok(DebugCommand::Watch(0x300, 3, WatchKind::Access)));
+        assert_eq!(DebugCommand::parse("b if V3 == 0x10"), Ok(DebugCommand::BreakIf(Expr::parse("V3 == 0x10").unwrap())));
+        assert_eq!(DebugCommand::parse("dc 2"), Ok(DebugCommand::DeleteCondition(2)));
+        assert!(DebugCommand::parse("b if V3 ==").is_err());
+        assert!(DebugCommand::parse("watch 300 1 2").is_err());
+        assert!(DebugCommand::parse("b").is_err());
+        assert!(DebugCommand::parse("b zz").is_err());
+        assert!(DebugCommand::parse("jump").is_err());
+        assert!(DebugCommand::parse("").is_err());
+    }
+}
@@ -0,0 +1,380 @@
+use std::fmt;
+
+use crate::processor::Processor;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    V(usize),
+    I,
+    Pc,
+    Sp,
+    Delay,
+    Sound,
+    Number(u32)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    BitOr,
+    BitAnd,
+    Add,
+    Sub
+}
+
+/*
+ * A condition typed at the debugger prompt, e.g. `V3 == 0x10 && I > 0x300`.
+ * `[addr]` reads a byte of memory, numbers are decimal unless prefixed
+ * with 0x and every value is treated as unsigned.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Operand(Operand),
+    Memory(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>)
+}
+
+impl Expr {
+
+    pub fn parse(s: &str) -> Result<Expr, String> {
+        let tokens = tokenize(s)?;
+        let mut parser = Parser { tokens: &tokens, pos: 0 };
+
+        let expr = parser.expr(0)?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some(token) => Err(format!("unexpected '{}'", token)),
+        }
+    }
+
+    pub fn eval(&self, processor: &Processor) -> u32 {
+        match self {
+            Expr::Operand(operand) => match *operand {
+                Operand::V(x) => processor.reg_v()[x] as u32,
+                Operand::I => processor.reg_i() as u32,
+                Operand::Pc => processor.pc() as u32,
+                Operand::Sp => processor.stack().len() as u32,
+                Operand::Delay => processor.timers().delay as u32,
+                Operand::Sound => processor.timers().sound as u32,
+                Operand::Number(n) => n,
+            },
+            Expr::Memory(addr) => {
+                let addr = addr.eval(processor) as usize;
+                processor.memory().get(addr).copied().unwrap_or(0) as u32
+            }
+            Expr::Not(expr) => (expr.eval(processor) == 0) as u32,
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = lhs.eval(processor);
+                // short circuit, so `[I] == 1 || ...` reads what one would expect
+                match op {
+                    BinaryOp::Or if lhs != 0 => return 1,
+                    BinaryOp::And if lhs == 0 => return 0,
+                    _ => {}
+                }
+                let rhs = rhs.eval(processor);
+
+                match op {
+                    BinaryOp::Or | BinaryOp::And => (rhs != 0) as u32,
+                    BinaryOp::Eq => (lhs == rhs) as u32,
+                    BinaryOp::Ne => (lhs != rhs) as u32,
+                    BinaryOp::Lt => (lhs < rhs) as u32,
+                    BinaryOp::Le => (lhs <= rhs) as u32,
+                    BinaryOp::Gt => (lhs > rhs) as u32,
+                    BinaryOp::Ge => (lhs >= rhs) as u32,
+                    BinaryOp::BitOr => lhs | rhs,
+                    BinaryOp::BitAnd => lhs & rhs,
+                    BinaryOp::Add => lhs.wrapping_add(rhs),
+                    BinaryOp::Sub => lhs.wrapping_sub(rhs),
+                }
+            }
+        }
+    }
+
+    pub fn is_true(&self, processor: &Processor) -> bool {
+        self.eval(processor) != 0
+    }
+}
+
+impl BinaryOp {
+    fn symbol(self) -> &'static str {
+        match self {
+            BinaryOp::Or => "||",
+            BinaryOp::And => "&&",
+            BinaryOp::Eq => "==",
+            BinaryOp::Ne => "!=",
+            BinaryOp::Lt => "<",
+            BinaryOp::Le => "<=",
+            BinaryOp::Gt => ">",
+            BinaryOp::Ge => ">=",
+            BinaryOp::BitOr => "|",
+            BinaryOp::BitAnd => "&",
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::V(x) => write!(f, "V{:X}", x),
+            Operand::I => write!(f, "I"),
+            Operand::Pc => write!(f, "PC"),
+            Operand::Sp => write!(f, "SP"),
+            Operand::Delay => write!(f, "DT"),
+            Operand::Sound => write!(f, "ST"),
+            Operand::Number(n) => write!(f, "{:#X}", n),
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Operand(operand) => write!(f, "{}", operand),
+            Expr::Memory(addr) => write!(f, "[{}]", addr),
+            Expr::Not(expr) => match expr.as_ref() {
+                Expr::Binary(..) => write!(f, "!({})", expr),
+                _ => write!(f, "!{}", expr),
+            },
+            Expr::Binary(op, lhs, rhs) => {
+                // parenthesise only where the parse would otherwise differ
+                let (_, strength) = binary_op(op.symbol()).unwrap();
+                match lhs.as_ref() {
+                    Expr::Binary(inner, ..) if binary_op(inner.symbol()).unwrap().1 < strength => write!(f, "({})", lhs)?,
+                    _ => write!(f, "{}", lhs)?,
+                }
+                write!(f, " {} ", op.symbol())?;
+                match rhs.as_ref() {
+                    Expr::Binary(inner, ..) if binary_op(inner.symbol()).unwrap().1 <= strength => write!(f, "({})", rhs),
+                    _ => write!(f, "{}", rhs),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Number(u32),
+    Symbol(&'static str)
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "{}", word),
+            Token::Number(n) => write!(f, "{}", n),
+            Token::Symbol(symbol) => write!(f, "{}", symbol),
+        }
+    }
+}
+
+// longest first, so "<=" isn't read as "<" followed by "="
+const SYMBOLS: [&str; 16] = ["||", "&&", "==", "!=", "<=", ">=", "<", ">", "|", "&", "+", "-", "!", "(", ")", "["];
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = s.trim_start();
+
+    while let Some(c) = rest.chars().next() {
+        if c.is_ascii_alphanumeric() {
+            let end = rest.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(rest.len());
+            let word = &rest[..end];
+            let token = if c.is_ascii_digit() {
+                Token::Number(parse_number(word)?)
+            } else {
+                Token::Word(word.to_ascii_uppercase())
+            };
+            tokens.push(token);
+            rest = &rest[end..];
+        } else if c == ']' {
+            tokens.push(Token::Symbol("]"));
+            rest = &rest[1..];
+        } else {
+            let symbol = SYMBOLS
+                .iter()
+                .find(|symbol| rest.starts_with(*symbol))
+                .ok_or_else(|| format!("unexpected '{}'", c))?;
+            tokens.push(Token::Symbol(symbol));
+            rest = &rest[symbol.len()..];
+        }
+        rest = rest.trim_start();
+    }
+
+    Ok(tokens)
+}
+
+fn parse_number(word: &str) -> Result<u32, String> {
+    let parsed = match word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => word.parse(),
+    };
+    parsed.map_err(|_| format!("invalid number '{}'", word))
+}
+
+/*
+ * Binding strength of each binary operator, loosest first.
+ */
+fn binary_op(symbol: &str) -> Option<(BinaryOp, u8)> {
+    let op = match symbol {
+        "||" => (BinaryOp::Or, 1),
+        "&&" => (BinaryOp::And, 2),
+        "==" => (BinaryOp::Eq, 3),
+        "!=" => (BinaryOp::Ne, 3),
+        "<" => (BinaryOp::Lt, 3),
+        "<=" => (BinaryOp::Le, 3),
+        ">" => (BinaryOp::Gt, 3),
+        ">=" => (BinaryOp::Ge, 3),
+        "|" => (BinaryOp::BitOr, 4),
+        "&" => (BinaryOp::BitAnd, 5),
+        "+" => (BinaryOp::Add, 6),
+        "-" => (BinaryOp::Sub, 6),
+        _ => return None,
+    };
+    Some(op)
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize
+}
+
+impl<'a> Parser<'a> {
+
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<&'a Token> {
+        let token = self.tokens.get(self.pos);
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), String> {
+        match self.next() {
+            Some(Token::Symbol(s)) if *s == symbol => Ok(()),
+            Some(token) => Err(format!("expected '{}', found '{}'", symbol, token)),
+            None => Err(format!("expected '{}'", symbol)),
+        }
+    }
+
+    /*
+     * Precedence climbing, only operators binding tighter than
+     * min_strength are taken into this expression.
+     */
+    fn expr(&mut self, min_strength: u8) -> Result<Expr, String> {
+        let mut lhs = self.unary()?;
+
+        while let Some(Token::Symbol(symbol)) = self.peek() {
+            let Some((op, strength)) = binary_op(symbol) else { break };
+            if strength <= min_strength {
+                break;
+            }
+            self.pos += 1;
+
+            let rhs = self.expr(strength)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Symbol("!")) => Ok(Expr::Not(Box::new(self.unary()?))),
+            Some(Token::Symbol("(")) => {
+                let expr = self.expr(0)?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            Some(Token::Symbol("[")) => {
+                let addr = self.expr(0)?;
+                self.expect("]")?;
+                Ok(Expr::Memory(Box::new(addr)))
+            }
+            Some(Token::Number(n)) => Ok(Expr::Operand(Operand::Number(*n))),
+            Some(Token::Word(word)) => operand(word).map(Expr::Operand),
+            Some(token) => Err(format!("unexpected '{}'", token)),
+            None => Err(String::from("unexpected end of expression")),
+        }
+    }
+}
+
+fn operand(word: &str) -> Result<Operand, String> {
+    let operand = match word {
+        "I" => Operand::I,
+        "PC" => Operand::Pc,
+        "SP" => Operand::Sp,
+        "DT" => Operand::Delay,
+        "ST" => Operand::Sound,
+        _ => match word.strip_prefix('V') {
+            Some(x) if x.len() == 1 => Operand::V(usize::from_str_radix(x, 16).map_err(|_| format!("unknown register '{}'", word))?),
+            _ => return Err(format!("unknown register '{}'", word)),
+        },
+    };
+    Ok(operand)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::quirks::Quirks;
+
+    fn eval(s: &str, program: &[u8]) -> u32 {
+        let mut p = Processor::new(Quirks::default());
+        p.load(program);
+        for _ in 0..program.len() / 2 {
+            p.tick([false; 16]).unwrap();
+        }
+        Expr::parse(s).unwrap().eval(&p)
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            Expr::parse("v3 == 0x10 && I > 0x300"),
+            Ok(Expr::Binary(
+                BinaryOp::And,
+                Box::new(Expr::Binary(BinaryOp::Eq, Box::new(Expr::Operand(Operand::V(3))), Box::new(Expr::Operand(Operand::Number(0x10))))),
+                Box::new(Expr::Binary(BinaryOp::Gt, Box::new(Expr::Operand(Operand::I)), Box::new(Expr::Operand(Operand::Number(0x300))))),
+            ))
+        );
+        assert_eq!(Expr::parse("v3 == 16 && (i > 0x300 || !v0)").unwrap().to_string(), "V3 == 0x10 && (I > 0x300 || !V0)");
+        assert_eq!(Expr::parse("V1 - (V2 - V3)").unwrap().to_string(), "V1 - (V2 - V3)");
+        assert_eq!(Expr::parse("[I + 1] & 0xf").unwrap().to_string(), "[I + 0x1] & 0xF");
+        assert!(Expr::parse("V3 ==").is_err());
+        assert!(Expr::parse("VG == 1").is_err());
+        assert!(Expr::parse("(V1").is_err());
+        assert!(Expr::parse("V1 V2").is_err());
+        assert!(Expr::parse("V1 = 2").is_err());
+    }
+
+    #[test]
+    fn evaluate() {
+        // LD V3, 0x10; LD I, 0x301; ADD V0, 0xFF
+        let program = [0x63, 0x10, 0xa3, 0x01, 0x70, 0xff];
+
+        assert_eq!(eval("V3 == 0x10 && I > 0x300", &program), 1);
+        assert_eq!(eval("V3 == 0x10 && I > 0x301", &program), 0);
+        assert_eq!(eval("V3 == 16 || V4", &program), 1);
+        assert_eq!(eval("!(V0 == 0xff)", &program), 0);
+        assert_eq!(eval("V0 + V3", &program), 0x10f);
+        assert_eq!(eval("V3 - V0", &program), 0x10u32.wrapping_sub(0xff));
+        assert_eq!(eval("V0 & 0xf | 0x100", &program), 0x10f);
+        assert_eq!(eval("PC", &program), 0x206);
+        assert_eq!(eval("[0x200] == 0x63 && [PC - 1] == 0xff", &program), 1);
+        assert_eq!(eval("1 + 2 == 3", &program), 1);
+    }
+}
@@ -0,0 +1,528 @@
+mod command;
+mod expr;
+mod repl;
+
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use crate::instruction::decode;
+use crate::processor::{Access, MemoryAccess, Processor};
+
+pub use self::command::{parse_addr, DebugCommand, HELP};
+pub use self::expr::Expr;
+pub use self::repl::{prompt, spawn_repl};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access
+}
+
+/*
+ * Breaks after an instruction touches any byte in start..start + len.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub start: usize,
+    pub len: usize,
+    pub kind: WatchKind
+}
+
+impl Watchpoint {
+    fn matches(&self, access: &MemoryAccess) -> bool {
+        let kind = matches!(
+            (self.kind, access.access),
+            (WatchKind::Access, _) | (WatchKind::Read, Access::Read) | (WatchKind::Write, Access::Write)
+        );
+        kind && access.addr >= self.start && access.addr < self.start + self.len
+    }
+}
+
+/*
+ * Conditions trigger when they become true, not for as long as they
+ * hold, so continuing doesn't stop again right away.
+ */
+struct Condition {
+    expr: Expr,
+    was_true: bool
+}
+
+enum RunMode {
+    Run,
+    StepOver { pc: usize, sp: usize },
+    StepOut { sp: usize }
+}
+
+/*
+ * Decides when execution stops: on breakpoints, after stepping over a
+ * call or when the current subroutine returns. The frontend calls
+ * before_instruction ahead of every instruction while running.
+ */
+pub struct Debugger {
+    paused: bool,
+    breakpoints: BTreeSet<usize>,
+    conditions: Vec<Condition>,
+    watchpoints: Vec<Watchpoint>,
+    mode: RunMode,
+    // why execution last stopped, other than stepping or a plain breakpoint
+    stop_reason: Option<String>,
+    // the pc we resumed from, so its breakpoint doesn't trigger again right away
+    resumed_at: Option<usize>
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Debugger::new()
+    }
+}
+
+impl Debugger {
+
+    /*
+     * A debugger that starts paused, before the first instruction.
+     */
+    pub fn new() -> Self {
+        Debugger {
+            paused: true,
+            breakpoints: BTreeSet::new(),
+            conditions: Vec::new(),
+            watchpoints: Vec::new(),
+            mode: RunMode::Run,
+            stop_reason: None,
+            resumed_at: None
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+        self.mode = RunMode::Run;
+    }
+
+    fn resume(&mut self, processor: &Processor, mode: RunMode) {
+        self.paused = false;
+        self.mode = mode;
+        self.resumed_at = Some(processor.pc());
+    }
+
+    /*
+     * Returns false, and pauses, when the instruction at the current pc
+     * must not be executed. Watchpoints are checked against the accesses
+     * of the instruction that ran just before.
+     */
+    pub fn before_instruction(&mut self, processor: &Processor) -> bool {
+        if self.paused {
+            return false;
+        }
+
+        let pc = processor.pc();
+        let sp = processor.stack().len();
+        let resumed_here = self.resumed_at.take() == Some(pc);
+
+        // always evaluated, so a condition that became true while paused doesn't fire later
+        let mut condition_hit = None;
+        for (n, condition) in self.conditions.iter_mut().enumerate() {
+            let is_true = condition.expr.is_true(processor);
+            if is_true && !condition.was_true && condition_hit.is_none() {
+                condition_hit = Some(format!("condition {} is true: {}", n, condition.expr));
+            }
+            condition.was_true = is_true;
+        }
+
+        let watch_hit = processor
+            .memory_accesses()
+            .iter()
+            .find(|access| self.watchpoints.iter().any(|watch| watch.matches(access)))
+            .map(|access| {
+                let verb = match access.access {
+                    Access::Read => "read",
+                    Access::Write => "written",
+                };
+                format!("{:#05X} {} by {:#05X}, value {:#04X}", access.addr, verb, access.pc, access.value)
+            });
+
+        if !resumed_here {
+            self.stop_reason = watch_hit.or(condition_hit);
+        }
+
+        let stop = match self.mode {
+            RunMode::StepOver { pc: target, sp: depth } if pc == target && sp == depth => true,
+            RunMode::StepOut { sp: depth } if sp < depth => true,
+            _ => !resumed_here && (self.breakpoints.contains(&pc) || self.stop_reason.is_some()),
+        };
+
+        if stop {
+            self.pause();
+        }
+        !stop
+    }
+
+    /*
+     * Apply a command and return the text to show. Step executes its
+     * instruction right away.
+     */
+    pub fn execute(&mut self, command: DebugCommand, processor: &mut Processor, keypad: [bool; 16]) -> String {
+        match command {
+            DebugCommand::Pause => {
+                self.pause();
+                self.location(processor)
+            }
+            DebugCommand::Continue => {
+                self.resume(processor, RunMode::Run);
+                String::from("continuing")
+            }
+            DebugCommand::Step => {
+                self.pause();
+                match processor.tick(keypad) {
+                    Ok(()) => self.location(processor),
+                    Err(err) => err.to_string(),
+                }
+            }
+            DebugCommand::StepOver => {
+                if is_call(processor) {
+                    let mode = RunMode::StepOver { pc: processor.pc() + 2, sp: processor.stack().len() };
+                    self.resume(processor, mode);
+                    String::from("stepping over call")
+                } else {
+                    self.execute(DebugCommand::Step, processor, keypad)
+                }
+            }
+            DebugCommand::StepOut => {
+                if processor.stack().is_empty() {
+                    return String::from("not in a subroutine");
+                }
+                let mode = RunMode::StepOut { sp: processor.stack().len() };
+                self.resume(processor, mode);
+                String::from("running until return")
+            }
+            DebugCommand::Break(addr) => {
+                self.breakpoints.insert(addr);
+                format!("breakpoint at {:#05X}", addr)
+            }
+            DebugCommand::Delete(addr) => {
+                if self.breakpoints.remove(&addr) {
+                    format!("removed breakpoint at {:#05X}", addr)
+                } else {
+                    format!("no breakpoint at {:#05X}", addr)
+                }
+            }
+            DebugCommand::BreakIf(expr) => {
+                let was_true = expr.is_true(processor);
+                self.conditions.push(Condition { expr, was_true });
+                format!("condition {} set", self.conditions.len() - 1)
+            }
+            DebugCommand::DeleteCondition(n) => {
+                if n < self.conditions.len() {
+                    self.conditions.remove(n);
+                    format!("removed condition {}", n)
+                } else {
+                    format!("no condition {}", n)
+                }
+            }
+            DebugCommand::Watch(start, len, kind) => {
+                let size = processor.memory().len();
+                if start >= size {
+                    return format!("{:#05X} is out of range", start);
+                }
+                // nothing past the end of memory can be touched
+                let len = len.clamp(1, size - start);
+                self.watchpoints.push(Watchpoint { start, len, kind });
+                processor.track_memory(true);
+                format!("watching {:#05X}..{:#05X}", start, start + len)
+            }
+            DebugCommand::Unwatch(start) => {
+                let before = self.watchpoints.len();
+                self.watchpoints.retain(|watch| watch.start != start);
+                processor.track_memory(!self.watchpoints.is_empty());
+                if self.watchpoints.len() < before {
+                    format!("removed watchpoints at {:#05X}", start)
+                } else {
+                    format!("no watchpoint at {:#05X}", start)
+                }
+            }
+            DebugCommand::Breakpoints => self.list(),
+            DebugCommand::Registers => registers(processor),
+            DebugCommand::Stack => stack(processor),
+            DebugCommand::Memory(addr, len) => memory(processor, addr, len),
+            DebugCommand::Help => String::from(HELP),
+        }
+    }
+
+    fn list(&self) -> String {
+        let mut lines: Vec<String> = self.breakpoints.iter().map(|addr| format!("break at {:#05X}", addr)).collect();
+
+        for (n, condition) in self.conditions.iter().enumerate() {
+            lines.push(format!("condition {}: {}", n, condition.expr));
+        }
+        for watch in &self.watchpoints {
+            let kind = match watch.kind {
+                WatchKind::Read => "read",
+                WatchKind::Write => "write",
+                WatchKind::Access => "access",
+            };
+            lines.push(format!("watch {} {:#05X}..{:#05X}", kind, watch.start, watch.start + watch.len));
+        }
+
+        if lines.is_empty() {
+            return String::from("no breakpoints");
+        }
+        lines.join("\n")
+    }
+
+    /*
+     * Why and where execution stopped, for printing after a run is cut short.
+     */
+    pub fn report(&mut self, processor: &Processor) -> String {
+        match self.stop_reason.take() {
+            Some(reason) => format!("{}\n{}", reason, self.location(processor)),
+            None => self.location(processor),
+        }
+    }
+
+    /*
+     * Where execution is paused, e.g. "paused at 0x204: 0x2300  CALL 0x300".
+     */
+    pub fn location(&self, processor: &Processor) -> String {
+        match processor.opcode_at(processor.pc()) {
+            Some(opcode) => match decode(opcode) {
+                Some(instruction) => format!("paused at {:#05X}: {:#06X}  {}", processor.pc(), opcode, instruction),
+                None => format!("paused at {:#05X}: {:#06X}", processor.pc(), opcode),
+            },
+            None => format!("paused at {:#05X}", processor.pc()),
+        }
+    }
+}
+
+fn is_call(processor: &Processor) -> bool {
+    matches!(processor.opcode_at(processor.pc()), Some(opcode) if opcode & 0xF000 == 0x2000)
+}
+
+fn registers(processor: &Processor) -> String {
+    let timers = processor.timers();
+    let mut out = format!(
+        "pc: {:#05X}  i: {:#05X}  sp: {}  dt: {}  st: {}\n",
+        processor.pc(), processor.reg_i(), processor.stack().len(), timers.delay, timers.sound
+    );
+
+    for (i, value) in processor.reg_v().iter().enumerate() {
+        let _ = write!(out, "V{:X}: {:02X}", i, value);
+        out.push(if i % 8 == 7 { '\n' } else { ' ' });
+    }
+
+    out.trim_end().to_string()
+}
+
+fn stack(processor: &Processor) -> String {
+    if processor.stack().is_empty() {
+        return String::from("stack is empty");
+    }
+
+    processor.stack()
+        .iter()
+        .enumerate()
+        .rev()
+        .map(|(depth, addr)| format!("#{} return to {:#05X}", depth, addr))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn memory(processor: &Processor, addr: usize, len: usize) -> String {
+    let ram = processor.memory();
+    let end = addr.saturating_add(len).min(ram.len());
+    if addr >= end {
+        return format!("{:#05X} is out of range", addr);
+    }
+
+    ram[addr..end]
+        .chunks(16)
+        .enumerate()
+        .map(|(line, bytes)| {
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            format!("{:#06X}: {}", addr + line * 16, hex.join(" "))
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::quirks::Quirks;
+
+    // 0x200: CALL 0x206; 0x202: ADD V1, 1; 0x204: JP 0x204
+    // 0x206: ADD V0, 1;  0x208: RET
+    const PROGRAM: [u8; 10] = [0x22, 0x06, 0x71, 0x01, 0x12, 0x04, 0x70, 0x01, 0x00, 0xee];
+
+    fn setup() -> (Debugger, Processor) {
+        let mut p = Processor::new(Quirks::default());
+        p.load(&PROGRAM);
+        (Debugger::new(), p)
+    }
+
+    // run like the frontend would until the debugger stops execution
+    fn run(d: &mut Debugger, p: &mut Processor, limit: usize) {
+        for _ in 0..limit {
+            if !d.before_instruction(p) {
+                return;
+            }
+            p.tick([false; 16]).unwrap();
+        }
+    }
+
+    #[test]
+    fn starts_paused() {
+        let (mut d, p) = setup();
+        assert!(d.is_paused());
+        assert!(!d.before_instruction(&p));
+    }
+
+    #[test]
+    fn step() {
+        let (mut d, mut p) = setup();
+        d.execute(DebugCommand::Step, &mut p, [false; 16]);
+        assert_eq!(p.pc(), 0x206);
+        assert!(d.is_paused());
+    }
+
+    #[test]
+    fn breakpoint() {
+        let (mut d, mut p) = setup();
+        d.execute(DebugCommand::Break(0x208), &mut p, [false; 16]);
+        d.execute(DebugCommand::Continue, &mut p, [false; 16]);
+
+        run(&mut d, &mut p, 100);
+        assert!(d.is_paused());
+        assert_eq!(p.pc(), 0x208);
+
+        // continuing from a breakpoint executes it
+        d.execute(DebugCommand::Continue, &mut p, [false; 16]);
+        run(&mut d, &mut p, 100);
+        assert_eq!(p.pc(), 0x204);
+    }
+
+    #[test]
+    fn step_over() {
+        let (mut d, mut p) = setup();
+        d.execute(DebugCommand::StepOver, &mut p, [false; 16]);
+        assert!(!d.is_paused());
+
+        run(&mut d, &mut p, 100);
+        assert!(d.is_paused());
+        assert_eq!(p.pc(), 0x202);
+        assert_eq!(p.reg_v()[0], 1);
+
+        // not a call, so a single step
+        d.execute(DebugCommand::StepOver, &mut p, [false; 16]);
+        assert_eq!(p.pc(), 0x204);
+        assert!(d.is_paused());
+    }
+
+    #[test]
+    fn step_out() {
+        let (mut d, mut p) = setup();
+        assert_eq!(d.execute(DebugCommand::StepOut, &mut p, [false; 16]), "not in a subroutine");
+
+        d.execute(DebugCommand::Step, &mut p, [false; 16]);
+        d.execute(DebugCommand::StepOut, &mut p, [false; 16]);
+        run(&mut d, &mut p, 100);
+
+        assert!(d.is_paused());
+        assert_eq!(p.pc(), 0x202);
+        assert!(p.stack().is_empty());
+    }
+
+    // 0x200: LD I, 0x300; 0x202: ADD V0, 1; 0x204: LD [I], V0
+    // 0x206: LD I, 0x300; 0x208: DRW V0, V0, 1; 0x20A: JP 0x200
+    const COUNTER: [u8; 12] = [0xa3, 0x00, 0x70, 0x01, 0xf0, 0x55, 0xa3, 0x00, 0xd0, 0x01, 0x12, 0x00];
+
+    #[test]
+    fn watch_write() {
+        let (mut d, mut p) = setup();
+        p.load(&COUNTER);
+        d.execute(DebugCommand::Watch(0x300, 1, WatchKind::Write), &mut p, [false; 16]);
+        d.execute(DebugCommand::Continue, &mut p, [false; 16]);
+
+        run(&mut d, &mut p, 100);
+        assert!(d.is_paused());
+        assert_eq!(p.pc(), 0x206);
+        assert_eq!(d.report(&p), "0x300 written by 0x204, value 0x01\npaused at 0x206: 0xA300  LD I, 0x300");
+
+        d.execute(DebugCommand::Continue, &mut p, [false; 16]);
+        run(&mut d, &mut p, 100);
+        assert_eq!(p.pc(), 0x206);
+        assert_eq!(p.memory()[0x300], 2);
+
+        // the sprite fetch reads 0x300 too, but that's not a write
+        d.execute(DebugCommand::Unwatch(0x300), &mut p, [false; 16]);
+        d.execute(DebugCommand::Watch(0x300, 1, WatchKind::Read), &mut p, [false; 16]);
+        d.execute(DebugCommand::Continue, &mut p, [false; 16]);
+        run(&mut d, &mut p, 100);
+        assert_eq!(p.pc(), 0x20A);
+        assert!(d.report(&p).starts_with("0x300 read by 0x208, value 0x02"));
+    }
+
+    #[test]
+    fn watch_range() {
+        let (mut d, mut p) = setup();
+        p.load(&COUNTER);
+        d.execute(DebugCommand::Watch(0x2ff, 2, WatchKind::Access), &mut p, [false; 16]);
+        d.execute(DebugCommand::Watch(0x301, 4, WatchKind::Access), &mut p, [false; 16]);
+        assert_eq!(d.execute(DebugCommand::Breakpoints, &mut p, [false; 16]), "watch access 0x2FF..0x301\nwatch access 0x301..0x305");
+        assert_eq!(d.execute(DebugCommand::Watch(0xffe, usize::MAX, WatchKind::Read), &mut p, [false; 16]), "watching 0xFFE..0x1000");
+        assert!(d.execute(DebugCommand::Watch(usize::MAX, 1, WatchKind::Read), &mut p, [false; 16]).ends_with("is out of range"));
+        d.execute(DebugCommand::Unwatch(0xffe), &mut p, [false; 16]);
+
+        d.execute(DebugCommand::Unwatch(0x2ff), &mut p, [false; 16]);
+        d.execute(DebugCommand::Continue, &mut p, [false; 16]);
+        run(&mut d, &mut p, 100);
+        assert!(!d.is_paused());
+    }
+
+    #[test]
+    fn condition() {
+        let (mut d, mut p) = setup();
+        p.load(&COUNTER);
+        d.execute(DebugCommand::BreakIf(Expr::parse("V0 == 3 && I < 0x301").unwrap()), &mut p, [false; 16]);
+        d.execute(DebugCommand::Continue, &mut p, [false; 16]);
+
+        run(&mut d, &mut p, 100);
+        assert!(d.is_paused());
+        assert_eq!(p.pc(), 0x204);
+        assert_eq!(p.reg_v()[0], 3);
+        assert!(d.report(&p).starts_with("condition 0 is true: V0 == 0x3 && I < 0x301"));
+
+        // false once Fx55 increments I, and true again after I is reloaded
+        d.execute(DebugCommand::Continue, &mut p, [false; 16]);
+        run(&mut d, &mut p, 100);
+        assert!(d.is_paused());
+        assert_eq!(p.pc(), 0x208);
+
+        // V0 moves past 3, so it doesn't stop again
+        d.execute(DebugCommand::Continue, &mut p, [false; 16]);
+        run(&mut d, &mut p, 100);
+        assert!(!d.is_paused());
+
+        assert_eq!(d.execute(DebugCommand::DeleteCondition(0), &mut p, [false; 16]), "removed condition 0");
+        assert_eq!(d.execute(DebugCommand::Breakpoints, &mut p, [false; 16]), "no breakpoints");
+    }
+
+    #[test]
+    fn inspect() {
+        let (mut d, mut p) = setup();
+        d.execute(DebugCommand::Step, &mut p, [false; 16]);
+
+        let regs = d.execute(DebugCommand::Registers, &mut p, [false; 16]);
+        assert!(regs.starts_with("pc: 0x206  i: 0x000  sp: 1"));
+        assert!(regs.contains("V0: 00"));
+
+        assert_eq!(d.execute(DebugCommand::Stack, &mut p, [false; 16]), "#0 return to 0x202");
+        assert_eq!(d.execute(DebugCommand::Memory(0x200, 4), &mut p, [false; 16]), "0x0200: 22 06 71 01");
+        assert!(d.execute(DebugCommand::Memory(0xffe, usize::MAX), &mut p, [false; 16]).starts_with("0x0FFE: "));
+        assert_eq!(d.location(&p), "paused at 0x206: 0x7001  ADD V0, 0x01");
+    }
+}
@@ -0,0 +1,45 @@
+use std::io::{self, BufRead, Write};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use super::DebugCommand;
+
+/*
+ * Read debugger commands from the terminal on a separate thread, so the
+ * frontend keeps running while waiting for input.
+ */
+pub fn spawn_repl() -> Receiver<DebugCommand> {
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        let stdin = io::stdin();
+
+        prompt();
+        for line in stdin.lock().lines() {
+            let Ok(line) = line else { break };
+
+            if !line.trim().is_empty() {
+                match DebugCommand::parse(&line) {
+                    Ok(command) => {
+                        if sender.send(command).is_err() {
+                            break;
+                        }
+                    }
+                    Err(err) => {
+                        println!("{}", err);
+                        prompt();
+                    }
+                }
+            } else {
+                prompt();
+            }
+        }
+    });
+
+    receiver
+}
+
+pub fn prompt() {
+    print!("(chip8) ");
+    let _ = io::stdout().flush();
+}
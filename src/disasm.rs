@@ -0,0 +1,264 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use crate::instruction::{decode, Instruction, Syntax};
+
+const PROGRAM_START: usize = 0x200;
+
+// data bytes per output line
+const DATA_ROW: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum LabelKind {
+    // ordered so the strongest claim on an address wins
+    Data,
+    Jump,
+    Sub
+}
+
+/*
+ * A rom split into code and data by following every path of execution
+ * from 0x200. Bytes never reached are taken to be data, e.g. sprites.
+ */
+pub struct Disassembly<'a> {
+    rom: &'a [u8],
+    // instruction start address, size in bytes and the long I operand if any
+    code: BTreeMap<usize, (Instruction, usize, Option<usize>)>,
+    labels: BTreeMap<usize, LabelKind>
+}
+
+impl<'a> Disassembly<'a> {
+
+    pub fn new(rom: &'a [u8]) -> Self {
+        let mut disassembly = Disassembly {
+            rom,
+            code: BTreeMap::new(),
+            labels: BTreeMap::new()
+        };
+
+        disassembly.trace();
+        disassembly
+    }
+
+    fn end(&self) -> usize {
+        PROGRAM_START + self.rom.len()
+    }
+
+    fn word(&self, addr: usize) -> Option<u16> {
+        let offset = addr.checked_sub(PROGRAM_START)?;
+        self.rom.get(offset..offset + 2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn label(&mut self, addr: usize, kind: LabelKind) {
+        if addr >= PROGRAM_START && addr < self.end() {
+            let entry = self.labels.entry(addr).or_insert(kind);
+            *entry = (*entry).max(kind);
+        }
+    }
+
+    /*
+     * Recursive descent over the control flow, one path at a time from
+     * a worklist rather than actual recursion.
+     */
+    fn trace(&mut self) {
+        let mut pending = vec![PROGRAM_START];
+        let mut visited = BTreeSet::new();
+
+        while let Some(start) = pending.pop() {
+            let mut addr = start;
+
+            while visited.insert(addr) {
+                let Some(instruction) = self.word(addr).and_then(decode) else { break };
+
+                let (size, long) = match instruction {
+                    Instruction::LdILong => match self.word(addr + 2) {
+                        Some(operand) => (4, Some(operand as usize)),
+                        None => break,
+                    },
+                    _ => (2, None),
+                };
+                self.code.insert(addr, (instruction, size, long));
+                let next = addr + size;
+
+                match instruction {
+                    Instruction::Jp(target) => {
+                        self.label(target, LabelKind::Jump);
+                        pending.push(target);
+                        break;
+                    }
+                    // the offset isn't known, but the table usually starts at the base
+                    Instruction::JpV0(target) => {
+                        self.label(target, LabelKind::Jump);
+                        pending.push(target);
+                        break;
+                    }
+                    Instruction::Call(target) => {
+                        self.label(target, LabelKind::Sub);
+                        pending.push(target);
+                    }
+                    Instruction::Ret | Instruction::Exit => break,
+                    Instruction::LdI(target) => self.label(target, LabelKind::Data),
+                    Instruction::LdILong => self.label(long.unwrap_or_default(), LabelKind::Data),
+                    _ if instruction.is_skip() => {
+                        let skipped = match self.word(next) {
+                            Some(0xf000) => 4,
+                            _ => 2,
+                        };
+                        pending.push(next + skipped);
+                    }
+                    _ => {}
+                }
+
+                addr = next;
+            }
+        }
+    }
+
+    fn label_name(&self, addr: usize) -> Option<String> {
+        if addr == PROGRAM_START {
+            return Some(String::from("main"));
+        }
+
+        let prefix = match self.labels.get(&addr)? {
+            LabelKind::Sub => "sub",
+            LabelKind::Jump => "label",
+            LabelKind::Data => "data",
+        };
+        Some(format!("{}_{:03X}", prefix, addr))
+    }
+
+    pub fn is_code(&self, addr: usize) -> bool {
+        self.code.contains_key(&addr)
+    }
+
+    /*
+     * A listing with labels for every jump, call and I target, and the
+     * address and raw bytes of each line in a trailing comment.
+     */
+    pub fn listing(&self, syntax: Syntax) -> String {
+        let label = |addr: usize| self.label_name(addr);
+        let (comment, indent) = match syntax {
+            Syntax::Cowgod => (";", "    "),
+            Syntax::Octo => ("#", "  "),
+        };
+
+        let mut out = String::new();
+        let mut addr = PROGRAM_START;
+
+        while addr < self.end() {
+            if let Some(name) = label(addr) {
+                match syntax {
+                    Syntax::Cowgod => { let _ = writeln!(out, "{}:", name); }
+                    Syntax::Octo => { let _ = writeln!(out, ": {}", name); }
+                }
+            }
+
+            let (text, size) = match self.code.get(&addr) {
+                Some(&(instruction, size, long)) => {
+                    let mut text = instruction.format(syntax, &label);
+                    if let Some(target) = long {
+                        let target = label(target).unwrap_or_else(|| format!("{:#06X}", target));
+                        text = format!("{} {}", text, target);
+                    }
+                    (text, size)
+                }
+                None => {
+                    // up to a row of bytes, stopping at the next code or label
+                    let mut size = 1;
+                    while size < DATA_ROW && addr + size < self.end() && !self.is_code(addr + size) && label(addr + size).is_none() {
+                        size += 1;
+                    }
+                    let offset = addr - PROGRAM_START;
+                    let bytes: Vec<String> = self.rom[offset..offset + size].iter().map(|b| format!("{:#04X}", b)).collect();
+                    let text = match syntax {
+                        Syntax::Cowgod => format!("db {}", bytes.join(", ")),
+                        Syntax::Octo => bytes.join(" "),
+                    };
+                    (text, size)
+                }
+            };
+
+            let offset = addr - PROGRAM_START;
+            let raw: String = self.rom[offset..offset + size].iter().map(|b| format!("{:02X}", b)).collect();
+            let _ = writeln!(out, "{}{:<32} {} {:03X}: {}", indent, text, comment, addr, raw);
+
+            addr += size;
+        }
+
+        out
+    }
+}
+
+/*
+ * Disassemble a rom loaded at 0x200.
+ */
+pub fn disassemble(rom: &[u8], syntax: Syntax) -> String {
+    Disassembly::new(rom).listing(syntax)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    // 0x200: CALL 0x208; 0x202: SE V0, 0; 0x204: JP 0x204; 0x206: data
+    // 0x208: LD I, 0x20C; 0x20A: RET; 0x20C: sprite
+    const ROM: [u8; 14] = [0x22, 0x08, 0x30, 0x00, 0x12, 0x04, 0xff, 0xff, 0xa2, 0x0c, 0x00, 0xee, 0x3c, 0x42];
+
+    #[test]
+    fn code_and_data() {
+        let d = Disassembly::new(&ROM);
+
+        for addr in [0x200, 0x202, 0x204, 0x208, 0x20a] {
+            assert!(d.is_code(addr), "{:#X} should be code", addr);
+        }
+        // both sides of the skip are followed, but nothing reaches 0x206
+        assert!(!d.is_code(0x206));
+        assert!(!d.is_code(0x20c));
+
+        assert_eq!(d.label_name(0x204), Some(String::from("label_204")));
+        assert_eq!(d.label_name(0x208), Some(String::from("sub_208")));
+        assert_eq!(d.label_name(0x20c), Some(String::from("data_20C")));
+        assert_eq!(d.label_name(0x202), None);
+    }
+
+    #[test]
+    fn cowgod_listing() {
+        let listing = disassemble(&ROM, Syntax::Cowgod);
+        let lines: Vec<&str> = listing.lines().map(str::trim_end).collect();
+
+        assert_eq!(lines, [
+            "main:",
+            "    CALL sub_208                     ; 200: 2208",
+            "    SE V0, 0x00                      ; 202: 3000",
+            "label_204:",
+            "    JP label_204                     ; 204: 1204",
+            "    db 0xFF, 0xFF                    ; 206: FFFF",
+            "sub_208:",
+            "    LD I, data_20C                   ; 208: A20C",
+            "    RET                              ; 20A: 00EE",
+            "data_20C:",
+            "    db 0x3C, 0x42                    ; 20C: 3C42",
+        ]);
+    }
+
+    #[test]
+    fn octo_listing() {
+        let listing = disassemble(&ROM, Syntax::Octo);
+
+        assert!(listing.starts_with(": main\n  sub_208 "));
+        assert!(listing.contains("\n: label_204\n  jump label_204 "));
+        assert!(listing.contains("\n  i := data_20C "));
+        assert!(listing.contains("\n: data_20C\n  0x3C 0x42 "));
+    }
+
+    #[test]
+    fn long_i() {
+        // 0x200: LD I, long 0x0206; 0x204: EXIT; 0x206: data
+        let rom = [0xf0, 0x00, 0x02, 0x06, 0x00, 0xfd, 0x81];
+        let listing = disassemble(&rom, Syntax::Octo);
+
+        assert!(listing.contains("i := long data_206"));
+        assert!(listing.contains(": data_206\n  0x81"));
+    }
+}
@@ -0,0 +1,96 @@
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+
+use crate::audio::{AudioPattern, Synth};
+
+const SAMPLE_RATE: i32 = 44_100;
+
+pub struct AudioSettings {
+    pub frequency: f32,
+    pub volume: f32,
+    pub muted: bool
+}
+
+impl Default for AudioSettings {
+    fn default() -> Self {
+        AudioSettings {
+            frequency: 440.0,
+            volume: 0.25,
+            muted: false
+        }
+    }
+}
+
+struct SynthCallback(Synth);
+
+impl AudioCallback for SynthCallback {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        self.0.fill(out);
+    }
+}
+
+pub struct AudioDriver {
+    // None without a usable audio device, the game then runs silently
+    device: Option<AudioDevice<SynthCallback>>,
+    muted: bool,
+    playing: bool,
+    pattern: Option<AudioPattern>
+}
+
+impl AudioDriver {
+
+    /*
+     * Warns and stays silent when there is no audio device to play on.
+     */
+    pub fn new(sdl_context: &sdl2::Sdl, settings: AudioSettings) -> Self {
+        let device = open_device(sdl_context, &settings)
+            .map_err(|err| eprintln!("audio unavailable: {}", err))
+            .ok();
+
+        AudioDriver {
+            device,
+            muted: settings.muted,
+            playing: false,
+            pattern: None
+        }
+    }
+
+    /*
+     * Play the tone, or the XO-CHIP pattern when one is loaded, while
+     * the sound timer is running.
+     */
+    pub fn update(&mut self, sound_active: bool, pattern: Option<AudioPattern>) {
+        let Some(device) = self.device.as_mut() else { return };
+        let play = sound_active && !self.muted;
+
+        if pattern != self.pattern {
+            device.lock().0.set_pattern(pattern);
+            self.pattern = pattern;
+        }
+
+        if play != self.playing {
+            if play {
+                device.resume();
+            } else {
+                device.pause();
+            }
+            self.playing = play;
+        }
+    }
+
+}
+
+fn open_device(sdl_context: &sdl2::Sdl, settings: &AudioSettings) -> Result<AudioDevice<SynthCallback>, String> {
+    let audio_subsystem = sdl_context.audio()?;
+
+    let desired_spec = AudioSpecDesired {
+        freq: Some(SAMPLE_RATE),
+        channels: Some(1),
+        samples: None
+    };
+
+    audio_subsystem.open_playback(None, &desired_spec, |spec| {
+        SynthCallback(Synth::new(spec.freq as u32, settings.frequency, settings.volume))
+    })
+}
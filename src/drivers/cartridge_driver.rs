@@ -1,27 +1,281 @@
-use std::fs::File;
-use std::path::Path;
-use std::io::Read;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use zip::result::ZipError;
+use zip::ZipArchive;
+
+use crate::error::RomError;
+use crate::processor::{PROGRAM_START, XO_CHIP_RAM};
+use crate::quirks::Quirks;
+
+// the end of the program area on a COSMAC VIP, above it live the stack and display
+const VIP_RESERVED_START: usize = 0xea0;
+
+// the largest rom any platform can load
+const MAX_ROM_SIZE: usize = XO_CHIP_RAM - PROGRAM_START;
+
+pub const ROM_EXTENSIONS: [&str; 3] = ["ch8", "sc8", "xo8"];
 
 pub struct CartridgeDriver {
-    pub rom: [u8; 3584],
-    pub size: usize,
+    rom: Vec<u8>
 }
 
 impl CartridgeDriver {
-    pub fn new<P: AsRef<Path>>(path: P) -> Self {
 
-        let mut f = File::open(path).expect("file not found");
-        let mut buffer = [0u8; 3584];
+    /*
+     * Read a whole ROM file. A .zip archive is searched for its one rom,
+     * or `archive.zip:game.ch8` names the entry to load. Anything that
+     * wouldn't fit in memory on any platform is rejected here, use check
+     * for the platform being run.
+     */
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, RomError> {
+        let path = path.as_ref();
 
-        let bytes_read = if let Ok(bytes_read) = f.read(&mut buffer) {
-            bytes_read
-        } else {
-            0
+        let rom = match split_archive_path(path) {
+            Some((archive, entry)) => read_archive(&archive, Some(&entry))?,
+            None if has_extension(path, &["zip"]) => read_archive(path, None)?,
+            None => {
+                let mut rom = Vec::new();
+                File::open(path)?.take(MAX_ROM_SIZE as u64 + 1).read_to_end(&mut rom)?;
+                rom
+            }
         };
 
+        if rom.is_empty() {
+            return Err(RomError::Empty);
+        }
+        if rom.len() > MAX_ROM_SIZE {
+            let size = fs::metadata(path).map_or(rom.len(), |metadata| metadata.len() as usize);
+            return Err(RomError::TooLarge { size, max: MAX_ROM_SIZE });
+        }
+
+        Ok(CartridgeDriver { rom })
+    }
+
+    /*
+     * Every rom in a directory, including those inside zip archives as
+     * `archive.zip:game.ch8`, sorted by name. Unreadable archives are
+     * left out.
+     */
+    pub fn scan<P: AsRef<Path>>(dir: P) -> io::Result<Vec<PathBuf>> {
+        let mut files: Vec<PathBuf> = fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_file())
+            .collect();
+        files.sort();
+
+        let mut roms = Vec::new();
+        for path in files {
+            if has_extension(&path, &ROM_EXTENSIONS) {
+                roms.push(path);
+            } else if has_extension(&path, &["zip"]) {
+                let Ok(archive) = File::open(&path).map_err(ZipError::from).and_then(ZipArchive::new) else { continue };
+                let mut archive_path = path.into_os_string();
+                archive_path.push(":");
+                roms.extend(rom_names(&archive).into_iter().map(|name| {
+                    let mut path = archive_path.clone();
+                    path.push(name);
+                    PathBuf::from(path)
+                }));
+            }
+        }
+
+        Ok(roms)
+    }
+
+    /*
+     * A cartridge for a rom built in memory, e.g. compiled from source.
+     */
+    pub fn from_rom(data: &[u8]) -> Self {
         CartridgeDriver {
-            rom: buffer,
-            size: bytes_read,
+            rom: data.to_vec()
+        }
+    }
+
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    pub fn size(&self) -> usize {
+        self.rom.len()
+    }
+
+    /*
+     * Make sure the ROM fits the memory of the platform the quirks
+     * describe. Returns a warning for ROMs that load fine but run into
+     * memory the original COSMAC VIP interpreter kept for itself.
+     */
+    pub fn check(&self, quirks: &Quirks) -> Result<Option<String>, RomError> {
+        let max = quirks.memory_size() - PROGRAM_START;
+        if self.size() > max {
+            return Err(RomError::TooLarge { size: self.size(), max });
         }
+
+        // only the VIP shifts Vy and resets VF after logic ops, either is
+        // enough to tell it apart from the later interpreters
+        let vip = !quirks.extended_memory && (quirks.shift_uses_vy || quirks.logic_resets_vf);
+        let vip_max = VIP_RESERVED_START - PROGRAM_START;
+        if vip && self.size() > vip_max {
+            return Ok(Some(format!(
+                "rom is {} bytes, more than the {} a COSMAC VIP has room for, it may have been written for a later interpreter",
+                self.size(), vip_max
+            )));
+        }
+
+        Ok(None)
     }
-}
\ No newline at end of file
+
+    /*
+     * SHA-1 of the ROM as a hex string, used to key per-game data.
+     */
+    pub fn hash(&self) -> String {
+        sha1_smol::Sha1::from(&self.rom).digest().to_string()
+    }
+}
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(OsStr::to_str)
+        .is_some_and(|ext| extensions.iter().any(|wanted| ext.eq_ignore_ascii_case(wanted)))
+}
+
+/*
+ * Split `archive.zip:game.ch8` into the archive and the entry name.
+ */
+fn split_archive_path(path: &Path) -> Option<(PathBuf, String)> {
+    let path = path.to_str()?;
+    let end = path.to_ascii_lowercase().rfind(".zip:")? + ".zip".len();
+    Some((PathBuf::from(&path[..end]), String::from(&path[end + 1..])))
+}
+
+fn rom_names<R: Read + io::Seek>(archive: &ZipArchive<R>) -> Vec<String> {
+    let mut names: Vec<String> = archive
+        .file_names()
+        .filter(|name| has_extension(Path::new(name), &ROM_EXTENSIONS))
+        .map(String::from)
+        .collect();
+    names.sort();
+    names
+}
+
+fn archive_error(err: ZipError) -> RomError {
+    match err {
+        ZipError::Io(err) => RomError::Io(err),
+        err => RomError::Archive(err.to_string()),
+    }
+}
+
+/*
+ * Read the named entry, or the only rom in the archive.
+ */
+fn read_archive(path: &Path, entry: Option<&str>) -> Result<Vec<u8>, RomError> {
+    let mut archive = ZipArchive::new(File::open(path)?).map_err(archive_error)?;
+
+    let name = match entry {
+        Some(name) => String::from(name),
+        None => {
+            let mut names = rom_names(&archive);
+            match names.len() {
+                0 => return Err(RomError::NoRomInArchive),
+                1 => names.remove(0),
+                _ => return Err(RomError::AmbiguousArchive(names)),
+            }
+        }
+    };
+
+    let file = archive.by_name(&name).map_err(|err| match err {
+        ZipError::FileNotFound => RomError::NotInArchive(name.clone()),
+        err => archive_error(err),
+    })?;
+    if file.size() > MAX_ROM_SIZE as u64 {
+        return Err(RomError::TooLarge { size: file.size() as usize, max: MAX_ROM_SIZE });
+    }
+
+    // the header could lie about the size, so never inflate more than fits
+    let mut rom = Vec::new();
+    file.take(MAX_ROM_SIZE as u64 + 1).read_to_end(&mut rom)?;
+    Ok(rom)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    fn write_zip(path: &Path, entries: &[(&str, &[u8])]) {
+        let mut zip = ZipWriter::new(File::create(path).unwrap());
+        for (name, data) in entries {
+            zip.start_file(*name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("chip8-cartridge-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn split_path() {
+        assert_eq!(split_archive_path(Path::new("roms/pack.ZIP:games/pong.ch8")), Some((PathBuf::from("roms/pack.ZIP"), String::from("games/pong.ch8"))));
+        assert_eq!(split_archive_path(Path::new("roms/pong.ch8")), None);
+    }
+
+    #[test]
+    fn archives() {
+        let dir = temp_dir("zip");
+        write_zip(&dir.join("one.zip"), &[("readme.txt", b"hi"), ("pong.CH8", &[0x12, 0x00])]);
+        write_zip(&dir.join("two.zip"), &[("a.ch8", &[0x00, 0xe0]), ("b.xo8", &[0x00, 0xfd])]);
+        write_zip(&dir.join("none.zip"), &[("readme.txt", b"hi")]);
+        fs::write(dir.join("broken.zip"), b"not a zip").unwrap();
+
+        let rom = |path: &str| CartridgeDriver::new(dir.join(path)).map(|cartridge| cartridge.rom().to_vec());
+
+        assert_eq!(rom("one.zip").unwrap(), [0x12, 0x00]);
+        assert_eq!(rom("two.zip:b.xo8").unwrap(), [0x00, 0xfd]);
+        assert!(matches!(rom("two.zip"), Err(RomError::AmbiguousArchive(names)) if names == ["a.ch8", "b.xo8"]));
+        assert!(matches!(rom("two.zip:c.ch8"), Err(RomError::NotInArchive(name)) if name == "c.ch8"));
+        assert!(matches!(rom("none.zip"), Err(RomError::NoRomInArchive)));
+        assert!(matches!(rom("broken.zip"), Err(RomError::Archive(_))));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn scan_directory() {
+        let dir = temp_dir("scan");
+        fs::write(dir.join("b.ch8"), [0x00, 0xe0]).unwrap();
+        fs::write(dir.join("notes.txt"), b"hi").unwrap();
+        fs::create_dir(dir.join("sub.ch8")).unwrap();
+        write_zip(&dir.join("a.zip"), &[("x.sc8", &[0x00, 0xe0]), ("readme.txt", b"hi")]);
+        fs::write(dir.join("c.zip"), b"not a zip").unwrap();
+
+        let roms = CartridgeDriver::scan(&dir).unwrap();
+        assert_eq!(roms, [PathBuf::from(format!("{}:x.sc8", dir.join("a.zip").display())), dir.join("b.ch8")]);
+        for rom in roms {
+            assert!(CartridgeDriver::new(rom).is_ok());
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn vip_warning() {
+        let cartridge = CartridgeDriver::from_rom(&[0; 0xd00]);
+        let mut quirks = Quirks::COSMAC_VIP;
+        quirks.clip_sprites = false;
+
+        assert!(cartridge.check(&Quirks::COSMAC_VIP).unwrap().is_some());
+        assert!(cartridge.check(&quirks).unwrap().is_some());
+        assert!(cartridge.check(&Quirks::SUPER_CHIP).unwrap().is_none());
+        assert!(cartridge.check(&Quirks::XO_CHIP).unwrap().is_none());
+    }
+}
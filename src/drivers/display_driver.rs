@@ -2,27 +2,43 @@ extern crate sdl2;
 
 use sdl2::pixels;
 use sdl2::rect::Rect;
-use sdl2::render::Canvas;
+use sdl2::render::{BlendMode, Canvas};
 use sdl2::video::Window;
 
-use crate::processor::CHIP8_WIDTH;
-use crate::processor::CHIP8_HEIGHT;
+use crate::font;
+use crate::menu::Menu;
+use crate::processor::{CHIP8_WIDTH, CHIP8_HEIGHT};
+use crate::processor::{SCHIP_WIDTH, SCHIP_HEIGHT};
+use crate::settings::Palette;
 
-const SCALE_FACTOR: u32 = 20;
-const SCREEN_WIDTH: u32 = (CHIP8_WIDTH as u32) * SCALE_FACTOR;
-const SCREEN_HEIGHT: u32 = (CHIP8_HEIGHT as u32) * SCALE_FACTOR;
+// the menu text is sized to fit this many characters across the window
+const MENU_COLUMNS: u32 = 80;
+
+const MENU_BACKGROUND: pixels::Color = pixels::Color::RGBA(0, 0, 0, 208);
+const MENU_HIGHLIGHT: pixels::Color = pixels::Color::RGB(255, 255, 255);
+const MENU_TEXT: pixels::Color = pixels::Color::RGB(170, 170, 170);
+const MENU_DIM: pixels::Color = pixels::Color::RGB(110, 110, 110);
 
 pub struct DisplayDriver {
     canvas: Canvas<Window>,
+    palette: Palette,
+    screen_width: u32,
+    screen_height: u32
 }
 
 impl DisplayDriver {
 
-    pub fn new(sdl_context: &sdl2::Sdl) -> Self {
+    /*
+     * A window showing the low resolution screen at `scale` pixels per
+     * CHIP-8 pixel.
+     */
+    pub fn new(sdl_context: &sdl2::Sdl, palette: Palette, scale: u32) -> Self {
         let video_subsystem = sdl_context.video().unwrap();
+        let screen_width = CHIP8_WIDTH as u32 * scale;
+        let screen_height = CHIP8_HEIGHT as u32 * scale;
 
         let window = video_subsystem
-            .window("rust-sdl2 demo: Video", SCREEN_WIDTH, SCREEN_HEIGHT)
+            .window("rust-sdl2 demo: Video", screen_width, screen_height)
             .position_centered()
             .opengl()
             .build()
@@ -34,32 +50,93 @@ impl DisplayDriver {
         canvas.present();
 
         DisplayDriver {
-            canvas
+            canvas,
+            palette,
+            screen_width,
+            screen_height
         }
     }
 
+    /*
+     * Takes effect on the next draw.
+     */
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+    }
+
     fn color(&self, value :u8) -> pixels::Color {
-        if value == 0 {
-            pixels::Color::RGB(0, 0, 0)
-        } else {
-            pixels::Color::RGB(0, 255, 0)
-        }
+        let (r, g, b) = self.palette[(value & 0b11) as usize];
+        pixels::Color::RGB(r, g, b)
     }
 
-    pub fn draw(&mut self, pixels: &[[u8; CHIP8_WIDTH]; CHIP8_HEIGHT]) {
+    /*
+     * Draw the top-left width x height pixels of the vram, scaled to fill
+     * the window. In SUPER-CHIP high resolution mode the pixels are half size.
+     * With a menu it is drawn over the dimmed screen.
+     */
+    pub fn draw(&mut self, pixels: &[[u8; SCHIP_WIDTH]; SCHIP_HEIGHT], width: usize, height: usize, menu: Option<&Menu>) {
+
+        let scale = self.screen_width / width as u32;
 
-        for (y, &row) in pixels.iter().enumerate() {
-            let sy = y as u32 * SCALE_FACTOR;
+        for (y, row) in pixels.iter().take(height).enumerate() {
+            let sy = y as u32 * scale;
 
-            for (x, &col) in row.iter().enumerate() {
-                let sx = x as u32 * SCALE_FACTOR;
+            for (x, &col) in row.iter().take(width).enumerate() {
+                let sx = x as u32 * scale;
                 
                 self.canvas.set_draw_color(self.color(col));
-                let _ = self.canvas.fill_rect(Rect::new(sx as i32, sy as i32, SCALE_FACTOR, SCALE_FACTOR));
+                let _ = self.canvas.fill_rect(Rect::new(sx as i32, sy as i32, scale, scale));
             } 
         }
 
+        if let Some(menu) = menu {
+            self.draw_menu(menu);
+        }
+
         self.canvas.present();
     }
 
+    /*
+     * Lay the menu out in a grid of character cells with a one cell
+     * margin, each cell a TEXT_FONT glyph and a pixel of spacing.
+     */
+    fn draw_menu(&mut self, menu: &Menu) {
+        self.canvas.set_blend_mode(BlendMode::Blend);
+        self.canvas.set_draw_color(MENU_BACKGROUND);
+        let _ = self.canvas.fill_rect(None);
+        self.canvas.set_blend_mode(BlendMode::None);
+
+        let dot = (self.screen_width / (MENU_COLUMNS * 4)).max(1);
+        let columns = (self.screen_width / (4 * dot)) as usize;
+        let rows = (self.screen_height / (6 * dot)) as usize;
+        let view = menu.view(rows.saturating_sub(2), columns.saturating_sub(2));
+
+        self.draw_text(&view.title, 1, 1, dot, MENU_HIGHLIGHT);
+        for (n, line) in view.lines.iter().enumerate() {
+            let color = if n == view.selected { MENU_HIGHLIGHT } else { MENU_TEXT };
+            self.draw_text(line, 1, n + 3, dot, color);
+        }
+        self.draw_text(&view.footer, 1, rows.saturating_sub(2), dot, MENU_DIM);
+    }
+
+    fn draw_text(&mut self, text: &str, column: usize, row: usize, dot: u32, color: pixels::Color) {
+        let mut rects = Vec::new();
+
+        for (n, c) in text.chars().enumerate() {
+            let x = (column + n) as i32 * 4 * dot as i32;
+            let y = row as i32 * 6 * dot as i32;
+
+            for (gy, bits) in font::text_glyph(c).iter().enumerate() {
+                for gx in 0..3 {
+                    if bits & (0x80 >> gx) != 0 {
+                        rects.push(Rect::new(x + gx * dot as i32, y + gy as i32 * dot as i32, dot, dot));
+                    }
+                }
+            }
+        }
+
+        self.canvas.set_draw_color(color);
+        let _ = self.canvas.fill_rects(&rects);
+    }
+
 }
\ No newline at end of file
@@ -1,36 +1,129 @@
-use sdl2::EventPump;
+use sdl2::{EventPump, GameControllerSubsystem};
+use sdl2::controller::{Button, GameController};
 use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use sdl2::keyboard::{Keycode, Mod};
+
+use crate::menu::MenuInput;
+use crate::settings::Keymap;
+
+pub const SAVE_SLOTS: u8 = 4;
+
+/*
+ * Emulator hotkeys, as opposed to keys forwarded to the CHIP-8 keypad.
+ */
+pub enum Command {
+    SaveState(u8),
+    LoadState(u8),
+    ToggleMenu
+}
+
+pub struct InputState {
+    pub keypad: [bool; 16],
+    pub commands: Vec<Command>,
+    pub menu: Vec<MenuInput>,
+    pub rewinding: bool
+}
 
 pub struct InputDriver {
-    event_pump: EventPump
+    event_pump: EventPump,
+    keyboard_mapping: [Keycode; 16],
+    controller_subsystem: Option<GameControllerSubsystem>,
+    controllers: Vec<GameController>
 }
 
 impl InputDriver {
 
-    pub fn new(sdl_context: &sdl2::Sdl) -> Self {
-        let event_pump = sdl_context.event_pump().unwrap();
+    /*
+     * Fails when the keymap names a key SDL doesn't know.
+     */
+    pub fn new(sdl_context: &sdl2::Sdl, keymap: &Keymap) -> Result<Self, String> {
+        let event_pump = sdl_context.event_pump()?;
+        let keyboard_mapping = keyboard_mapping(keymap)?;
 
-        InputDriver {
-            event_pump
-        }
-    } 
+        // gamepads are only used for the menu, so carry on without them
+        let controller_subsystem = sdl_context.game_controller()
+            .map_err(|err| eprintln!("gamepads unavailable: {}", err))
+            .ok();
 
-    pub fn update(&mut self) -> Result<[bool; 16], ()> {
+        Ok(InputDriver {
+            event_pump,
+            keyboard_mapping,
+            controller_subsystem,
+            controllers: Vec::new()
+        })
+    }
+
+    /*
+     * Switch to another keymap, e.g. for a rom opened from the menu. The
+     * current one is kept when `keymap` names a key SDL doesn't know.
+     */
+    pub fn set_keymap(&mut self, keymap: &Keymap) -> Result<(), String> {
+        self.keyboard_mapping = keyboard_mapping(keymap)?;
+        Ok(())
+    }
 
-        let keyboard_mapping:[Keycode; 16] = [
-            Keycode::Num1,  Keycode::Num2,  Keycode::Num3,  Keycode::Num4,
-            Keycode::Q,     Keycode::W,     Keycode::E,     Keycode::R,
-            Keycode::A,     Keycode::S,     Keycode::D,     Keycode::F,
-            Keycode::Z,     Keycode::X,     Keycode::C,     Keycode::V
-        ];
+    /*
+     * Poll the keyboard and gamepads, returns None when the window was
+     * closed. F1-F4 load save slots 1-4, with shift held they save
+     * instead. Holding backspace rewinds. Escape or Start opens and closes
+     * the menu, which is driven by the arrow keys, enter and backspace or
+     * the d-pad, A and B.
+     */
+    pub fn update(&mut self) -> Option<InputState> {
+
+        let slot_keys: [Keycode; SAVE_SLOTS as usize] = [Keycode::F1, Keycode::F2, Keycode::F3, Keycode::F4];
+        let mut commands = Vec::new();
+        let mut menu = Vec::new();
 
         for event in self.event_pump.poll_iter() {
             if let Event::Quit { .. } = event {
-                return Err(());
+                return None;
+            };
+            if let Event::ControllerDeviceAdded { which, .. } = event {
+                // SDL keeps the gamepad open as long as the handle lives
+                if let Some(subsystem) = &self.controller_subsystem {
+                    match subsystem.open(which) {
+                        Ok(controller) => self.controllers.push(controller),
+                        Err(err) => eprintln!("could not open gamepad {}: {}", which, err),
+                    }
+                }
+            };
+            if let Event::ControllerDeviceRemoved { which, .. } = event {
+                self.controllers.retain(|controller| controller.instance_id() != which);
+            };
+            if let Event::ControllerButtonDown { button, .. } = event {
+                match button {
+                    Button::Start => commands.push(Command::ToggleMenu),
+                    Button::DPadUp => menu.push(MenuInput::Up),
+                    Button::DPadDown => menu.push(MenuInput::Down),
+                    Button::DPadLeft => menu.push(MenuInput::Left),
+                    Button::DPadRight => menu.push(MenuInput::Right),
+                    Button::A => menu.push(MenuInput::Select),
+                    Button::B => menu.push(MenuInput::Back),
+                    _ => {}
+                }
+            };
+            if let Event::KeyDown { keycode: Some(keycode), repeat, .. } = event {
+                match keycode {
+                    Keycode::Escape if !repeat => commands.push(Command::ToggleMenu),
+                    Keycode::Up => menu.push(MenuInput::Up),
+                    Keycode::Down => menu.push(MenuInput::Down),
+                    Keycode::Left => menu.push(MenuInput::Left),
+                    Keycode::Right => menu.push(MenuInput::Right),
+                    Keycode::Return | Keycode::KpEnter if !repeat => menu.push(MenuInput::Select),
+                    Keycode::Backspace if !repeat => menu.push(MenuInput::Back),
+                    _ => {}
+                }
             };
-            if let Event::KeyDown { keycode: Some(Keycode::Escape), .. } = event {
-                return Err(());
+            if let Event::KeyDown { keycode: Some(keycode), keymod, repeat: false, .. } = event {
+                if let Some(slot) = slot_keys.iter().position(|&k| k == keycode) {
+                    let slot = slot as u8 + 1;
+                    if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                        commands.push(Command::SaveState(slot));
+                    } else {
+                        commands.push(Command::LoadState(slot));
+                    }
+                }
             };
         }
 
@@ -40,8 +133,18 @@ impl InputDriver {
             .filter_map(Keycode::from_scancode)
             .collect();
 
-        let keymap = keyboard_mapping.map(|f: Keycode| keys.contains(&f));
-        Ok(keymap)
+        let keypad = self.keyboard_mapping.map(|f: Keycode| keys.contains(&f));
+        let rewinding = keys.contains(&Keycode::Backspace);
+        Some(InputState { keypad, commands, menu, rewinding })
     }
 
-}
\ No newline at end of file
+}
+
+fn keyboard_mapping(keymap: &Keymap) -> Result<[Keycode; 16], String> {
+    let mut keyboard_mapping = [Keycode::Num1; 16];
+    for (key, name) in keymap.keys.iter().enumerate() {
+        keyboard_mapping[key] = Keycode::from_name(name)
+            .ok_or_else(|| format!("unknown key name '{}' for keypad key {:X}", name, key))?;
+    }
+    Ok(keyboard_mapping)
+}
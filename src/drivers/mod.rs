@@ -1,7 +1,17 @@
 mod cartridge_driver;
+
+#[cfg(feature = "sdl")]
+mod audio_driver;
+#[cfg(feature = "sdl")]
 mod display_driver;
+#[cfg(feature = "sdl")]
 mod input_driver;
 
-pub use self::display_driver::DisplayDriver;
 pub use self::cartridge_driver::CartridgeDriver;
-pub use self::input_driver::InputDriver;
\ No newline at end of file
+
+#[cfg(feature = "sdl")]
+pub use self::audio_driver::{AudioDriver, AudioSettings};
+#[cfg(feature = "sdl")]
+pub use self::display_driver::DisplayDriver;
+#[cfg(feature = "sdl")]
+pub use self::input_driver::{Command, InputDriver, InputState, SAVE_SLOTS};
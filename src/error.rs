@@ -0,0 +1,144 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    UnknownOpcode,
+    StackOverflow,
+    StackUnderflow,
+    MemoryOutOfBounds(usize),
+    PcOutOfRange
+}
+
+/*
+ * A fault raised while executing the instruction at `pc`. Instructions
+ * check their memory range before changing anything, so the processor
+ * is left untouched at that instruction and a frontend can inspect it.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExecutionError {
+    pub pc: usize,
+    pub opcode: u16,
+    pub kind: ErrorKind
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::UnknownOpcode => write!(f, "unknown opcode"),
+            ErrorKind::StackOverflow => write!(f, "stack overflow"),
+            ErrorKind::StackUnderflow => write!(f, "stack underflow"),
+            ErrorKind::MemoryOutOfBounds(addr) => write!(f, "memory access out of bounds at {:#05X}", addr),
+            ErrorKind::PcOutOfRange => write!(f, "program counter out of range"),
+        }
+    }
+}
+
+impl fmt::Display for ExecutionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (pc: {:#05X}, opcode: {:#06X})", self.kind, self.pc, self.opcode)
+    }
+}
+
+impl Error for ExecutionError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotError {
+    InvalidFormat,
+    UnsupportedVersion(u16),
+    RomMismatch,
+    MemorySizeMismatch
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::InvalidFormat => write!(f, "not a valid save state"),
+            SnapshotError::UnsupportedVersion(version) => write!(f, "unsupported save state version {}", version),
+            SnapshotError::RomMismatch => write!(f, "save state belongs to a different rom"),
+            SnapshotError::MemorySizeMismatch => write!(f, "save state was made with a different memory size"),
+        }
+    }
+}
+
+impl Error for SnapshotError {}
+
+/*
+ * An error in assembly source, line and column are 1-based.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub message: String
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}: {}", self.file, self.line, self.column, self.message)
+    }
+}
+
+impl Error for AsmError {}
+
+/*
+ * A malformed line in a reference trace, line is 1-based.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceError {
+    pub line: usize,
+    pub message: String
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for TraceError {}
+
+/*
+ * Why a ROM can't be loaded.
+ */
+#[derive(Debug)]
+pub enum RomError {
+    Io(io::Error),
+    Empty,
+    TooLarge { size: usize, max: usize },
+    Archive(String),
+    NoRomInArchive,
+    AmbiguousArchive(Vec<String>),
+    NotInArchive(String)
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RomError::Io(err) => write!(f, "{}", err),
+            RomError::Empty => write!(f, "rom is empty"),
+            RomError::TooLarge { size, max } => write!(f, "rom is {} bytes, only {} fit in memory", size, max),
+            RomError::Archive(err) => write!(f, "not a valid zip archive: {}", err),
+            RomError::NoRomInArchive => write!(f, "archive contains no .ch8, .sc8 or .xo8 rom"),
+            RomError::AmbiguousArchive(names) => write!(f, "archive contains several roms, pick one with ARCHIVE.zip:NAME: {}", names.join(", ")),
+            RomError::NotInArchive(name) => write!(f, "archive has no entry '{}'", name),
+        }
+    }
+}
+
+impl Error for RomError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RomError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RomError {
+    fn from(err: io::Error) -> Self {
+        RomError::Io(err)
+    }
+}
@@ -16,3 +16,102 @@ pub const FONT_SET:[u8;5 * 16] = [
   0xF0, 0x80, 0xF0, 0x80, 0xF0,
   0xF0, 0x80, 0xF0, 0x80, 0x80
 ];
+
+pub const BIG_FONT_SET:[u8;10 * 16] = [
+  0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF,
+  0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF,
+  0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF,
+  0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF,
+  0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03,
+  0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF,
+  0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF,
+  0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18,
+  0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF,
+  0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF,
+  0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3,
+  0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC,
+  0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C,
+  0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC,
+  0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF,
+  0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0
+];
+
+// 3x5 glyphs for ' ' to '_' in the same layout as FONT_SET, used for the
+// menu overlay. Lower case letters are drawn upper case.
+#[cfg(feature = "sdl")]
+pub const TEXT_FONT:[u8;5 * 64] = [
+  0x00, 0x00, 0x00, 0x00, 0x00,
+  0x40, 0x40, 0x40, 0x00, 0x40,
+  0xA0, 0xA0, 0x00, 0x00, 0x00,
+  0xA0, 0xE0, 0xA0, 0xE0, 0xA0,
+  0x60, 0xC0, 0x40, 0x60, 0xC0,
+  0xA0, 0x20, 0x40, 0x80, 0xA0,
+  0x40, 0xA0, 0x40, 0xA0, 0x60,
+  0x40, 0x40, 0x00, 0x00, 0x00,
+  0x20, 0x40, 0x40, 0x40, 0x20,
+  0x80, 0x40, 0x40, 0x40, 0x80,
+  0x00, 0xA0, 0x40, 0xA0, 0x00,
+  0x00, 0x40, 0xE0, 0x40, 0x00,
+  0x00, 0x00, 0x00, 0x40, 0x80,
+  0x00, 0x00, 0xE0, 0x00, 0x00,
+  0x00, 0x00, 0x00, 0x00, 0x40,
+  0x20, 0x20, 0x40, 0x80, 0x80,
+  0xE0, 0xA0, 0xA0, 0xA0, 0xE0,
+  0x40, 0xC0, 0x40, 0x40, 0xE0,
+  0xE0, 0x20, 0xE0, 0x80, 0xE0,
+  0xE0, 0x20, 0xE0, 0x20, 0xE0,
+  0xA0, 0xA0, 0xE0, 0x20, 0x20,
+  0xE0, 0x80, 0xE0, 0x20, 0xE0,
+  0xE0, 0x80, 0xE0, 0xA0, 0xE0,
+  0xE0, 0x20, 0x20, 0x40, 0x40,
+  0xE0, 0xA0, 0xE0, 0xA0, 0xE0,
+  0xE0, 0xA0, 0xE0, 0x20, 0xE0,
+  0x00, 0x40, 0x00, 0x40, 0x00,
+  0x00, 0x40, 0x00, 0x40, 0x80,
+  0x20, 0x40, 0x80, 0x40, 0x20,
+  0x00, 0xE0, 0x00, 0xE0, 0x00,
+  0x80, 0x40, 0x20, 0x40, 0x80,
+  0xE0, 0x20, 0x40, 0x00, 0x40,
+  0x40, 0xA0, 0xE0, 0x80, 0x60,
+  0x40, 0xA0, 0xE0, 0xA0, 0xA0,
+  0xC0, 0xA0, 0xC0, 0xA0, 0xC0,
+  0x60, 0x80, 0x80, 0x80, 0x60,
+  0xC0, 0xA0, 0xA0, 0xA0, 0xC0,
+  0xE0, 0x80, 0xC0, 0x80, 0xE0,
+  0xE0, 0x80, 0xC0, 0x80, 0x80,
+  0x60, 0x80, 0xA0, 0xA0, 0x60,
+  0xA0, 0xA0, 0xE0, 0xA0, 0xA0,
+  0xE0, 0x40, 0x40, 0x40, 0xE0,
+  0x20, 0x20, 0x20, 0xA0, 0x40,
+  0xA0, 0xA0, 0xC0, 0xA0, 0xA0,
+  0x80, 0x80, 0x80, 0x80, 0xE0,
+  0xA0, 0xE0, 0xE0, 0xA0, 0xA0,
+  0xC0, 0xA0, 0xA0, 0xA0, 0xA0,
+  0x40, 0xA0, 0xA0, 0xA0, 0x40,
+  0xC0, 0xA0, 0xC0, 0x80, 0x80,
+  0x40, 0xA0, 0xA0, 0xC0, 0x60,
+  0xC0, 0xA0, 0xC0, 0xA0, 0xA0,
+  0x60, 0x80, 0x40, 0x20, 0xC0,
+  0xE0, 0x40, 0x40, 0x40, 0x40,
+  0xA0, 0xA0, 0xA0, 0xA0, 0xE0,
+  0xA0, 0xA0, 0xA0, 0xA0, 0x40,
+  0xA0, 0xA0, 0xE0, 0xE0, 0xA0,
+  0xA0, 0xA0, 0x40, 0xA0, 0xA0,
+  0xA0, 0xA0, 0x40, 0x40, 0x40,
+  0xE0, 0x20, 0x40, 0x80, 0xE0,
+  0x60, 0x40, 0x40, 0x40, 0x60,
+  0x80, 0x80, 0x40, 0x20, 0x20,
+  0xC0, 0x40, 0x40, 0x40, 0xC0,
+  0x40, 0xA0, 0x00, 0x00, 0x00,
+  0x00, 0x00, 0x00, 0x00, 0xE0
+];
+
+/*
+ * The TEXT_FONT rows for a character, '?' for those it doesn't have.
+ */
+#[cfg(feature = "sdl")]
+pub fn text_glyph(c: char) -> &'static [u8] {
+    let c = c.to_ascii_uppercase();
+    let index = if (' '..='_').contains(&c) { c as usize - ' ' as usize } else { '?' as usize - ' ' as usize };
+    &TEXT_FONT[index * 5..index * 5 + 5]
+}
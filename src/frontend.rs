@@ -0,0 +1,344 @@
+use std::path::Path;
+use std::thread;
+use std::time::Instant;
+
+use crate::debugger::{self, Debugger};
+use crate::drivers::{AudioDriver, AudioSettings, Command, DisplayDriver, CartridgeDriver, InputDriver, SAVE_SLOTS};
+use crate::menu::{Menu, MenuAction};
+use crate::processor::{Processor, RPL_FLAGS};
+use crate::quirks::Quirks;
+use crate::rewind::Rewind;
+use crate::romdb::{RomDatabase, RomInfo};
+use crate::scheduler::{Scheduler, Speed};
+use crate::settings::{Overrides, WindowSettings};
+use crate::storage::Storage;
+use crate::trace::Tracer;
+
+/*
+ * The running ROM and the per-ROM state kept alongside it.
+ */
+struct Game {
+    cartridge: CartridgeDriver,
+    processor: Processor,
+    rom_hash: String,
+    saved_rpl_flags: [u8; RPL_FLAGS]
+}
+
+impl Game {
+
+    /*
+     * Restores the SUPER-CHIP high score flags saved by a previous run.
+     */
+    fn new(cartridge: CartridgeDriver, mut processor: Processor, storage: &Storage) -> Self {
+        let rom_hash = cartridge.hash();
+        match storage.read(&rom_hash, "rpl") {
+            Ok(Some(data)) if data.len() == RPL_FLAGS => {
+                let mut flags = [0; RPL_FLAGS];
+                flags.copy_from_slice(&data);
+                processor.set_rpl_flags(flags);
+            }
+            Ok(_) => {}
+            Err(err) => eprintln!("could not read rpl flags: {}", err),
+        }
+
+        Game {
+            saved_rpl_flags: processor.rpl_flags(),
+            cartridge,
+            processor,
+            rom_hash
+        }
+    }
+
+    /*
+     * Load the rom at `path` configured like a rom given on the command
+     * line: the command line first, then the ROM database, then the
+     * defaults. Fails like the command line when the rom doesn't fit.
+     */
+    fn open<'a>(path: &Path, overrides: &Overrides, database: &'a RomDatabase, storage: &Storage) -> Result<(Self, Option<&'a RomInfo>), String> {
+        let cartridge = CartridgeDriver::new(path).map_err(|err| err.to_string())?;
+        let info = database.get(&cartridge.hash());
+
+        let quirks = overrides.quirks(info);
+        match cartridge.check(&quirks) {
+            Ok(Some(warning)) => eprintln!("warning: {}", warning),
+            Ok(None) => {}
+            Err(err) if !quirks.extended_memory && cartridge.check(&Quirks::XO_CHIP).is_ok() => {
+                return Err(format!("{}, try --quirks xochip", err));
+            }
+            Err(err) => return Err(err.to_string()),
+        }
+
+        let mut processor = Processor::new(quirks);
+        processor.load(cartridge.rom());
+        if let Some(seed) = overrides.seed {
+            processor.seed_rng(seed);
+        }
+        Ok((Game::new(cartridge, processor, storage), info))
+    }
+
+    /*
+     * Start the ROM over, keeping the quirks, high score flags and tracer.
+     * A seeded run starts from the same seed again.
+     */
+    fn reset(&mut self, seed: Option<u64>) {
+        let mut processor = Processor::new(self.processor.quirks());
+        processor.load(self.cartridge.rom());
+        processor.set_rpl_flags(self.processor.rpl_flags());
+        processor.set_tracer(self.processor.take_tracer());
+        if let Some(seed) = seed {
+            processor.seed_rng(seed);
+        }
+        self.processor = processor;
+    }
+
+    fn save_rpl_flags(&mut self, storage: &Storage) {
+        if self.processor.rpl_flags() == self.saved_rpl_flags {
+            return;
+        }
+
+        self.saved_rpl_flags = self.processor.rpl_flags();
+        if let Err(err) = storage.write(&self.rom_hash, "rpl", &self.saved_rpl_flags) {
+            eprintln!("could not save rpl flags: {}", err);
+        }
+    }
+
+    fn save_state(&self, storage: &Storage, slot: u8) -> Result<String, String> {
+        match storage.write(&self.rom_hash, &state_slot(slot), &self.processor.snapshot()) {
+            Ok(()) => Ok(format!("saved state to slot {}", slot)),
+            Err(err) => Err(format!("could not save state to slot {}: {}", slot, err)),
+        }
+    }
+
+    fn load_state(&mut self, storage: &Storage, slot: u8) -> Result<String, String> {
+        let data = match storage.read(&self.rom_hash, &state_slot(slot)) {
+            Ok(Some(data)) => data,
+            Ok(None) => return Err(format!("slot {} is empty", slot)),
+            Err(err) => return Err(format!("could not read slot {}: {}", slot, err)),
+        };
+
+        match self.processor.restore(&data) {
+            Ok(()) => Ok(format!("loaded state from slot {}", slot)),
+            Err(err) => Err(format!("could not load slot {}: {}", slot, err)),
+        }
+    }
+}
+
+/*
+ * Run a loaded processor in an SDL window until the user quits or the
+ * program exits. With a debugger, commands are read from the terminal
+ * and the window keeps rendering while execution is paused. Emulation
+ * also pauses while the menu is open. Fails when SDL can't be set up.
+ */
+pub fn run(cartridge: CartridgeDriver, processor: Processor, speed: Speed, settings: &WindowSettings, mut rewind: Rewind, mut debugger: Option<Debugger>) -> Result<(), String> {
+
+    let sdl_context = sdl2::init()?;
+
+    let mut display = DisplayDriver::new(&sdl_context, settings.palette, settings.scale);
+    let mut input: InputDriver = InputDriver::new(&sdl_context, &settings.keymap)?;
+    let mut audio = AudioDriver::new(&sdl_context, AudioSettings { muted: settings.muted, ..AudioSettings::default() });
+
+    let storage = Storage::new();
+    // a broken user database was already reported when the first ROM loaded
+    let database = RomDatabase::load(&storage).unwrap_or_else(|_| RomDatabase::bundled());
+    let mut game = Game::new(cartridge, processor, &storage);
+
+    let mut scheduler = Scheduler::new(speed);
+    let mut last_frame = Instant::now();
+    let mut crashed = false;
+
+    let mut menu = Menu::new(&settings.rom_dir, SAVE_SLOTS);
+    let mut menu_open = false;
+    let mut palette = settings.palette;
+    let mut redraw = false;
+
+    let commands = debugger.as_ref().map(|debugger| {
+        println!("{}", debugger.location(&game.processor));
+        debugger::spawn_repl()
+    });
+
+    'running: loop {
+
+        let now = Instant::now();
+        let frames = scheduler.advance(now - last_frame);
+        last_frame = now;
+
+        let input_state = match input.update() {
+            Some(input_state) => input_state,
+            None => break 'running,
+        };
+        let keymap = input_state.keypad;
+
+        for command in input_state.commands {
+            match command {
+                Command::SaveState(slot) => {
+                    report(game.save_state(&storage, slot));
+                }
+                Command::LoadState(slot) => {
+                    if report(game.load_state(&storage, slot)) {
+                        rewind.clear();
+                        crashed = false;
+                    }
+                }
+                Command::ToggleMenu => {
+                    menu_open = !menu_open;
+                    if menu_open {
+                        menu.open(scheduler.speed(), palette, game.processor.quirks());
+                    }
+                    redraw = true;
+                }
+            }
+        }
+
+        for menu_input in input_state.menu {
+            if !menu_open {
+                break;
+            }
+            redraw = true;
+
+            match menu.handle(menu_input) {
+                None => {}
+                Some(MenuAction::Resume) => menu_open = false,
+                Some(MenuAction::Open(path)) => match Game::open(&path, &settings.overrides, &database, &storage) {
+                    Ok((mut opened, info)) => {
+                        if let Some(info) = info {
+                            eprintln!("{}", info);
+                        }
+                        scheduler.set_speed(settings.overrides.speed(info));
+                        palette = settings.overrides.palette(info);
+                        display.set_palette(palette);
+                        if let Err(err) = input.set_keymap(&settings.overrides.keymap(info)) {
+                            eprintln!("keeping the current keymap: {}", err);
+                        }
+
+                        opened.processor.set_tracer(game.processor.take_tracer());
+                        game = opened;
+                        rewind.clear();
+                        crashed = false;
+                        menu_open = false;
+                    }
+                    Err(err) => menu.set_message(format!("could not load {}: {}", path.display(), err)),
+                },
+                Some(MenuAction::Reset) => {
+                    game.reset(settings.overrides.seed);
+                    rewind.clear();
+                    crashed = false;
+                    menu_open = false;
+                }
+                Some(MenuAction::SaveState(slot)) => match game.save_state(&storage, slot) {
+                    Ok(message) | Err(message) => menu.set_message(message),
+                },
+                Some(MenuAction::LoadState(slot)) => match game.load_state(&storage, slot) {
+                    Ok(_) => {
+                        rewind.clear();
+                        crashed = false;
+                        menu_open = false;
+                    }
+                    Err(err) => menu.set_message(err),
+                },
+                Some(MenuAction::SetSpeed(speed)) => scheduler.set_speed(speed),
+                Some(MenuAction::SetPalette(menu_palette)) => {
+                    palette = menu_palette;
+                    display.set_palette(palette);
+                }
+                Some(MenuAction::SetQuirks(quirks)) => game.processor.set_quirks(quirks),
+                Some(MenuAction::Quit) => break 'running,
+            }
+        }
+
+        if let (Some(debugger), Some(commands)) = (debugger.as_mut(), commands.as_ref()) {
+            for command in commands.try_iter() {
+                println!("{}", debugger.execute(command, &mut game.processor, keymap));
+                debugger::prompt();
+            }
+        }
+
+        let frames = if menu_open { 0 } else { frames };
+        let processor = &mut game.processor;
+        for _ in 0..frames {
+            if input_state.rewinding {
+                let Some(state) = rewind.pop() else { continue };
+                match processor.restore(&state) {
+                    Ok(()) => crashed = false,
+                    Err(err) => {
+                        eprintln!("could not rewind: {}", err);
+                        rewind.clear();
+                    }
+                }
+                continue;
+            }
+
+            // keep the last frame on screen after a crash until the user quits
+            if crashed {
+                break;
+            }
+
+            let result = match debugger.as_mut() {
+                Some(debugger) if debugger.is_paused() => break,
+                Some(debugger) => scheduler.run_frame_with(processor, keymap, |p| debugger.before_instruction(p)),
+                None => scheduler.run_frame(processor, keymap).map(|()| true),
+            };
+
+            match result {
+                Ok(true) => rewind.push(processor.snapshot()),
+                Ok(false) => {
+                    if let Some(debugger) = debugger.as_mut() {
+                        println!("\n{}", debugger.report(processor));
+                        debugger::prompt();
+                    }
+                }
+                // drop into the debugger instead of halting
+                Err(err) if debugger.is_some() => {
+                    println!("\n{}", err);
+                    if let Some(debugger) = debugger.as_mut() {
+                        debugger.pause();
+                        println!("{}", debugger.location(processor));
+                    }
+                    debugger::prompt();
+                }
+                Err(err) => {
+                    eprintln!("execution halted: {}", err);
+                    crashed = true;
+                }
+            }
+        }
+
+        game.save_rpl_flags(&storage);
+
+        let state = game.processor.output();
+        if state.vram_changed || redraw {
+            display.draw(state.vram, state.width, state.height, menu_open.then_some(&menu));
+            redraw = false;
+        }
+        if state.exited {
+            break 'running;
+        }
+        audio.update(state.sound_active && !crashed && !menu_open, state.audio_pattern);
+
+        thread::sleep(scheduler.time_until_next_frame());
+    }
+
+    if let Some(Err(err)) = game.processor.take_tracer().map(Tracer::finish) {
+        eprintln!("could not write trace: {}", err);
+    }
+    Ok(())
+}
+
+fn state_slot(slot: u8) -> String {
+    format!("state{}", slot)
+}
+
+/*
+ * Print the outcome of a hotkey, returns true when it succeeded.
+ */
+fn report(result: Result<String, String>) -> bool {
+    match result {
+        Ok(message) => {
+            println!("{}", message);
+            true
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            false
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use crate::error::ExecutionError;
+use crate::processor::{OutputState, Processor};
+use crate::scheduler::{Scheduler, Speed};
+
+const PIXEL_CHARS: [char; 4] = ['.', '#', '+', '@'];
+
+/*
+ * Run up to `frames` 60 Hz frames without a display or input, stopping
+ * early when the program exits. Returns the number of frames executed.
+ */
+pub fn run(processor: &mut Processor, speed: Speed, frames: u32) -> Result<u32, ExecutionError> {
+    let mut scheduler = Scheduler::new(speed);
+
+    for frame in 0..frames {
+        if processor.output().exited {
+            return Ok(frame);
+        }
+        scheduler.run_frame(processor, [false; 16])?;
+    }
+
+    Ok(frames)
+}
+
+/*
+ * Render the visible framebuffer as text, one line per row. Pixels are
+ * '.' when off and '#' when set, XO-CHIP plane 2 and both planes show
+ * as '+' and '@'.
+ */
+pub fn dump_framebuffer(state: &OutputState) -> String {
+    let mut out = String::with_capacity((state.width + 1) * state.height);
+
+    for row in state.vram.iter().take(state.height) {
+        for &pixel in row.iter().take(state.width) {
+            out.push(PIXEL_CHARS[(pixel & 0b11) as usize]);
+        }
+        out.push('\n');
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::quirks::Quirks;
+
+    #[test]
+    fn run_and_dump() {
+        let mut p = Processor::new(Quirks::default());
+        // LD V0, 1; LD F, V0; DRW V1, V1, 5; EXIT
+        p.load(&[0x60, 0x01, 0xf0, 0x29, 0xd1, 0x15, 0x00, 0xfd]);
+
+        let frames = run(&mut p, Speed::Hz(60), 100).unwrap();
+        assert_eq!(frames, 4);
+
+        let dump = dump_framebuffer(&p.output());
+        let lines: Vec<&str> = dump.lines().collect();
+
+        assert_eq!(lines.len(), 32);
+        assert_eq!(lines[0].len(), 64);
+        assert_eq!(&lines[0][0..4], "..#.");
+        assert_eq!(&lines[1][0..4], ".##.");
+        assert_eq!(&lines[4][0..4], ".###");
+        assert!(lines[5].chars().all(|c| c == '.'));
+    }
+
+    #[test]
+    fn run_stops_on_error() {
+        let mut p = Processor::new(Quirks::default());
+        p.load(&[0xff, 0xff]);
+
+        assert!(run(&mut p, Speed::default(), 10).is_err());
+    }
+}
@@ -0,0 +1,421 @@
+use std::fmt;
+
+/*
+ * A decoded opcode. Fields follow run_opcode: vx and vy are register
+ * indices, kk a byte, n a nibble and addr a 12 bit address.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    Sys(usize),
+    Cls,
+    Ret,
+    ScrollDown(u8),
+    ScrollUp(u8),
+    ScrollRight,
+    ScrollLeft,
+    Exit,
+    Low,
+    High,
+    Jp(usize),
+    Call(usize),
+    SeByte(usize, u8),
+    SneByte(usize, u8),
+    SeReg(usize, usize),
+    Save(usize, usize),
+    Load(usize, usize),
+    LdByte(usize, u8),
+    AddByte(usize, u8),
+    LdReg(usize, usize),
+    Or(usize, usize),
+    And(usize, usize),
+    Xor(usize, usize),
+    AddReg(usize, usize),
+    Sub(usize, usize),
+    Shr(usize, usize),
+    Subn(usize, usize),
+    Shl(usize, usize),
+    SneReg(usize, usize),
+    LdI(usize),
+    JpV0(usize),
+    Rnd(usize, u8),
+    Drw(usize, usize, u8),
+    Skp(usize),
+    Sknp(usize),
+    // F000, the address is in the following word
+    LdILong,
+    Plane(u8),
+    Audio,
+    LdVxDt(usize),
+    LdVxK(usize),
+    LdDtVx(usize),
+    LdStVx(usize),
+    AddI(usize),
+    LdF(usize),
+    LdHf(usize),
+    LdB(usize),
+    Pitch(usize),
+    LdIVx(usize),
+    LdVxI(usize),
+    LdRVx(usize),
+    LdVxR(usize)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Syntax {
+    #[default]
+    Cowgod,
+    Octo
+}
+
+/*
+ * Decode a single opcode, None when it isn't a known instruction.
+ */
+pub fn decode(opcode: u16) -> Option<Instruction> {
+
+    // unpack the opcode into 4 bit hex digits (nibbles)
+    let hex_digits = (
+        (opcode & 0xF000) >> 12,
+        (opcode & 0x0F00) >> 8,
+        (opcode & 0x00F0) >> 4,
+        opcode & 0x000F,
+    );
+
+    let kk = (opcode & 0x00FF) as u8;
+    let vx = hex_digits.1 as usize;
+    let vy = hex_digits.2 as usize;
+    let n = hex_digits.3 as u8;
+    let addr = (opcode & 0x0FFF) as usize;
+
+    let instruction = match hex_digits {
+        (0x00, 0x00, 0x0e, 0x0e) => Instruction::Ret,
+        (0x00, 0x00, 0x0e, 0x00) => Instruction::Cls,
+        (0x00, 0x00, 0x0c, _) => Instruction::ScrollDown(n),
+        (0x00, 0x00, 0x0d, _) => Instruction::ScrollUp(n),
+        (0x00, 0x00, 0x0f, 0x0b) => Instruction::ScrollRight,
+        (0x00, 0x00, 0x0f, 0x0c) => Instruction::ScrollLeft,
+        (0x00, 0x00, 0x0f, 0x0d) => Instruction::Exit,
+        (0x00, 0x00, 0x0f, 0x0e) => Instruction::Low,
+        (0x00, 0x00, 0x0f, 0x0f) => Instruction::High,
+        (0x00, _, _, _) => Instruction::Sys(addr),
+        (0x01, _, _, _) => Instruction::Jp(addr),
+        (0x02, _, _, _) => Instruction::Call(addr),
+        (0x03, _, _, _) => Instruction::SeByte(vx, kk),
+        (0x04, _, _, _) => Instruction::SneByte(vx, kk),
+        (0x05, _, _, 0x00) => Instruction::SeReg(vx, vy),
+        (0x05, _, _, 0x02) => Instruction::Save(vx, vy),
+        (0x05, _, _, 0x03) => Instruction::Load(vx, vy),
+        (0x06, _, _, _) => Instruction::LdByte(vx, kk),
+        (0x07, _, _, _) => Instruction::AddByte(vx, kk),
+        (0x08, _, _, 0x00) => Instruction::LdReg(vx, vy),
+        (0x08, _, _, 0x01) => Instruction::Or(vx, vy),
+        (0x08, _, _, 0x02) => Instruction::And(vx, vy),
+        (0x08, _, _, 0x03) => Instruction::Xor(vx, vy),
+        (0x08, _, _, 0x04) => Instruction::AddReg(vx, vy),
+        (0x08, _, _, 0x05) => Instruction::Sub(vx, vy),
+        (0x08, _, _, 0x06) => Instruction::Shr(vx, vy),
+        (0x08, _, _, 0x07) => Instruction::Subn(vx, vy),
+        (0x08, _, _, 0x0e) => Instruction::Shl(vx, vy),
+        (0x09, _, _, 0x00) => Instruction::SneReg(vx, vy),
+        (0x0a, _, _, _) => Instruction::LdI(addr),
+        (0x0b, _, _, _) => Instruction::JpV0(addr),
+        (0x0c, _, _, _) => Instruction::Rnd(vx, kk),
+        (0x0d, _, _, _) => Instruction::Drw(vx, vy, n),
+        (0x0e, _, 0x09, 0x0e) => Instruction::Skp(vx),
+        (0x0e, _, 0x0a, 0x01) => Instruction::Sknp(vx),
+        (0x0f, 0x00, 0x00, 0x00) => Instruction::LdILong,
+        (0x0f, _, 0x00, 0x01) => Instruction::Plane(vx as u8),
+        (0x0f, 0x00, 0x00, 0x02) => Instruction::Audio,
+        (0x0f, _, 0x00, 0x07) => Instruction::LdVxDt(vx),
+        (0x0f, _, 0x00, 0x0a) => Instruction::LdVxK(vx),
+        (0x0f, _, 0x01, 0x05) => Instruction::LdDtVx(vx),
+        (0x0f, _, 0x01, 0x08) => Instruction::LdStVx(vx),
+        (0x0f, _, 0x01, 0x0e) => Instruction::AddI(vx),
+        (0x0f, _, 0x02, 0x09) => Instruction::LdF(vx),
+        (0x0f, _, 0x03, 0x00) => Instruction::LdHf(vx),
+        (0x0f, _, 0x03, 0x03) => Instruction::LdB(vx),
+        (0x0f, _, 0x03, 0x0a) => Instruction::Pitch(vx),
+        (0x0f, _, 0x05, 0x05) => Instruction::LdIVx(vx),
+        (0x0f, _, 0x06, 0x05) => Instruction::LdVxI(vx),
+        (0x0f, _, 0x07, 0x05) => Instruction::LdRVx(vx),
+        (0x0f, _, 0x08, 0x05) => Instruction::LdVxR(vx),
+
+        _ => return None
+    };
+
+    Some(instruction)
+}
+
+impl Instruction {
+
+    /*
+     * The opcode for this instruction, the inverse of decode. Fields are
+     * masked to their width.
+     */
+    pub fn encode(&self) -> u16 {
+        let xy = |op: u16, vx: usize, vy: usize, n: u16| op | (vx as u16 & 0xf) << 8 | (vy as u16 & 0xf) << 4 | n;
+        let xkk = |op: u16, vx: usize, kk: u8| op | (vx as u16 & 0xf) << 8 | kk as u16;
+        let x = |op: u16, vx: usize| op | (vx as u16 & 0xf) << 8;
+        let nnn = |op: u16, addr: usize| op | (addr as u16 & 0xfff);
+
+        match *self {
+            Instruction::Sys(addr) => nnn(0x0000, addr),
+            Instruction::Cls => 0x00e0,
+            Instruction::Ret => 0x00ee,
+            Instruction::ScrollDown(n) => 0x00c0 | (n as u16 & 0xf),
+            Instruction::ScrollUp(n) => 0x00d0 | (n as u16 & 0xf),
+            Instruction::ScrollRight => 0x00fb,
+            Instruction::ScrollLeft => 0x00fc,
+            Instruction::Exit => 0x00fd,
+            Instruction::Low => 0x00fe,
+            Instruction::High => 0x00ff,
+            Instruction::Jp(addr) => nnn(0x1000, addr),
+            Instruction::Call(addr) => nnn(0x2000, addr),
+            Instruction::SeByte(vx, kk) => xkk(0x3000, vx, kk),
+            Instruction::SneByte(vx, kk) => xkk(0x4000, vx, kk),
+            Instruction::SeReg(vx, vy) => xy(0x5000, vx, vy, 0),
+            Instruction::Save(vx, vy) => xy(0x5000, vx, vy, 2),
+            Instruction::Load(vx, vy) => xy(0x5000, vx, vy, 3),
+            Instruction::LdByte(vx, kk) => xkk(0x6000, vx, kk),
+            Instruction::AddByte(vx, kk) => xkk(0x7000, vx, kk),
+            Instruction::LdReg(vx, vy) => xy(0x8000, vx, vy, 0),
+            Instruction::Or(vx, vy) => xy(0x8000, vx, vy, 1),
+            Instruction::And(vx, vy) => xy(0x8000, vx, vy, 2),
+            Instruction::Xor(vx, vy) => xy(0x8000, vx, vy, 3),
+            Instruction::AddReg(vx, vy) => xy(0x8000, vx, vy, 4),
+            Instruction::Sub(vx, vy) => xy(0x8000, vx, vy, 5),
+            Instruction::Shr(vx, vy) => xy(0x8000, vx, vy, 6),
+            Instruction::Subn(vx, vy) => xy(0x8000, vx, vy, 7),
+            Instruction::Shl(vx, vy) => xy(0x8000, vx, vy, 0xe),
+            Instruction::SneReg(vx, vy) => xy(0x9000, vx, vy, 0),
+            Instruction::LdI(addr) => nnn(0xa000, addr),
+            Instruction::JpV0(addr) => nnn(0xb000, addr),
+            Instruction::Rnd(vx, kk) => xkk(0xc000, vx, kk),
+            Instruction::Drw(vx, vy, n) => xy(0xd000, vx, vy, n as u16 & 0xf),
+            Instruction::Skp(vx) => x(0xe09e, vx),
+            Instruction::Sknp(vx) => x(0xe0a1, vx),
+            Instruction::LdILong => 0xf000,
+            Instruction::Plane(n) => x(0xf001, n as usize),
+            Instruction::Audio => 0xf002,
+            Instruction::LdVxDt(vx) => x(0xf007, vx),
+            Instruction::LdVxK(vx) => x(0xf00a, vx),
+            Instruction::LdDtVx(vx) => x(0xf015, vx),
+            Instruction::LdStVx(vx) => x(0xf018, vx),
+            Instruction::AddI(vx) => x(0xf01e, vx),
+            Instruction::LdF(vx) => x(0xf029, vx),
+            Instruction::LdHf(vx) => x(0xf030, vx),
+            Instruction::LdB(vx) => x(0xf033, vx),
+            Instruction::Pitch(vx) => x(0xf03a, vx),
+            Instruction::LdIVx(vx) => x(0xf055, vx),
+            Instruction::LdVxI(vx) => x(0xf065, vx),
+            Instruction::LdRVx(vx) => x(0xf075, vx),
+            Instruction::LdVxR(vx) => x(0xf085, vx),
+        }
+    }
+
+    /*
+     * Skips conditionally execute the following instruction, the
+     * disassembler follows both paths.
+     */
+    pub fn is_skip(&self) -> bool {
+        matches!(
+            self,
+            Instruction::SeByte(..) | Instruction::SneByte(..) | Instruction::SeReg(..) |
+            Instruction::SneReg(..) | Instruction::Skp(_) | Instruction::Sknp(_)
+        )
+    }
+
+    /*
+     * Render as assembly, `label` names addresses that have a label.
+     * LdILong renders without its operand, which isn't part of the opcode.
+     */
+    pub fn format(&self, syntax: Syntax, label: &dyn Fn(usize) -> Option<String>) -> String {
+        let target = |addr: usize| label(addr).unwrap_or_else(|| format!("{:#05X}", addr));
+
+        match (syntax, *self) {
+            // a bare label calls it, plain addresses need :call
+            (Syntax::Octo, Instruction::Call(addr)) => label(addr).unwrap_or_else(|| format!(":call {:#05X}", addr)),
+            (Syntax::Octo, _) => self.format_octo(&target),
+            (Syntax::Cowgod, _) => self.format_cowgod(&target),
+        }
+    }
+
+    fn format_cowgod(&self, target: &dyn Fn(usize) -> String) -> String {
+        match *self {
+            Instruction::Sys(addr) => format!("SYS {:#05X}", addr),
+            Instruction::Cls => String::from("CLS"),
+            Instruction::Ret => String::from("RET"),
+            Instruction::ScrollDown(n) => format!("SCD {}", n),
+            Instruction::ScrollUp(n) => format!("SCU {}", n),
+            Instruction::ScrollRight => String::from("SCR"),
+            Instruction::ScrollLeft => String::from("SCL"),
+            Instruction::Exit => String::from("EXIT"),
+            Instruction::Low => String::from("LOW"),
+            Instruction::High => String::from("HIGH"),
+            Instruction::Jp(addr) => format!("JP {}", target(addr)),
+            Instruction::Call(addr) => format!("CALL {}", target(addr)),
+            Instruction::SeByte(vx, kk) => format!("SE V{:X}, {:#04X}", vx, kk),
+            Instruction::SneByte(vx, kk) => format!("SNE V{:X}, {:#04X}", vx, kk),
+            Instruction::SeReg(vx, vy) => format!("SE V{:X}, V{:X}", vx, vy),
+            Instruction::Save(vx, vy) => format!("SAVE V{:X} - V{:X}", vx, vy),
+            Instruction::Load(vx, vy) => format!("LOAD V{:X} - V{:X}", vx, vy),
+            Instruction::LdByte(vx, kk) => format!("LD V{:X}, {:#04X}", vx, kk),
+            Instruction::AddByte(vx, kk) => format!("ADD V{:X}, {:#04X}", vx, kk),
+            Instruction::LdReg(vx, vy) => format!("LD V{:X}, V{:X}", vx, vy),
+            Instruction::Or(vx, vy) => format!("OR V{:X}, V{:X}", vx, vy),
+            Instruction::And(vx, vy) => format!("AND V{:X}, V{:X}", vx, vy),
+            Instruction::Xor(vx, vy) => format!("XOR V{:X}, V{:X}", vx, vy),
+            Instruction::AddReg(vx, vy) => format!("ADD V{:X}, V{:X}", vx, vy),
+            Instruction::Sub(vx, vy) => format!("SUB V{:X}, V{:X}", vx, vy),
+            Instruction::Shr(vx, vy) => format!("SHR V{:X}, V{:X}", vx, vy),
+            Instruction::Subn(vx, vy) => format!("SUBN V{:X}, V{:X}", vx, vy),
+            Instruction::Shl(vx, vy) => format!("SHL V{:X}, V{:X}", vx, vy),
+            Instruction::SneReg(vx, vy) => format!("SNE V{:X}, V{:X}", vx, vy),
+            Instruction::LdI(addr) => format!("LD I, {}", target(addr)),
+            Instruction::JpV0(addr) => format!("JP V0, {}", target(addr)),
+            Instruction::Rnd(vx, kk) => format!("RND V{:X}, {:#04X}", vx, kk),
+            Instruction::Drw(vx, vy, n) => format!("DRW V{:X}, V{:X}, {}", vx, vy, n),
+            Instruction::Skp(vx) => format!("SKP V{:X}", vx),
+            Instruction::Sknp(vx) => format!("SKNP V{:X}", vx),
+            Instruction::LdILong => String::from("LD I, long"),
+            Instruction::Plane(n) => format!("PLANE {}", n),
+            Instruction::Audio => String::from("AUDIO"),
+            Instruction::LdVxDt(vx) => format!("LD V{:X}, DT", vx),
+            Instruction::LdVxK(vx) => format!("LD V{:X}, K", vx),
+            Instruction::LdDtVx(vx) => format!("LD DT, V{:X}", vx),
+            Instruction::LdStVx(vx) => format!("LD ST, V{:X}", vx),
+            Instruction::AddI(vx) => format!("ADD I, V{:X}", vx),
+            Instruction::LdF(vx) => format!("LD F, V{:X}", vx),
+            Instruction::LdHf(vx) => format!("LD HF, V{:X}", vx),
+            Instruction::LdB(vx) => format!("LD B, V{:X}", vx),
+            Instruction::Pitch(vx) => format!("PITCH V{:X}", vx),
+            Instruction::LdIVx(vx) => format!("LD [I], V{:X}", vx),
+            Instruction::LdVxI(vx) => format!("LD V{:X}, [I]", vx),
+            Instruction::LdRVx(vx) => format!("LD R, V{:X}", vx),
+            Instruction::LdVxR(vx) => format!("LD V{:X}, R", vx),
+        }
+    }
+
+    fn format_octo(&self, target: &dyn Fn(usize) -> String) -> String {
+        match *self {
+            Instruction::Sys(addr) => format!("0x{:02X} 0x{:02X}", addr >> 8, addr & 0xff),
+            Instruction::Cls => String::from("clear"),
+            Instruction::Ret => String::from("return"),
+            Instruction::ScrollDown(n) => format!("scroll-down {}", n),
+            Instruction::ScrollUp(n) => format!("scroll-up {}", n),
+            Instruction::ScrollRight => String::from("scroll-right"),
+            Instruction::ScrollLeft => String::from("scroll-left"),
+            Instruction::Exit => String::from("exit"),
+            Instruction::Low => String::from("lores"),
+            Instruction::High => String::from("hires"),
+            Instruction::Jp(addr) => format!("jump {}", target(addr)),
+            Instruction::Call(addr) => format!(":call {}", target(addr)),
+            // Octo skips are written as the condition under which the next instruction runs
+            Instruction::SeByte(vx, kk) => format!("if v{:x} != {:#04X} then", vx, kk),
+            Instruction::SneByte(vx, kk) => format!("if v{:x} == {:#04X} then", vx, kk),
+            Instruction::SeReg(vx, vy) => format!("if v{:x} != v{:x} then", vx, vy),
+            Instruction::Save(vx, vy) => format!("save v{:x} - v{:x}", vx, vy),
+            Instruction::Load(vx, vy) => format!("load v{:x} - v{:x}", vx, vy),
+            Instruction::LdByte(vx, kk) => format!("v{:x} := {:#04X}", vx, kk),
+            Instruction::AddByte(vx, kk) => format!("v{:x} += {:#04X}", vx, kk),
+            Instruction::LdReg(vx, vy) => format!("v{:x} := v{:x}", vx, vy),
+            Instruction::Or(vx, vy) => format!("v{:x} |= v{:x}", vx, vy),
+            Instruction::And(vx, vy) => format!("v{:x} &= v{:x}", vx, vy),
+            Instruction::Xor(vx, vy) => format!("v{:x} ^= v{:x}", vx, vy),
+            Instruction::AddReg(vx, vy) => format!("v{:x} += v{:x}", vx, vy),
+            Instruction::Sub(vx, vy) => format!("v{:x} -= v{:x}", vx, vy),
+            Instruction::Shr(vx, vy) => format!("v{:x} >>= v{:x}", vx, vy),
+            Instruction::Subn(vx, vy) => format!("v{:x} =- v{:x}", vx, vy),
+            Instruction::Shl(vx, vy) => format!("v{:x} <<= v{:x}", vx, vy),
+            Instruction::SneReg(vx, vy) => format!("if v{:x} == v{:x} then", vx, vy),
+            Instruction::LdI(addr) => format!("i := {}", target(addr)),
+            Instruction::JpV0(addr) => format!("jump0 {}", target(addr)),
+            Instruction::Rnd(vx, kk) => format!("v{:x} := random {:#04X}", vx, kk),
+            Instruction::Drw(vx, vy, n) => format!("sprite v{:x} v{:x} {}", vx, vy, n),
+            Instruction::Skp(vx) => format!("if v{:x} -key then", vx),
+            Instruction::Sknp(vx) => format!("if v{:x} key then", vx),
+            Instruction::LdILong => String::from("i := long"),
+            Instruction::Plane(n) => format!("plane {}", n),
+            Instruction::Audio => String::from("audio"),
+            Instruction::LdVxDt(vx) => format!("v{:x} := delay", vx),
+            Instruction::LdVxK(vx) => format!("v{:x} := key", vx),
+            Instruction::LdDtVx(vx) => format!("delay := v{:x}", vx),
+            Instruction::LdStVx(vx) => format!("buzzer := v{:x}", vx),
+            Instruction::AddI(vx) => format!("i += v{:x}", vx),
+            Instruction::LdF(vx) => format!("i := hex v{:x}", vx),
+            Instruction::LdHf(vx) => format!("i := bighex v{:x}", vx),
+            Instruction::LdB(vx) => format!("bcd v{:x}", vx),
+            Instruction::Pitch(vx) => format!("pitch := v{:x}", vx),
+            Instruction::LdIVx(vx) => format!("save v{:x}", vx),
+            Instruction::LdVxI(vx) => format!("load v{:x}", vx),
+            Instruction::LdRVx(vx) => format!("saveflags v{:x}", vx),
+            Instruction::LdVxR(vx) => format!("loadflags v{:x}", vx),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.format(Syntax::Cowgod, &|_| None))
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn decode_opcodes() {
+        assert_eq!(decode(0x00e0), Some(Instruction::Cls));
+        assert_eq!(decode(0x00ee), Some(Instruction::Ret));
+        assert_eq!(decode(0x00c4), Some(Instruction::ScrollDown(4)));
+        assert_eq!(decode(0x0123), Some(Instruction::Sys(0x123)));
+        assert_eq!(decode(0x2abc), Some(Instruction::Call(0xabc)));
+        assert_eq!(decode(0x5122), Some(Instruction::Save(1, 2)));
+        assert_eq!(decode(0x8ab6), Some(Instruction::Shr(0xa, 0xb)));
+        assert_eq!(decode(0xd12f), Some(Instruction::Drw(1, 2, 0xf)));
+        assert_eq!(decode(0xf000), Some(Instruction::LdILong));
+        assert_eq!(decode(0xf201), Some(Instruction::Plane(2)));
+        assert_eq!(decode(0xf365), Some(Instruction::LdVxI(3)));
+
+        assert_eq!(decode(0x5121), None);
+        assert_eq!(decode(0x8128), None);
+        assert_eq!(decode(0x9121), None);
+        assert_eq!(decode(0xe19f), None);
+        assert_eq!(decode(0xf102), None);
+        assert_eq!(decode(0xf0ff), None);
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        for opcode in 0..=0xffff {
+            if let Some(instruction) = decode(opcode) {
+                assert_eq!(instruction.encode(), opcode, "{:?}", instruction);
+            }
+        }
+    }
+
+    #[test]
+    fn cowgod() {
+        assert_eq!(decode(0x6a02).unwrap().to_string(), "LD VA, 0x02");
+        assert_eq!(decode(0xd01f).unwrap().to_string(), "DRW V0, V1, 15");
+        assert_eq!(decode(0x1204).unwrap().to_string(), "JP 0x204");
+        assert_eq!(decode(0xf155).unwrap().to_string(), "LD [I], V1");
+        assert_eq!(decode(0x5132).unwrap().to_string(), "SAVE V1 - V3");
+    }
+
+    #[test]
+    fn octo() {
+        let label = |addr| (addr == 0x204).then(|| String::from("main"));
+        let octo = |opcode| decode(opcode).unwrap().format(Syntax::Octo, &label);
+
+        assert_eq!(octo(0x6a02), "va := 0x02");
+        assert_eq!(octo(0x1204), "jump main");
+        assert_eq!(octo(0x2204), "main");
+        assert_eq!(octo(0x2206), ":call 0x206");
+        assert_eq!(octo(0x3a02), "if va != 0x02 then");
+        assert_eq!(octo(0xe19e), "if v1 -key then");
+        assert_eq!(octo(0xd01f), "sprite v0 v1 15");
+        assert_eq!(octo(0xf329), "i := hex v3");
+    }
+}
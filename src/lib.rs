@@ -0,0 +1,28 @@
+pub mod assembler;
+pub mod audio;
+pub mod debugger;
+pub mod disasm;
+pub mod drivers;
+pub mod error;
+pub mod headless;
+pub mod instruction;
+pub mod menu;
+pub mod octo;
+pub mod processor;
+pub mod quirks;
+pub mod rewind;
+pub mod romdb;
+pub mod scheduler;
+pub mod settings;
+pub mod storage;
+pub mod timers;
+pub mod trace;
+mod font;
+
+#[cfg(feature = "sdl")]
+pub mod frontend;
+
+pub use drivers::CartridgeDriver;
+pub use error::{AsmError, ErrorKind, ExecutionError, RomError, SnapshotError};
+pub use processor::{OutputState, Processor};
+pub use quirks::Quirks;
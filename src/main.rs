@@ -1,43 +1,480 @@
-mod drivers;
-mod processor;
-mod font;
+use std::fmt::Display;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
 
-use std::thread;
-use std::time::Duration;
-use std::env;
-use std::time::Instant;
+use clap::error::ErrorKind as UsageError;
+use clap::{Args, CommandFactory, Parser, Subcommand};
 
-use drivers::{DisplayDriver, CartridgeDriver, InputDriver};
-use processor::Processor;
+use chip8_emu::instruction::Syntax;
+use chip8_emu::{assembler, disasm, headless, octo};
+use chip8_emu::rewind::{Rewind, DEFAULT_MEMORY_BUDGET, DEFAULT_REWIND_SECONDS};
+use chip8_emu::romdb::{RomDatabase, RomInfo, USER_FILE};
+use chip8_emu::scheduler::Speed;
+use chip8_emu::storage::Storage;
+use chip8_emu::settings::{parse_palette, Keymap, Overrides, Palette, WindowSettings, DEFAULT_SCALE};
+use chip8_emu::trace::{self, DiffOutcome, Timing, TraceFilter, TraceFormat, Tracer};
+use chip8_emu::{CartridgeDriver, Processor, Quirks};
 
-fn main() -> ! {
-    let rom_file_name = env::args().nth(1).unwrap();
-    let cartridge = CartridgeDriver::new(rom_file_name);
+/*
+ * chip8-emu [OPTIONS] ROM|ARCHIVE.zip[:NAME]|DIRECTORY|SOURCE.8o
+ * chip8-emu disasm [--octo] ROM
+ * chip8-emu asm SOURCE [-o ROM] [--listing FILE]
+ * chip8-emu asm SOURCE.8o [-o ROM]
+ * chip8-emu trace-diff [--before] ROM REFERENCE
+ * chip8-emu info ROM
+ */
+#[derive(Parser)]
+#[command(name = "chip8-emu", version, about = "A CHIP-8, SUPER-CHIP and XO-CHIP emulator")]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
 
-    let sdl_context = sdl2::init().unwrap();
+    #[command(flatten)]
+    run: RunArgs
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Disassemble a ROM")]
+    Disasm {
+        #[arg(long, help = "Use Octo syntax instead of Cowgod mnemonics")]
+        octo: bool,
+        #[arg(help = "ROM to disassemble")]
+        rom: PathBuf
+    },
+
+    #[command(about = "Assemble Cowgod style or Octo (.8o) source into a ROM")]
+    Asm {
+        #[arg(help = "Source file, compiled as Octo when it ends in .8o")]
+        source: PathBuf,
+        #[arg(short, long, value_name = "ROM", help = "Where to write the ROM [default: SOURCE.ch8]")]
+        output: Option<PathBuf>,
+        #[arg(long, value_name = "FILE", help = "Also write an address listing, not supported for Octo source")]
+        listing: Option<PathBuf>
+    },
+
+    #[command(about = "Run a ROM against a reference trace, stopping at the first divergence")]
+    TraceDiff {
+        #[arg(long, help = "The reference logs registers before each instruction instead of after")]
+        before: bool,
+        #[arg(help = "ROM to run")]
+        rom: PathBuf,
+        #[arg(help = "Reference trace, text or binary")]
+        reference: PathBuf,
+        #[command(flatten)]
+        machine: MachineArgs
+    },
+
+    #[command(about = "Show a ROM's SHA-1 and what the ROM database knows about it")]
+    Info {
+        #[arg(help = "ROM to look up")]
+        rom: PathBuf
+    }
+}
+
+/*
+ * Options that change how the program itself runs. Unset ones come from
+ * the ROM database when it knows the ROM.
+ */
+#[derive(Args)]
+struct MachineArgs {
+    #[arg(long, value_name = "PRESET", value_parser = parse_quirks,
+          help = "Interpreter to emulate: vip, chip48, schip or xochip [default: vip]")]
+    quirks: Option<Quirks>,
+
+    #[arg(long, value_name = "HZ", help = "Instructions per second, or 'unlimited' [default: 700]")]
+    cpu_hz: Option<Speed>,
+
+    #[arg(long, help = "Seed the random number generator for a repeatable run")]
+    seed: Option<u64>
+}
+
+#[derive(Args)]
+struct RunArgs {
+    #[arg(required = true, help = "ROM to run: a file, a zip archive, ARCHIVE.zip:NAME, a directory to pick from, or Octo source ending in .8o")]
+    rom: Option<PathBuf>,
+
+    #[command(flatten)]
+    machine: MachineArgs,
+
+    #[arg(long, default_value_t = DEFAULT_SCALE, value_parser = clap::value_parser!(u32).range(1..=64),
+          help = "Window pixels per CHIP-8 pixel")]
+    scale: u32,
+
+    #[arg(long, value_name = "COLOURS", value_parser = parse_palette,
+          help = "A named palette (default, mono, amber, lcd, octo), background and foreground as hex colours, e.g. 000000,ffffff, or all four XO-CHIP colours")]
+    palette: Option<Palette>,
+
+    #[arg(long, help = "Turn the sound off")]
+    mute: bool,
+
+    #[arg(long, value_name = "FILE", help = "Keypad mapping, one 'KEY = NAME' line per keypad key")]
+    keymap: Option<PathBuf>,
+
+    #[arg(long, requires = "frames", help = "Run without a window and print the screen at the end")]
+    headless: bool,
+
+    #[arg(long, value_name = "N", requires = "headless", help = "Number of 60 Hz frames to run headless")]
+    frames: Option<u32>,
+
+    #[arg(long, conflicts_with = "headless", help = "Start paused with a debugger on the terminal")]
+    debug: bool,
+
+    #[arg(long, value_name = "SECONDS", default_value_t = DEFAULT_REWIND_SECONDS, conflicts_with = "headless",
+          help = "How far holding backspace can rewind, 0 turns rewinding off")]
+    rewind_seconds: usize,
+
+    #[arg(long, value_name = "MIB", default_value_t = DEFAULT_MEMORY_BUDGET >> 20, conflicts_with = "headless",
+          help = "Memory the rewind buffer may use, older frames are dropped beyond it")]
+    rewind_budget: usize,
+
+    #[command(flatten)]
+    trace: TraceArgs
+}
+
+#[derive(Args)]
+#[command(next_help_heading = "Tracing")]
+struct TraceArgs {
+    #[arg(long, value_name = "FILE", help = "Write every executed instruction to FILE")]
+    trace: Option<PathBuf>,
+
+    #[arg(long, value_name = "FORMAT", requires = "trace", help = "text or binary [default: text]")]
+    trace_format: Option<TraceFormat>,
+
+    #[arg(long, value_name = "START-END", requires = "trace", value_parser = TraceFilter::parse_range,
+          help = "Only trace instructions in this hex address range, e.g. 200-2ff")]
+    trace_range: Option<(usize, usize)>,
+
+    #[arg(long, value_name = "CLASSES", requires = "trace", value_parser = TraceFilter::parse_classes,
+          help = "Only trace these opcode classes (high nibbles), e.g. 8,d")]
+    trace_class: Option<u16>,
+
+    #[arg(long, value_name = "N", requires = "trace", help = "Stop tracing after N instructions")]
+    trace_limit: Option<u64>
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    match cli.command {
+        Some(Command::Disasm { octo, rom }) => run_disassembler(&rom, octo),
+        Some(Command::Asm { source, output, listing }) => run_assembler(&source, output, listing),
+        Some(Command::TraceDiff { before, rom, reference, machine }) => run_trace_diff(&rom, &reference, &machine, before),
+        Some(Command::Info { rom }) => run_info(&rom),
+        None => run(cli.run),
+    }
+}
+
+/*
+ * Print an error and exit, for failures past argument parsing.
+ */
+fn fail(message: impl Display) -> ! {
+    eprintln!("error: {}", message);
+    process::exit(1);
+}
+
+fn parse_quirks(name: &str) -> Result<Quirks, String> {
+    Quirks::preset(name).ok_or_else(|| {
+        let names: Vec<&str> = Quirks::PRESETS.iter().map(|&(name, _)| name).collect();
+        format!("unknown preset '{}', expected one of {}", name, names.join(", "))
+    })
+}
+
+/*
+ * The bundled ROM database and the user's additions, falling back to
+ * just the bundled one when the user file is broken.
+ */
+fn rom_database() -> RomDatabase {
+    RomDatabase::load(&Storage::new()).unwrap_or_else(|err| {
+        eprintln!("warning: {}", err);
+        RomDatabase::bundled()
+    })
+}
+
+impl MachineArgs {
+
+    /*
+     * A processor with the ROM loaded, exiting when it doesn't fit.
+     */
+    fn processor(&self, cartridge: &CartridgeDriver, info: Option<&RomInfo>) -> Processor {
+        let quirks = self.overrides().quirks(info);
+
+        match cartridge.check(&quirks) {
+            Ok(Some(warning)) => eprintln!("warning: {}", warning),
+            Ok(None) => {}
+            Err(err) if !quirks.extended_memory && cartridge.check(&Quirks::XO_CHIP).is_ok() => {
+                fail(format!("{}, try --quirks xochip", err));
+            }
+            Err(err) => fail(err),
+        }
+
+        let mut processor = Processor::new(quirks);
+        processor.load(cartridge.rom());
+        if let Some(seed) = self.seed {
+            processor.seed_rng(seed);
+        }
+        processor
+    }
+
+    fn overrides(&self) -> Overrides {
+        Overrides {
+            quirks: self.quirks,
+            speed: self.cpu_hz,
+            seed: self.seed,
+            ..Overrides::default()
+        }
+    }
+}
+
+impl TraceArgs {
+
+    fn tracer(&self) -> Option<Tracer> {
+        let path = self.trace.as_ref()?;
+
+        let mut filter = TraceFilter::default();
+        if let Some((start, end)) = self.trace_range {
+            (filter.start, filter.end) = (start, end);
+        }
+        if let Some(classes) = self.trace_class {
+            filter.classes = classes;
+        }
+        filter.limit = self.trace_limit;
+
+        match Tracer::create(path, self.trace_format.unwrap_or_default(), filter) {
+            Ok(tracer) => Some(tracer),
+            Err(err) => fail(format!("could not create {}: {}", path.display(), err)),
+        }
+    }
+}
+
+/*
+ * Load a ROM, compiling it first when it's Octo source.
+ */
+fn load_rom(path: &Path) -> CartridgeDriver {
+    if is_octo_source(path) {
+        return CartridgeDriver::from_rom(&compile_source(path));
+    }
+
+    CartridgeDriver::new(path).unwrap_or_else(|err| fail(format!("could not load {}: {}", path.display(), err)))
+}
+
+fn run(args: RunArgs) {
+    let Some(rom_path) = args.rom.as_deref() else {
+        Cli::command().error(UsageError::MissingRequiredArgument, "a ROM to run is required").exit();
+    };
+
+    let rom_path = if rom_path.is_dir() { pick_rom(rom_path) } else { rom_path.to_path_buf() };
+    let cartridge = load_rom(&rom_path);
+    let database = rom_database();
+    let info = database.get(&cartridge.hash());
+    if let Some(info) = info {
+        eprintln!("{}", info);
+    }
+
+    let speed = args.machine.overrides().speed(info);
+    let mut processor = args.machine.processor(&cartridge, info);
+    processor.set_tracer(args.trace.tracer());
+
+    if args.headless {
+        run_headless(processor, speed, args.frames.unwrap_or_default());
+        return;
+    }
+
+    let overrides = Overrides {
+        palette: args.palette,
+        keymap: args.keymap.as_ref().map(|path| Keymap::load(path).unwrap_or_else(|err| fail(err))),
+        ..args.machine.overrides()
+    };
+    let settings = WindowSettings {
+        scale: args.scale,
+        palette: overrides.palette(info),
+        muted: args.mute,
+        keymap: overrides.keymap(info),
+        rom_dir: match rom_path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        },
+        overrides
+    };
+    let rewind = Rewind::new(args.rewind_seconds.saturating_mul(60), args.rewind_budget.saturating_mul(1 << 20));
+    run_window(cartridge, processor, speed, &settings, rewind, args.debug);
+}
+
+/*
+ * List the roms in a directory on the terminal and read the number of
+ * the one to run.
+ */
+fn pick_rom(dir: &Path) -> PathBuf {
+    let roms = CartridgeDriver::scan(dir).unwrap_or_else(|err| fail(format!("could not read {}: {}", dir.display(), err)));
+    if roms.is_empty() {
+        fail(format!("no roms in {}", dir.display()));
+    }
+
+    for (n, rom) in roms.iter().enumerate() {
+        println!("{:>3}  {}", n + 1, rom.strip_prefix(dir).unwrap_or(rom).display());
+    }
+
+    loop {
+        print!("rom to run (1-{}, q to quit): ", roms.len());
+        let _ = io::stdout().flush();
+
+        let mut line = String::new();
+        match io::stdin().read_line(&mut line) {
+            Ok(0) => process::exit(0),
+            Ok(_) => {}
+            Err(err) => fail(err),
+        }
+
+        match line.trim() {
+            "q" | "" => process::exit(0),
+            choice => match choice.parse::<usize>() {
+                Ok(n) if (1..=roms.len()).contains(&n) => return roms[n - 1].clone(),
+                _ => eprintln!("no rom number {}", choice),
+            },
+        }
+    }
+}
+
+fn run_disassembler(rom_path: &Path, octo: bool) {
+    let syntax = if octo { Syntax::Octo } else { Syntax::Cowgod };
+    let cartridge = CartridgeDriver::new(rom_path).unwrap_or_else(|err| fail(format!("could not load {}: {}", rom_path.display(), err)));
+
+    print!("{}", disasm::disassemble(cartridge.rom(), syntax));
+}
+
+fn is_octo_source(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "8o")
+}
+
+/*
+ * Compile Octo (.8o) or assembler source, exiting with the error if it doesn't build.
+ */
+fn compile_source(path: &Path) -> Vec<u8> {
+    let source = fs::read_to_string(path).unwrap_or_else(|err| fail(format!("could not read {}: {}", path.display(), err)));
+
+    let result = if is_octo_source(path) {
+        octo::compile(&source, path)
+    } else {
+        assembler::assemble(&source, path).map(|assembly| assembly.rom)
+    };
+    result.unwrap_or_else(|err| fail(err))
+}
 
-    let mut display = DisplayDriver::new(&sdl_context);
-    let mut input: InputDriver = InputDriver::new(&sdl_context);
-    let mut processor = Processor::new();
-    processor.load(&cartridge.rom);
+fn run_assembler(source_path: &Path, output: Option<PathBuf>, listing: Option<PathBuf>) {
+    if listing.is_some() && is_octo_source(source_path) {
+        let mut cli = Cli::command();
+        cli.build();
+        let asm = cli.find_subcommand_mut("asm").expect("asm subcommand");
+        asm.error(UsageError::ArgumentConflict, "--listing is only supported for Cowgod style source, not Octo (.8o)").exit();
+    }
+    let rom_path = output.unwrap_or_else(|| source_path.with_extension("ch8"));
 
-    let sleep_duration = Duration::from_millis(2);
+    let rom = match listing {
+        Some(listing_path) => {
+            let source = fs::read_to_string(source_path).unwrap_or_else(|err| fail(format!("could not read {}: {}", source_path.display(), err)));
+            let assembly = assembler::assemble(&source, source_path).unwrap_or_else(|err| fail(err));
+            if let Err(err) = fs::write(&listing_path, assembly.listing()) {
+                fail(format!("could not write {}: {}", listing_path.display(), err));
+            }
+            assembly.rom
+        }
+        None => compile_source(source_path),
+    };
 
-    let mut start = Instant::now();
+    if let Err(err) = fs::write(&rom_path, &rom) {
+        fail(format!("could not write {}: {}", rom_path.display(), err));
+    }
+    println!("wrote {} bytes to {}", rom.len(), rom_path.display());
+}
 
-    'running: loop {
+fn run_trace_diff(rom_path: &Path, reference_path: &Path, machine: &MachineArgs, before: bool) {
+    let timing = if before { Timing::Before } else { Timing::After };
 
-        let delta = start.elapsed();
+    let data = fs::read(reference_path).unwrap_or_else(|err| fail(format!("could not read {}: {}", reference_path.display(), err)));
+    let reference = trace::parse_reference(&data).unwrap_or_else(|err| fail(format!("{}: {}", reference_path.display(), err)));
 
-        let keymap = input.update().unwrap();
-        let state = processor.tick(delta, keymap);
+    let cartridge = load_rom(rom_path);
+    let database = rom_database();
+    let info = database.get(&cartridge.hash());
+    let mut processor = machine.processor(&cartridge, info);
 
-        if state.vram_changed {
-            display.draw(&state.vram);
+    match trace::diff(&mut processor, &reference, machine.overrides().speed(info), timing) {
+        DiffOutcome::Matched(steps) => println!("{} steps match the reference", steps),
+        DiffOutcome::Diverged(divergence) => {
+            println!("{}", divergence);
+            process::exit(1);
         }
+    }
+}
+
+fn run_info(rom_path: &Path) {
+    let cartridge = load_rom(rom_path);
+    let hash = cartridge.hash();
+
+    println!("size      {} bytes", cartridge.size());
+    println!("sha1      {}", hash);
 
-        start = Instant::now();
-        thread::sleep(sleep_duration);
+    let database = rom_database();
+    let Some(info) = database.get(&hash) else {
+        println!("not in the ROM database, add it as [{}] to {}", hash, Storage::new().file(USER_FILE).display());
+        return;
+    };
+
+    let field = |name: &str, value: Option<String>| {
+        if let Some(value) = value {
+            println!("{:<10}{}", name, value);
+        }
+    };
+    field("title", info.title.clone());
+    field("author", info.author.clone());
+    field("platform", info.platform.clone());
+    if !info.quirks.is_empty() {
+        let quirks: Vec<String> = info.quirks.iter().map(|(name, enabled)| format!("{}{}", if *enabled { "" } else { "!" }, name)).collect();
+        field("quirks", Some(quirks.join(", ")));
+    }
+    field("cpu-hz", info.speed.map(|speed| match speed {
+        Speed::Hz(hz) => hz.to_string(),
+        Speed::Unlimited => String::from("unlimited"),
+    }));
+    if !info.keys.is_empty() {
+        let keys: Vec<String> = info.keys.iter().map(|(key, name)| format!("{}:{}", key, name)).collect();
+        field("keys", Some(keys.join(", ")));
     }
-    
+    field("palette", info.palette.map(|palette| {
+        let colours: Vec<String> = palette.iter().map(|(r, g, b)| format!("{:02x}{:02x}{:02x}", r, g, b)).collect();
+        colours.join(", ")
+    }));
+}
+
+fn run_headless(mut processor: Processor, speed: Speed, frames: u32) {
+    let result = headless::run(&mut processor, speed, frames);
+
+    if let Some(tracer) = processor.take_tracer() {
+        if let Err(err) = tracer.finish() {
+            eprintln!("could not write trace: {}", err);
+        }
+    }
+
+    print!("{}", headless::dump_framebuffer(&processor.output()));
+
+    if let Err(err) = result {
+        fail(format!("execution halted: {}", err));
+    }
+}
+
+#[cfg(feature = "sdl")]
+fn run_window(cartridge: CartridgeDriver, processor: Processor, speed: Speed, settings: &WindowSettings, rewind: Rewind, debug: bool) {
+    let debugger = debug.then(chip8_emu::debugger::Debugger::new);
+
+    if let Err(err) = chip8_emu::frontend::run(cartridge, processor, speed, settings, rewind, debugger) {
+        fail(err);
+    }
+}
+
+#[cfg(not(feature = "sdl"))]
+fn run_window(_cartridge: CartridgeDriver, _processor: Processor, _speed: Speed, _settings: &WindowSettings, _rewind: Rewind, _debug: bool) {
+    fail("built without the sdl feature, use --headless --frames N");
 }
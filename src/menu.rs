@@ -0,0 +1,574 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::drivers::CartridgeDriver;
+use crate::quirks::Quirks;
+use crate::scheduler::Speed;
+use crate::settings::{palette_name, Palette, PALETTES};
+
+// the cpu speeds the menu steps through
+const SPEEDS: [Speed; 7] = [
+    Speed::Hz(500),
+    Speed::Hz(700),
+    Speed::Hz(1000),
+    Speed::Hz(1500),
+    Speed::Hz(2000),
+    Speed::Hz(5000),
+    Speed::Unlimited
+];
+
+// left and right move this many entries in the rom browser
+const BROWSE_PAGE: usize = 10;
+
+const HINT: &str = "arrows/d-pad move, enter/A select, backspace/B back, esc/start close";
+
+/*
+ * Menu navigation, from the keyboard or a gamepad.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MenuInput {
+    Up,
+    Down,
+    Left,
+    Right,
+    Select,
+    Back
+}
+
+/*
+ * What the frontend should do in response to a menu input.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum MenuAction {
+    Resume,
+    Open(PathBuf),
+    Reset,
+    SaveState(u8),
+    LoadState(u8),
+    SetSpeed(Speed),
+    SetPalette(Palette),
+    SetQuirks(Quirks),
+    Quit
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Item {
+    Resume,
+    Open,
+    Reset,
+    Save,
+    Load,
+    Speed,
+    Palette,
+    Quirks,
+    Quit
+}
+
+const ITEMS: [Item; 9] = [
+    Item::Resume,
+    Item::Open,
+    Item::Reset,
+    Item::Save,
+    Item::Load,
+    Item::Speed,
+    Item::Palette,
+    Item::Quirks,
+    Item::Quit
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    label: String,
+    path: PathBuf,
+    dir: bool
+}
+
+enum Page {
+    Main,
+    Browse(Vec<Entry>),
+    Quirks
+}
+
+/*
+ * What to draw for the menu: a title, the visible lines with the
+ * selected one marked, and a help or status line.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MenuView {
+    pub title: String,
+    pub lines: Vec<String>,
+    pub selected: usize,
+    pub footer: String
+}
+
+/*
+ * The pause menu. It only keeps track of what is selected and shown,
+ * changes are handed to the frontend as MenuActions.
+ */
+pub struct Menu {
+    page: Page,
+    selected: usize,
+    slots: u8,
+    slot: u8,
+    speed: Speed,
+    palette: Palette,
+    quirks: Quirks,
+    dir: PathBuf,
+    message: Option<String>
+}
+
+impl Menu {
+
+    /*
+     * A menu offering `slots` save slots and browsing roms from `dir`.
+     */
+    pub fn new<P: AsRef<Path>>(dir: P, slots: u8) -> Self {
+        let dir = dir.as_ref();
+        Menu {
+            page: Page::Main,
+            selected: 0,
+            slots,
+            slot: 1,
+            speed: Speed::default(),
+            palette: PALETTES[0].1,
+            quirks: Quirks::default(),
+            dir: fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf()),
+            message: None
+        }
+    }
+
+    /*
+     * Show the main page with the current settings.
+     */
+    pub fn open(&mut self, speed: Speed, palette: Palette, quirks: Quirks) {
+        self.page = Page::Main;
+        self.selected = 0;
+        self.speed = speed;
+        self.palette = palette;
+        self.quirks = quirks;
+        self.message = None;
+    }
+
+    /*
+     * A status line to show until the next input, e.g. why a rom
+     * didn't load.
+     */
+    pub fn set_message<S: Into<String>>(&mut self, message: S) {
+        self.message = Some(message.into());
+    }
+
+    pub fn handle(&mut self, input: MenuInput) -> Option<MenuAction> {
+        self.message = None;
+
+        let len = self.len();
+        match input {
+            MenuInput::Up => {
+                self.selected = (self.selected + len - 1) % len;
+                return None;
+            }
+            MenuInput::Down => {
+                self.selected = (self.selected + 1) % len;
+                return None;
+            }
+            _ => {}
+        }
+
+        match self.page {
+            Page::Main => self.handle_main(input),
+            Page::Browse(_) => self.handle_browse(input),
+            Page::Quirks => self.handle_quirks(input),
+        }
+    }
+
+    fn handle_main(&mut self, input: MenuInput) -> Option<MenuAction> {
+        let item = ITEMS[self.selected];
+        let step = match input {
+            MenuInput::Back => return Some(MenuAction::Resume),
+            MenuInput::Left => -1,
+            MenuInput::Right | MenuInput::Select => 1,
+            _ => return None,
+        };
+
+        match (item, input) {
+            (Item::Save | Item::Load, MenuInput::Left | MenuInput::Right) => {
+                self.slot = ((self.slot as i32 - 1 + step).rem_euclid(self.slots as i32) + 1) as u8;
+                None
+            }
+            (Item::Speed, _) => {
+                let speed = step_speed(self.speed, step)?;
+                self.speed = speed;
+                Some(MenuAction::SetSpeed(speed))
+            }
+            (Item::Palette, _) => {
+                self.palette = step_palette(&self.palette, step);
+                Some(MenuAction::SetPalette(self.palette))
+            }
+            (_, MenuInput::Select) => match item {
+                Item::Resume => Some(MenuAction::Resume),
+                Item::Open => {
+                    self.browse(self.dir.clone());
+                    None
+                }
+                Item::Reset => Some(MenuAction::Reset),
+                Item::Save => Some(MenuAction::SaveState(self.slot)),
+                Item::Load => Some(MenuAction::LoadState(self.slot)),
+                Item::Quirks => {
+                    self.page = Page::Quirks;
+                    self.selected = 0;
+                    None
+                }
+                Item::Quit => Some(MenuAction::Quit),
+                Item::Speed | Item::Palette => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn handle_browse(&mut self, input: MenuInput) -> Option<MenuAction> {
+        let Page::Browse(entries) = &self.page else { return None };
+
+        match input {
+            MenuInput::Left => self.selected = self.selected.saturating_sub(BROWSE_PAGE),
+            MenuInput::Right => self.selected = (self.selected + BROWSE_PAGE).min(entries.len().saturating_sub(1)),
+            MenuInput::Back => self.back_to(Item::Open),
+            MenuInput::Select => {
+                let entry = entries.get(self.selected)?.clone();
+                if !entry.dir {
+                    return Some(MenuAction::Open(entry.path));
+                }
+                self.browse(entry.path);
+            }
+            _ => {}
+        }
+        None
+    }
+
+    fn handle_quirks(&mut self, input: MenuInput) -> Option<MenuAction> {
+        let Some(&name) = Quirks::FLAGS.get(self.selected) else {
+            // the last line leads back
+            if input == MenuInput::Select || input == MenuInput::Back {
+                self.back_to(Item::Quirks);
+            }
+            return None;
+        };
+
+        match input {
+            MenuInput::Back => {
+                self.back_to(Item::Quirks);
+                None
+            }
+            MenuInput::Left | MenuInput::Right | MenuInput::Select => {
+                let enabled = self.quirks.get(name).unwrap_or_default();
+                self.quirks.set(name, !enabled);
+                Some(MenuAction::SetQuirks(self.quirks))
+            }
+            _ => None,
+        }
+    }
+
+    fn back_to(&mut self, item: Item) {
+        self.page = Page::Main;
+        self.selected = ITEMS.iter().position(|&i| i == item).unwrap_or_default();
+    }
+
+    /*
+     * Show the contents of `dir`, staying on the current page when it
+     * can't be read.
+     */
+    fn browse(&mut self, dir: PathBuf) {
+        match list_dir(&dir) {
+            Ok(entries) => {
+                // select the directory we came from when going up
+                self.selected = entries.iter().position(|entry| entry.path == self.dir).unwrap_or_default();
+                self.page = Page::Browse(entries);
+                self.dir = dir;
+            }
+            Err(err) => self.message = Some(format!("could not read {}: {}", dir.display(), err)),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match &self.page {
+            Page::Main => ITEMS.len(),
+            Page::Browse(entries) => entries.len().max(1),
+            Page::Quirks => Quirks::FLAGS.len() + 1,
+        }
+    }
+
+    fn labels(&self) -> Vec<String> {
+        match &self.page {
+            Page::Main => ITEMS.iter().map(|&item| self.item_label(item)).collect(),
+            Page::Browse(entries) if entries.is_empty() => vec![String::from("no roms here")],
+            Page::Browse(entries) => entries.iter().map(|entry| entry.label.clone()).collect(),
+            Page::Quirks => Quirks::FLAGS
+                .iter()
+                .map(|name| {
+                    let enabled = self.quirks.get(name).unwrap_or_default();
+                    format!("[{}] {}", if enabled { "x" } else { " " }, name.replace('_', " "))
+                })
+                .chain([String::from("back")])
+                .collect(),
+        }
+    }
+
+    fn item_label(&self, item: Item) -> String {
+        let value = |name: &str, value: String| format!("{:<12}< {} >", name, value);
+        match item {
+            Item::Resume => String::from("resume"),
+            Item::Open => String::from("open rom"),
+            Item::Reset => String::from("reset"),
+            Item::Save => value("save state", format!("slot {}", self.slot)),
+            Item::Load => value("load state", format!("slot {}", self.slot)),
+            Item::Speed => value("speed", self.speed.to_string()),
+            Item::Palette => value("palette", String::from(palette_name(&self.palette).unwrap_or("custom"))),
+            Item::Quirks => String::from("quirks"),
+            Item::Quit => String::from("quit"),
+        }
+    }
+
+    /*
+     * The menu laid out in `rows` lines of `columns` characters: the
+     * title, a blank line, the items scrolled to keep the selection in
+     * view, a blank line and the footer. Long lines are cut off, long
+     * directory names keep their end.
+     */
+    pub fn view(&self, rows: usize, columns: usize) -> MenuView {
+        let title = match self.page {
+            Page::Main => String::from("paused"),
+            Page::Browse(_) => {
+                let dir = self.dir.display().to_string();
+                let chars = dir.chars().count();
+                if chars > columns {
+                    dir.chars().skip(chars - columns).collect()
+                } else {
+                    dir
+                }
+            }
+            Page::Quirks => String::from("quirks"),
+        };
+
+        let labels = self.labels();
+        let visible = rows.saturating_sub(4).max(1);
+        let first = self.selected
+            .saturating_sub(visible / 2)
+            .min(labels.len().saturating_sub(visible));
+
+        let lines = labels
+            .iter()
+            .enumerate()
+            .skip(first)
+            .take(visible)
+            .map(|(n, label)| {
+                let marker = if n == self.selected { "> " } else { "  " };
+                format!("{}{}", marker, label).chars().take(columns).collect()
+            })
+            .collect();
+
+        let footer = self.message.clone().unwrap_or_else(|| String::from(HINT));
+        MenuView {
+            title,
+            lines,
+            selected: self.selected - first,
+            footer: footer.chars().take(columns).collect()
+        }
+    }
+}
+
+/*
+ * The next faster (step 1) or slower (step -1) speed from SPEEDS, None
+ * at either end.
+ */
+fn step_speed(speed: Speed, step: i32) -> Option<Speed> {
+    let rank = |speed: &Speed| match *speed {
+        Speed::Hz(hz) => hz,
+        Speed::Unlimited => u32::MAX,
+    };
+
+    if step > 0 {
+        SPEEDS.iter().find(|s| rank(s) > rank(&speed)).copied()
+    } else {
+        SPEEDS.iter().rev().find(|s| rank(s) < rank(&speed)).copied()
+    }
+}
+
+/*
+ * The next or previous named palette, wrapping around. A custom palette
+ * steps to the first or last one.
+ */
+fn step_palette(palette: &Palette, step: i32) -> Palette {
+    let len = PALETTES.len() as i32;
+    let index = match PALETTES.iter().position(|(_, named)| named == palette) {
+        Some(index) => (index as i32 + step).rem_euclid(len),
+        None if step > 0 => 0,
+        None => len - 1,
+    };
+    PALETTES[index as usize].1
+}
+
+/*
+ * The parent directory, the visible subdirectories and the roms in a
+ * directory, as CartridgeDriver::scan finds them.
+ */
+fn list_dir(dir: &Path) -> io::Result<Vec<Entry>> {
+    let mut dirs: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_dir() && !path.file_name().is_some_and(|name| name.to_string_lossy().starts_with('.')))
+        .collect();
+    dirs.sort();
+
+    let mut entries = Vec::new();
+    if let Some(parent) = dir.parent() {
+        entries.push(Entry { label: String::from("../"), path: parent.to_path_buf(), dir: true });
+    }
+    entries.extend(dirs.into_iter().map(|path| Entry {
+        label: format!("{}/", path.file_name().unwrap_or_default().to_string_lossy()),
+        path,
+        dir: true
+    }));
+    entries.extend(CartridgeDriver::scan(dir)?.into_iter().map(|path| Entry {
+        label: path.strip_prefix(dir).unwrap_or(&path).display().to_string(),
+        path,
+        dir: false
+    }));
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("chip8-menu-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::canonicalize(dir).unwrap()
+    }
+
+    fn select(menu: &mut Menu, label: &str) {
+        let index = menu.labels().iter().position(|l| l.starts_with(label)).unwrap();
+        menu.selected = index;
+    }
+
+    #[test]
+    fn main_page() {
+        let mut menu = Menu::new(".", 4);
+        menu.open(Speed::Hz(700), PALETTES[0].1, Quirks::default());
+
+        assert_eq!(menu.handle(MenuInput::Select), Some(MenuAction::Resume));
+        assert_eq!(menu.handle(MenuInput::Up), None);
+        assert_eq!(menu.handle(MenuInput::Select), Some(MenuAction::Quit));
+        assert_eq!(menu.handle(MenuInput::Back), Some(MenuAction::Resume));
+
+        select(&mut menu, "save state");
+        assert_eq!(menu.handle(MenuInput::Left), None);
+        assert_eq!(menu.handle(MenuInput::Select), Some(MenuAction::SaveState(4)));
+        assert_eq!(menu.handle(MenuInput::Right), None);
+        select(&mut menu, "load state");
+        assert_eq!(menu.handle(MenuInput::Select), Some(MenuAction::LoadState(1)));
+
+        select(&mut menu, "speed");
+        assert_eq!(menu.handle(MenuInput::Right), Some(MenuAction::SetSpeed(Speed::Hz(1000))));
+        assert_eq!(menu.handle(MenuInput::Left), Some(MenuAction::SetSpeed(Speed::Hz(700))));
+        menu.open(Speed::Hz(5000), PALETTES[0].1, Quirks::default());
+        select(&mut menu, "speed");
+        assert_eq!(menu.handle(MenuInput::Right), Some(MenuAction::SetSpeed(Speed::Unlimited)));
+        assert_eq!(menu.handle(MenuInput::Right), None);
+        assert!(menu.labels()[menu.selected].ends_with("< unlimited >"));
+
+        select(&mut menu, "palette");
+        assert_eq!(menu.handle(MenuInput::Left), Some(MenuAction::SetPalette(PALETTES[PALETTES.len() - 1].1)));
+        assert_eq!(menu.handle(MenuInput::Right), Some(MenuAction::SetPalette(PALETTES[0].1)));
+    }
+
+    #[test]
+    fn custom_palette() {
+        let custom = [(1, 2, 3); 4];
+        assert_eq!(step_palette(&custom, 1), PALETTES[0].1);
+        assert_eq!(step_palette(&custom, -1), PALETTES[PALETTES.len() - 1].1);
+        assert_eq!(step_speed(Speed::Hz(600), 1), Some(Speed::Hz(700)));
+        assert_eq!(step_speed(Speed::Hz(600), -1), Some(Speed::Hz(500)));
+    }
+
+    #[test]
+    fn quirks_page() {
+        let mut menu = Menu::new(".", 4);
+        menu.open(Speed::default(), PALETTES[0].1, Quirks::COSMAC_VIP);
+        select(&mut menu, "quirks");
+        assert_eq!(menu.handle(MenuInput::Select), None);
+        assert_eq!(menu.labels()[0], "[x] shift uses vy");
+
+        let expected = Quirks { shift_uses_vy: false, ..Quirks::COSMAC_VIP };
+        assert_eq!(menu.handle(MenuInput::Select), Some(MenuAction::SetQuirks(expected)));
+        assert_eq!(menu.labels()[0], "[ ] shift uses vy");
+
+        // wrap around to "back"
+        assert_eq!(menu.handle(MenuInput::Up), None);
+        assert_eq!(menu.handle(MenuInput::Select), None);
+        assert_eq!(ITEMS[menu.selected], Item::Quirks);
+    }
+
+    #[test]
+    fn browse() {
+        let dir = temp_dir("browse");
+        fs::create_dir(dir.join("sub")).unwrap();
+        fs::create_dir(dir.join(".hidden")).unwrap();
+        fs::write(dir.join("sub/inner.ch8"), [0x00, 0xe0]).unwrap();
+        fs::write(dir.join("game.ch8"), [0x00, 0xe0]).unwrap();
+        fs::write(dir.join("notes.txt"), b"hi").unwrap();
+
+        let mut menu = Menu::new(&dir, 4);
+        menu.open(Speed::default(), PALETTES[0].1, Quirks::default());
+        select(&mut menu, "open rom");
+        assert_eq!(menu.handle(MenuInput::Select), None);
+        assert_eq!(menu.labels(), ["../", "sub/", "game.ch8"]);
+        assert_eq!(menu.view(24, 80).title, dir.display().to_string());
+
+        select(&mut menu, "sub/");
+        assert_eq!(menu.handle(MenuInput::Select), None);
+        assert_eq!(menu.labels(), ["../", "inner.ch8"]);
+        assert_eq!(menu.handle(MenuInput::Down), None);
+        assert_eq!(menu.handle(MenuInput::Select), Some(MenuAction::Open(dir.join("sub/inner.ch8"))));
+
+        // going up selects the directory we came from
+        menu.selected = 0;
+        assert_eq!(menu.handle(MenuInput::Select), None);
+        assert_eq!(menu.labels()[menu.selected], "sub/");
+
+        assert_eq!(menu.handle(MenuInput::Back), None);
+        assert_eq!(ITEMS[menu.selected], Item::Open);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn scrolling() {
+        let mut menu = Menu::new(".", 4);
+        menu.open(Speed::default(), PALETTES[0].1, Quirks::default());
+
+        let view = menu.view(24, 80);
+        assert_eq!(view.title, "paused");
+        assert_eq!(view.lines.len(), ITEMS.len());
+        assert_eq!(view.lines[0], "> resume");
+        assert_eq!(view.lines[5], "  speed       < 700 Hz >");
+        assert_eq!(view.footer, HINT);
+
+        // six rows leave two for items
+        menu.selected = 5;
+        let view = menu.view(6, 10);
+        assert_eq!(view.lines, ["  load sta", "> speed   "]);
+        assert_eq!(view.selected, 1);
+
+        menu.selected = ITEMS.len() - 1;
+        let view = menu.view(6, 80);
+        assert_eq!(view.lines, ["  quirks", "> quit"]);
+        assert_eq!(view.selected, 1);
+
+        menu.set_message("slot 2 is empty");
+        assert_eq!(menu.view(6, 80).footer, "slot 2 is empty");
+        menu.handle(MenuInput::Up);
+        assert_eq!(menu.view(6, 80).footer, HINT);
+    }
+}
@@ -0,0 +1,99 @@
+use super::{parse_number, Compiler};
+use crate::error::AsmError;
+
+impl Compiler {
+
+    /*
+     * Evaluate the body of a { calc } block, the { is already consumed.
+     * Like Octo there's no operator precedence, expressions are read
+     * right to left so `2 * 3 + 1` is 8. Parentheses group as usual.
+     */
+    pub(super) fn calc(&mut self) -> Result<f64, AsmError> {
+        let value = self.calc_expr()?;
+        self.expect("}")?;
+        Ok(value)
+    }
+
+    fn calc_expr(&mut self) -> Result<f64, AsmError> {
+        let lhs = self.calc_term()?;
+
+        let op = match self.peek() {
+            Some(")" | "}") | None => return Ok(lhs),
+            Some(_) => self.next()?,
+        };
+        let rhs = self.calc_expr()?;
+
+        let value = match op.text.as_str() {
+            "+" => lhs + rhs,
+            "-" => lhs - rhs,
+            "*" => lhs * rhs,
+            "/" => lhs / rhs,
+            "%" => lhs % rhs,
+            "&" => ((lhs as i64) & (rhs as i64)) as f64,
+            "|" => ((lhs as i64) | (rhs as i64)) as f64,
+            "^" => ((lhs as i64) ^ (rhs as i64)) as f64,
+            "<<" | ">>" => {
+                let shift = if op.text == "<<" { i64::checked_shl } else { i64::checked_shr };
+                u32::try_from(rhs as i64)
+                    .ok()
+                    .and_then(|amount| shift(lhs as i64, amount))
+                    .ok_or_else(|| self.error(&op, String::from("shift out of range")))? as f64
+            }
+            "pow" => lhs.powf(rhs),
+            "min" => lhs.min(rhs),
+            "max" => lhs.max(rhs),
+            "<" => (lhs < rhs) as i64 as f64,
+            ">" => (lhs > rhs) as i64 as f64,
+            "<=" => (lhs <= rhs) as i64 as f64,
+            ">=" => (lhs >= rhs) as i64 as f64,
+            "==" => (lhs == rhs) as i64 as f64,
+            "!=" => (lhs != rhs) as i64 as f64,
+            _ => return Err(self.error(&op, format!("unknown operator '{}'", op.text))),
+        };
+        Ok(value)
+    }
+
+    fn calc_term(&mut self) -> Result<f64, AsmError> {
+        let token = self.next()?;
+
+        let unary: Option<fn(f64) -> f64> = match token.text.as_str() {
+            "-" => Some(|x| -x),
+            "~" => Some(|x| !(x as i64) as f64),
+            "!" => Some(|x| (x == 0.0) as i64 as f64),
+            "abs" => Some(f64::abs),
+            "sqrt" => Some(f64::sqrt),
+            "sin" => Some(f64::sin),
+            "cos" => Some(f64::cos),
+            "tan" => Some(f64::tan),
+            "exp" => Some(f64::exp),
+            "log" => Some(f64::ln),
+            "sign" => Some(f64::signum),
+            "ceil" => Some(f64::ceil),
+            "floor" => Some(f64::floor),
+            _ => None,
+        };
+        if let Some(unary) = unary {
+            return Ok(unary(self.calc_term()?));
+        }
+
+        match token.text.as_str() {
+            "(" => {
+                let value = self.calc_expr()?;
+                self.expect(")")?;
+                Ok(value)
+            }
+            // the byte already compiled at an address
+            "@" => {
+                let addr = self.calc_term()?;
+                Ok(self.rom.get(addr as usize).copied().unwrap_or(0) as f64)
+            }
+            "HERE" => Ok(self.here as f64),
+            "PI" => Ok(std::f64::consts::PI),
+            "E" => Ok(std::f64::consts::E),
+            text => parse_number(text)
+                .or_else(|| self.constants.get(text).copied())
+                .or_else(|| self.labels.get(text).map(|&addr| addr as f64))
+                .ok_or_else(|| self.error(&token, format!("unknown value '{}'", text))),
+        }
+    }
+}
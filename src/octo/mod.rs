@@ -0,0 +1,1010 @@
+mod calc;
+
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+
+use crate::error::AsmError;
+use crate::instruction::Instruction;
+use crate::processor::XO_CHIP_RAM;
+
+const PROGRAM_START: usize = 0x200;
+
+// guards against macros that expand themselves forever
+const MAX_EXPANSIONS: usize = 100_000;
+
+/*
+ * Compile Octo source to a rom image loaded at 0x200, ready for
+ * Processor::load. Supported on top of the plain statements are
+ * :const, :alias, :macro, :calc, :org, :next, :byte, :call,
+ * loop/while/again and if with then or begin/else/end, including the
+ * < > <= >= comparisons which use vf as scratch like Octo does.
+ */
+pub fn compile(source: &str, path: &Path) -> Result<Vec<u8>, AsmError> {
+    let mut compiler = Compiler::new(path.display().to_string(), tokenize(source));
+    compiler.compile()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize
+}
+
+/*
+ * Octo tokens are separated by whitespace, # comments run to the end
+ * of the line.
+ */
+fn tokenize(source: &str) -> VecDeque<Token> {
+    let mut tokens = VecDeque::new();
+
+    for (number, line) in source.lines().enumerate() {
+        let mut start = None;
+        for (column, c) in line.chars().chain([' ']).enumerate() {
+            if c == '#' && start.is_none() {
+                break;
+            }
+            match (c.is_whitespace(), start) {
+                (false, None) => start = Some(column),
+                (true, Some(first)) => {
+                    let text: String = line.chars().skip(first).take(column - first).collect();
+                    tokens.push_back(Token { text, line: number + 1, column: first + 1 });
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    tokens
+}
+
+fn parse_number(text: &str) -> Option<f64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()? as f64
+    } else if let Some(bin) = digits.strip_prefix("0b") {
+        i64::from_str_radix(bin, 2).ok()? as f64
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+
+    Some(if negative { -value } else { value })
+}
+
+fn register(text: &str) -> Option<usize> {
+    match text.as_bytes() {
+        [b'v' | b'V', digit] => (*digit as char).to_digit(16).map(|x| x as usize),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Fixup {
+    // the low 12 bits of the instruction at the address
+    Addr,
+    // the word after the F000 at the address
+    Long
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Token>
+}
+
+enum Block {
+    // the jump to patch once the else or end is reached
+    If(usize, Token),
+    Else(usize, Token),
+    // the loop start and the jumps out of it from while
+    Loop(usize, Vec<usize>, Token)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    Key,
+    NotKey
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Operand {
+    Register(usize),
+    Byte(u8)
+}
+
+struct Condition {
+    vx: usize,
+    comparison: Comparison,
+    operand: Operand
+}
+
+struct Compiler {
+    file: String,
+    tokens: VecDeque<Token>,
+    last: Token,
+    rom: Vec<u8>,
+    here: usize,
+    end: usize,
+    labels: HashMap<String, usize>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, usize>,
+    macros: HashMap<String, Macro>,
+    // references to labels that weren't defined yet
+    protos: Vec<(usize, Fixup, Token)>,
+    blocks: Vec<Block>,
+    next: Option<Token>,
+    expansions: usize,
+    // whether the jump to main at 0x200 is still to be decided
+    entry_pending: bool
+}
+
+impl Compiler {
+
+    fn new(file: String, tokens: VecDeque<Token>) -> Self {
+        Compiler {
+            file,
+            tokens,
+            last: Token { text: String::new(), line: 1, column: 1 },
+            rom: vec![0; XO_CHIP_RAM],
+            here: PROGRAM_START,
+            end: PROGRAM_START,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            protos: Vec::new(),
+            blocks: Vec::new(),
+            next: None,
+            expansions: 0,
+            entry_pending: true
+        }
+    }
+
+    fn error(&self, token: &Token, message: impl Into<String>) -> AsmError {
+        AsmError {
+            file: self.file.clone(),
+            line: token.line,
+            column: token.column,
+            message: message.into()
+        }
+    }
+
+    fn next(&mut self) -> Result<Token, AsmError> {
+        match self.tokens.pop_front() {
+            Some(token) => {
+                self.last = token.clone();
+                Ok(token)
+            }
+            None => Err(self.error(&self.last, "unexpected end of file")),
+        }
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.front().map(|token| token.text.as_str())
+    }
+
+    fn expect(&mut self, text: &str) -> Result<Token, AsmError> {
+        let token = self.next()?;
+        if token.text != text {
+            return Err(self.error(&token, format!("expected '{}', found '{}'", text, token.text)));
+        }
+        Ok(token)
+    }
+
+    fn compile(&mut self) -> Result<Vec<u8>, AsmError> {
+
+        while !self.tokens.is_empty() {
+            let token = self.next()?;
+            self.statement(token)?;
+        }
+        self.entry();
+
+        if let Some(block) = self.blocks.last() {
+            let (token, message) = match block {
+                Block::If(_, token) | Block::Else(_, token) => (token, "'begin' without 'end'"),
+                Block::Loop(_, _, token) => (token, "'loop' without 'again'"),
+            };
+            return Err(self.error(token, message));
+        }
+        if let Some(token) = &self.next {
+            return Err(self.error(token, ":next must be followed by an instruction"));
+        }
+
+        for (addr, fixup, token) in std::mem::take(&mut self.protos) {
+            let target = *self.labels.get(&token.text).ok_or_else(|| self.error(&token, format!("undefined label '{}'", token.text)))?;
+            self.patch(addr, fixup, target, &token)?;
+        }
+
+        Ok(self.rom[PROGRAM_START..self.end].to_vec())
+    }
+
+    fn statement(&mut self, token: Token) -> Result<(), AsmError> {
+        let text = token.text.as_str();
+
+        let declaration = matches!(text, ":" | ":const" | ":calc" | ":alias" | ":macro" | ":proto" | ":breakpoint" | ":next");
+        if !declaration && !self.macros.contains_key(text) {
+            self.entry();
+        }
+
+        if let Some(vx) = self.register_of(text) {
+            return self.register_statement(vx);
+        }
+        if let Some(value) = parse_number(text) {
+            let byte = self.check_byte(&token, value)?;
+            return self.emit_byte(byte);
+        }
+
+        match text {
+            ":" => {
+                let name = self.next()?;
+                if name.text != "main" {
+                    self.entry();
+                }
+                self.define_label(&name, self.here)?;
+            }
+            ":const" => {
+                let name = self.next()?;
+                let value = self.value()?;
+                self.define_constant(&name, value)?;
+            }
+            ":calc" => {
+                let name = self.next()?;
+                self.expect("{")?;
+                let value = self.calc()?;
+                self.define_constant(&name, value)?;
+            }
+            ":alias" => {
+                let name = self.next()?;
+                let vx = self.register()?;
+                self.check_name(&name)?;
+                self.aliases.insert(name.text, vx);
+            }
+            ":macro" => self.define_macro()?,
+            ":org" => {
+                let value = self.value()?;
+                if value < PROGRAM_START as f64 || value >= XO_CHIP_RAM as f64 {
+                    return Err(self.error(&self.last, format!(":org {} is outside 0x200..0xFFFF", value)));
+                }
+                self.here = value as usize;
+            }
+            ":next" => self.next = Some(self.next()?),
+            ":byte" => {
+                let value = self.value()?;
+                let byte = self.check_byte(&self.last, value)?;
+                self.emit_byte(byte)?;
+            }
+            ":call" => {
+                let addr = self.target(Fixup::Addr)?;
+                self.emit(Instruction::Call(addr))?;
+            }
+            // forward declarations, nothing to do as any label can be used before it's defined
+            ":proto" => {
+                self.next()?;
+            }
+            ":breakpoint" => {
+                self.next()?;
+            }
+            "return" | ";" => self.emit(Instruction::Ret)?,
+            "clear" => self.emit(Instruction::Cls)?,
+            "exit" => self.emit(Instruction::Exit)?,
+            "hires" => self.emit(Instruction::High)?,
+            "lores" => self.emit(Instruction::Low)?,
+            "scroll-left" => self.emit(Instruction::ScrollLeft)?,
+            "scroll-right" => self.emit(Instruction::ScrollRight)?,
+            "scroll-down" => {
+                let n = self.nibble()?;
+                self.emit(Instruction::ScrollDown(n))?;
+            }
+            "scroll-up" => {
+                let n = self.nibble()?;
+                self.emit(Instruction::ScrollUp(n))?;
+            }
+            "audio" => self.emit(Instruction::Audio)?,
+            "plane" => {
+                let n = self.nibble()?;
+                if n > 3 {
+                    return Err(self.error(&self.last, "plane must be 0 to 3"));
+                }
+                self.emit(Instruction::Plane(n))?;
+            }
+            "bcd" => {
+                let vx = self.register()?;
+                self.emit(Instruction::LdB(vx))?;
+            }
+            "save" | "load" => {
+                let vx = self.register()?;
+                let instruction = if self.peek() == Some("-") {
+                    self.next()?;
+                    let vy = self.register()?;
+                    if text == "save" { Instruction::Save(vx, vy) } else { Instruction::Load(vx, vy) }
+                } else if text == "save" {
+                    Instruction::LdIVx(vx)
+                } else {
+                    Instruction::LdVxI(vx)
+                };
+                self.emit(instruction)?;
+            }
+            "saveflags" => {
+                let vx = self.register()?;
+                self.emit(Instruction::LdRVx(vx))?;
+            }
+            "loadflags" => {
+                let vx = self.register()?;
+                self.emit(Instruction::LdVxR(vx))?;
+            }
+            "sprite" => {
+                let vx = self.register()?;
+                let vy = self.register()?;
+                let n = self.nibble()?;
+                self.emit(Instruction::Drw(vx, vy, n))?;
+            }
+            "jump" => {
+                let addr = self.target(Fixup::Addr)?;
+                self.emit(Instruction::Jp(addr))?;
+            }
+            "jump0" => {
+                let addr = self.target(Fixup::Addr)?;
+                self.emit(Instruction::JpV0(addr))?;
+            }
+            "native" => {
+                let addr = self.target(Fixup::Addr)?;
+                self.emit(Instruction::Sys(addr))?;
+            }
+            "i" => self.i_statement()?,
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let vx = self.register()?;
+                let instruction = match text {
+                    "delay" => Instruction::LdDtVx(vx),
+                    "buzzer" => Instruction::LdStVx(vx),
+                    _ => Instruction::Pitch(vx),
+                };
+                self.emit(instruction)?;
+            }
+            "if" => self.if_statement(&token)?,
+            "else" => {
+                let Some(Block::If(jump, _)) = self.blocks.pop() else {
+                    return Err(self.error(&token, "'else' without 'if ... begin'"));
+                };
+                let end = self.here;
+                self.emit(Instruction::Jp(0))?;
+                self.patch(jump, Fixup::Addr, self.here, &token)?;
+                self.blocks.push(Block::Else(end, token));
+            }
+            "end" => {
+                let jump = match self.blocks.pop() {
+                    Some(Block::If(jump, _) | Block::Else(jump, _)) => jump,
+                    _ => return Err(self.error(&token, "'end' without 'if ... begin'")),
+                };
+                self.patch(jump, Fixup::Addr, self.here, &token)?;
+            }
+            "loop" => self.blocks.push(Block::Loop(self.here, Vec::new(), token)),
+            "while" => {
+                let condition = self.condition()?;
+                // leave the loop unless the condition holds
+                self.skip(&condition, true)?;
+                let jump = self.here;
+                self.emit(Instruction::Jp(0))?;
+
+                match self.blocks.iter_mut().rev().find_map(|block| match block {
+                    Block::Loop(_, breaks, _) => Some(breaks),
+                    _ => None,
+                }) {
+                    Some(breaks) => breaks.push(jump),
+                    None => return Err(self.error(&token, "'while' outside of a loop")),
+                }
+            }
+            "again" => {
+                let Some(Block::Loop(start, breaks, _)) = self.blocks.pop() else {
+                    return Err(self.error(&token, "'again' without 'loop'"));
+                };
+                self.emit(Instruction::Jp(start))?;
+                for jump in breaks {
+                    self.patch(jump, Fixup::Addr, self.here, &token)?;
+                }
+            }
+            _ if self.macros.contains_key(text) => self.expand(&token)?,
+            _ if self.constants.contains_key(text) => {
+                let value = self.constants[text];
+                let byte = self.check_byte(&token, value)?;
+                self.emit_byte(byte)?;
+            }
+            _ if text.starts_with(':') || text == "{" || text == "}" => {
+                return Err(self.error(&token, format!("unexpected '{}'", text)));
+            }
+            // anything else calls a subroutine, which may be defined further down
+            _ => {
+                self.tokens.push_front(token);
+                let addr = self.target(Fixup::Addr)?;
+                self.emit(Instruction::Call(addr))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn register_statement(&mut self, vx: usize) -> Result<(), AsmError> {
+        let op = self.next()?;
+
+        let instruction = match op.text.as_str() {
+            ":=" => match self.peek() {
+                Some("random") => {
+                    self.next()?;
+                    Instruction::Rnd(vx, self.byte()?)
+                }
+                Some("key") => {
+                    self.next()?;
+                    Instruction::LdVxK(vx)
+                }
+                Some("delay") => {
+                    self.next()?;
+                    Instruction::LdVxDt(vx)
+                }
+                _ => match self.operand()? {
+                    Operand::Register(vy) => Instruction::LdReg(vx, vy),
+                    Operand::Byte(kk) => Instruction::LdByte(vx, kk),
+                },
+            },
+            "+=" => match self.operand()? {
+                Operand::Register(vy) => Instruction::AddReg(vx, vy),
+                Operand::Byte(kk) => Instruction::AddByte(vx, kk),
+            },
+            "-=" => match self.operand()? {
+                Operand::Register(vy) => Instruction::Sub(vx, vy),
+                Operand::Byte(kk) => Instruction::AddByte(vx, kk.wrapping_neg()),
+            },
+            "=-" => Instruction::Subn(vx, self.register()?),
+            "|=" => Instruction::Or(vx, self.register()?),
+            "&=" => Instruction::And(vx, self.register()?),
+            "^=" => Instruction::Xor(vx, self.register()?),
+            ">>=" => Instruction::Shr(vx, self.register()?),
+            "<<=" => Instruction::Shl(vx, self.register()?),
+            _ => return Err(self.error(&op, format!("unknown operator '{}'", op.text))),
+        };
+
+        self.emit(instruction)
+    }
+
+    fn i_statement(&mut self) -> Result<(), AsmError> {
+        let op = self.next()?;
+
+        match op.text.as_str() {
+            ":=" => match self.peek() {
+                Some("hex") => {
+                    self.next()?;
+                    let vx = self.register()?;
+                    self.emit(Instruction::LdF(vx))
+                }
+                Some("bighex") => {
+                    self.next()?;
+                    let vx = self.register()?;
+                    self.emit(Instruction::LdHf(vx))
+                }
+                Some("long") => {
+                    self.next()?;
+                    let addr = self.target(Fixup::Long)?;
+                    self.emit(Instruction::LdILong)?;
+                    self.emit_byte((addr >> 8) as u8)?;
+                    self.emit_byte(addr as u8)
+                }
+                _ => {
+                    let addr = self.target(Fixup::Addr)?;
+                    self.emit(Instruction::LdI(addr))
+                }
+            },
+            "+=" => {
+                let vx = self.register()?;
+                self.emit(Instruction::AddI(vx))
+            }
+            _ => Err(self.error(&op, format!("unknown operator 'i {}'", op.text))),
+        }
+    }
+
+    fn if_statement(&mut self, token: &Token) -> Result<(), AsmError> {
+        let condition = self.condition()?;
+        let keyword = self.next()?;
+
+        match keyword.text.as_str() {
+            // skip the next instruction unless the condition holds
+            "then" => self.skip(&condition, false),
+            // skip the jump past the block when the condition holds
+            "begin" => {
+                self.skip(&condition, true)?;
+                let jump = self.here;
+                self.emit(Instruction::Jp(0))?;
+                self.blocks.push(Block::If(jump, token.clone()));
+                Ok(())
+            }
+            _ => Err(self.error(&keyword, format!("expected 'then' or 'begin', found '{}'", keyword.text))),
+        }
+    }
+
+    fn condition(&mut self) -> Result<Condition, AsmError> {
+        let vx = self.register()?;
+        let op = self.next()?;
+
+        let comparison = match op.text.as_str() {
+            "==" => Comparison::Eq,
+            "!=" => Comparison::Ne,
+            "<" => Comparison::Lt,
+            ">" => Comparison::Gt,
+            "<=" => Comparison::Le,
+            ">=" => Comparison::Ge,
+            "key" => return Ok(Condition { vx, comparison: Comparison::Key, operand: Operand::Byte(0) }),
+            "-key" => return Ok(Condition { vx, comparison: Comparison::NotKey, operand: Operand::Byte(0) }),
+            _ => return Err(self.error(&op, format!("unknown comparison '{}'", op.text))),
+        };
+
+        let operand = self.operand()?;
+        Ok(Condition { vx, comparison, operand })
+    }
+
+    /*
+     * Emit instructions ending in a skip that's taken when the condition
+     * equals `when`. < > <= >= subtract into vf first and test the
+     * borrow flag, 1 when there was no borrow.
+     */
+    fn skip(&mut self, condition: &Condition, when: bool) -> Result<(), AsmError> {
+        let vx = condition.vx;
+
+        let (comparison, operand) = match condition.comparison {
+            Comparison::Lt | Comparison::Gt | Comparison::Le | Comparison::Ge => {
+                if vx == 0xf || matches!(condition.operand, Operand::Register(0xf)) {
+                    return Err(self.error(&self.last, "vf can't be compared with < > <= >= as it's used as scratch"));
+                }
+
+                // the flag is vx >= operand for < and >=, operand >= vx for > and <=
+                let flipped = matches!(condition.comparison, Comparison::Gt | Comparison::Le);
+                let instructions = match (condition.operand, flipped) {
+                    (Operand::Register(vy), false) => [Instruction::LdReg(0xf, vx), Instruction::Sub(0xf, vy)],
+                    (Operand::Register(vy), true) => [Instruction::LdReg(0xf, vy), Instruction::Sub(0xf, vx)],
+                    (Operand::Byte(kk), false) => [Instruction::LdByte(0xf, kk), Instruction::Subn(0xf, vx)],
+                    (Operand::Byte(kk), true) => [Instruction::LdByte(0xf, kk), Instruction::Sub(0xf, vx)],
+                };
+                for instruction in instructions {
+                    self.emit(instruction)?;
+                }
+
+                let flag = matches!(condition.comparison, Comparison::Ge | Comparison::Le) as u8;
+                return self.skip(&Condition { vx: 0xf, comparison: Comparison::Eq, operand: Operand::Byte(flag) }, when);
+            }
+            comparison => (comparison, condition.operand),
+        };
+
+        // skip instructions for "skip when true", swapped when skipping on false
+        let (equal, different) = match operand {
+            Operand::Byte(kk) => (Instruction::SeByte(vx, kk), Instruction::SneByte(vx, kk)),
+            Operand::Register(vy) => (Instruction::SeReg(vx, vy), Instruction::SneReg(vx, vy)),
+        };
+        let (on_true, on_false) = match comparison {
+            Comparison::Eq => (equal, different),
+            Comparison::Ne => (different, equal),
+            Comparison::Key => (Instruction::Skp(vx), Instruction::Sknp(vx)),
+            _ => (Instruction::Sknp(vx), Instruction::Skp(vx)),
+        };
+
+        self.emit(if when { on_true } else { on_false })
+    }
+
+    fn define_macro(&mut self) -> Result<(), AsmError> {
+        let name = self.next()?;
+        self.check_name(&name)?;
+
+        let mut params = Vec::new();
+        loop {
+            let token = self.next()?;
+            if token.text == "{" {
+                break;
+            }
+            params.push(token.text);
+        }
+
+        let body = self.braced()?;
+        self.macros.insert(name.text, Macro { params, body });
+        Ok(())
+    }
+
+    /*
+     * The tokens up to the matching }, the { is already consumed.
+     */
+    fn braced(&mut self) -> Result<Vec<Token>, AsmError> {
+        let mut body = Vec::new();
+        let mut depth = 0;
+        loop {
+            let token = self.next()?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" if depth == 0 => return Ok(body),
+                "}" => depth -= 1,
+                _ => {}
+            }
+            body.push(token);
+        }
+    }
+
+    fn expand(&mut self, token: &Token) -> Result<(), AsmError> {
+        self.expansions += 1;
+        if self.expansions > MAX_EXPANSIONS {
+            return Err(self.error(token, "too many macro expansions, is a macro recursive?"));
+        }
+
+        let count = self.macros[&token.text].params.len();
+        let mut args = Vec::new();
+        for _ in 0..count {
+            args.push(self.next()?);
+        }
+
+        let expansion = &self.macros[&token.text];
+        let body: Vec<Token> = expansion.body
+            .iter()
+            .map(|body_token| match expansion.params.iter().position(|param| *param == body_token.text) {
+                Some(i) => args[i].clone(),
+                None => body_token.clone(),
+            })
+            .collect();
+
+        for body_token in body.into_iter().rev() {
+            self.tokens.push_front(body_token);
+        }
+        Ok(())
+    }
+
+    fn register_of(&self, text: &str) -> Option<usize> {
+        register(text).or_else(|| self.aliases.get(text).copied())
+    }
+
+    fn register(&mut self) -> Result<usize, AsmError> {
+        let token = self.next()?;
+        self.register_of(&token.text).ok_or_else(|| self.error(&token, format!("expected a register, found '{}'", token.text)))
+    }
+
+    fn operand(&mut self) -> Result<Operand, AsmError> {
+        if let Some(vy) = self.peek().and_then(|text| self.register_of(text)) {
+            self.next()?;
+            return Ok(Operand::Register(vy));
+        }
+        Ok(Operand::Byte(self.byte()?))
+    }
+
+    /*
+     * A value known right now: a number, constant, label defined above
+     * or a { calc } expression.
+     */
+    fn value(&mut self) -> Result<f64, AsmError> {
+        let token = self.next()?;
+        if token.text == "{" {
+            return self.calc();
+        }
+
+        parse_number(&token.text)
+            .or_else(|| self.constants.get(&token.text).copied())
+            .or_else(|| self.labels.get(&token.text).map(|&addr| addr as f64))
+            .ok_or_else(|| self.error(&token, format!("unknown value '{}'", token.text)))
+    }
+
+    fn check_byte(&self, token: &Token, value: f64) -> Result<u8, AsmError> {
+        if !(-128.0..=255.0).contains(&value) {
+            return Err(self.error(token, format!("{} doesn't fit in a byte", value)));
+        }
+        Ok(value as i64 as u8)
+    }
+
+    fn byte(&mut self) -> Result<u8, AsmError> {
+        let value = self.value()?;
+        self.check_byte(&self.last, value)
+    }
+
+    fn nibble(&mut self) -> Result<u8, AsmError> {
+        let value = self.value()?;
+        if !(0.0..=15.0).contains(&value) {
+            return Err(self.error(&self.last, format!("{} doesn't fit in a nibble", value)));
+        }
+        Ok(value as u8)
+    }
+
+    /*
+     * An address operand for the instruction about to be emitted. Labels
+     * that aren't defined yet are patched in at the end.
+     */
+    fn target(&mut self, fixup: Fixup) -> Result<usize, AsmError> {
+        let token = self.next()?;
+
+        let known = parse_number(&token.text)
+            .or_else(|| self.constants.get(&token.text).copied())
+            .or_else(|| self.labels.get(&token.text).map(|&addr| addr as f64));
+
+        match known {
+            Some(value) => {
+                let max = if fixup == Fixup::Long { 0xffff } else { 0xfff };
+                if value < 0.0 || value > max as f64 {
+                    return Err(self.error(&token, format!("address {} is out of range", value)));
+                }
+                Ok(value as usize)
+            }
+            None => {
+                if register(&token.text).is_some() || token.text.starts_with(':') {
+                    return Err(self.error(&token, format!("expected an address, found '{}'", token.text)));
+                }
+                self.protos.push((self.here, fixup, token));
+                Ok(0)
+            }
+        }
+    }
+
+    fn patch(&mut self, addr: usize, fixup: Fixup, target: usize, token: &Token) -> Result<(), AsmError> {
+        match fixup {
+            Fixup::Addr => {
+                if target > 0xfff {
+                    return Err(self.error(token, format!("{:#X} is out of reach, use i := long", target)));
+                }
+                self.rom[addr] = (self.rom[addr] & 0xf0) | (target >> 8) as u8;
+                self.rom[addr + 1] = target as u8;
+            }
+            Fixup::Long => {
+                self.rom[addr + 2] = (target >> 8) as u8;
+                self.rom[addr + 3] = target as u8;
+            }
+        }
+        Ok(())
+    }
+
+    fn check_name(&self, token: &Token) -> Result<(), AsmError> {
+        let text = token.text.as_str();
+        let taken = register(text).is_some()
+            || parse_number(text).is_some()
+            || self.labels.contains_key(text)
+            || self.constants.contains_key(text)
+            || self.aliases.contains_key(text)
+            || self.macros.contains_key(text);
+
+        if taken {
+            return Err(self.error(token, format!("'{}' is already defined", text)));
+        }
+        Ok(())
+    }
+
+    /*
+     * Execution starts at 0x200, so unless main is defined there before
+     * anything else the program starts with a jump to it.
+     */
+    fn entry(&mut self) {
+        if !std::mem::take(&mut self.entry_pending) || self.labels.contains_key("main") {
+            return;
+        }
+
+        let main = Token { text: String::from("main"), line: 1, column: 1 };
+        self.protos.push((PROGRAM_START, Fixup::Addr, main));
+        self.rom[PROGRAM_START] = 0x10;
+        self.here = PROGRAM_START + 2;
+        self.end = self.end.max(self.here);
+    }
+
+    fn define_label(&mut self, name: &Token, addr: usize) -> Result<(), AsmError> {
+        self.check_name(name)?;
+        self.labels.insert(name.text.clone(), addr);
+        Ok(())
+    }
+
+    fn define_constant(&mut self, name: &Token, value: f64) -> Result<(), AsmError> {
+        self.check_name(name)?;
+        self.constants.insert(name.text.clone(), value);
+        Ok(())
+    }
+
+    fn emit(&mut self, instruction: Instruction) -> Result<(), AsmError> {
+        // :next points at the operand byte, for self modifying code
+        if let Some(name) = self.next.take() {
+            self.define_label(&name, self.here + 1)?;
+        }
+
+        let [hi, lo] = instruction.encode().to_be_bytes();
+        self.emit_byte(hi)?;
+        self.emit_byte(lo)
+    }
+
+    fn emit_byte(&mut self, byte: u8) -> Result<(), AsmError> {
+        if self.here >= self.rom.len() {
+            return Err(self.error(&self.last, "program doesn't fit in memory"));
+        }
+        self.rom[self.here] = byte;
+        self.here += 1;
+        self.end = self.end.max(self.here);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn octo(source: &str) -> Result<Vec<u8>, AsmError> {
+        compile(source, Path::new("test.8o"))
+    }
+
+    fn error(source: &str) -> (usize, usize, String) {
+        let err = octo(source).unwrap_err();
+        (err.line, err.column, err.message)
+    }
+
+    #[test]
+    fn statements() {
+        let source = "
+            : main
+                clear            # comments are ignored
+                va := 0x02
+                v1 := va
+                v1 += 3
+                v1 -= 1
+                v2 =- v1
+                i := sprite-data
+                i := long 0x1234
+                sprite v0 v1 15
+                save v1 - v3
+                delay := va
+                v0 := random 0xFF
+                return
+            : sprite-data
+                0x3C 0x42
+        ";
+        assert_eq!(octo(source), Ok(vec![
+            0x00, 0xe0, 0x6a, 0x02, 0x81, 0xa0, 0x71, 0x03, 0x71, 0xff, 0x82, 0x17,
+            0xa2, 0x1c, 0xf0, 0x00, 0x12, 0x34, 0xd0, 0x1f, 0x51, 0x32, 0xfa, 0x15,
+            0xc0, 0xff, 0x00, 0xee, 0x3c, 0x42,
+        ]));
+    }
+
+    #[test]
+    fn main_and_calls() {
+        // main doesn't come first, so the program starts with a jump to it
+        let source = "
+            : draw
+                return
+            : main
+                draw
+                update
+                :call draw
+            : update
+                ;
+        ";
+        assert_eq!(octo(source), Ok(vec![0x12, 0x04, 0x00, 0xee, 0x22, 0x02, 0x22, 0x0a, 0x22, 0x02, 0x00, 0xee]));
+        assert_eq!(error(": start clear").2, "undefined label 'main'");
+    }
+
+    #[test]
+    fn conditionals() {
+        let source = "
+            : main
+                if v0 == 5 then v1 := 1
+                if v0 != v2 then v1 := 2
+                if v3 key then clear
+                if v0 == 1 begin
+                    v1 := 3
+                else
+                    v1 := 4
+                end
+        ";
+        assert_eq!(octo(source), Ok(vec![
+            0x40, 0x05, 0x61, 0x01,
+            0x50, 0x20, 0x61, 0x02,
+            0xe3, 0xa1, 0x00, 0xe0,
+            // skip the jump to the else branch when v0 == 1
+            0x30, 0x01, 0x12, 0x14, 0x61, 0x03, 0x12, 0x16, 0x61, 0x04,
+        ]));
+    }
+
+    #[test]
+    fn loops() {
+        let source = "
+            : main
+                loop
+                    v0 += 1
+                    while v0 != 10
+                    v1 += 1
+                again
+        ";
+        assert_eq!(octo(source), Ok(vec![
+            0x70, 0x01, 0x40, 0x0a, 0x12, 0x0a, 0x71, 0x01, 0x12, 0x00,
+        ]));
+        assert_eq!(error(": main\n  loop\n  v0 += 1").0, 2);
+        assert_eq!(error(": main again").2, "'again' without 'loop'");
+    }
+
+    #[test]
+    fn relational() {
+        // run each comparison on the processor, every combination around the boundary
+        use crate::processor::Processor;
+        use crate::quirks::Quirks;
+
+        for op in ["<", ">", "<=", ">="] {
+            for (a, b) in [(1, 2), (2, 2), (3, 2)] {
+                for rhs in ["v1", "2"] {
+                    let source = format!(": main v0 := {} v1 := {} v2 := 0 if v0 {} {} then v2 := 1 v2 += 0", a, b, op, rhs);
+                    let rom = octo(&source).unwrap();
+
+                    let mut p = Processor::new(Quirks::default());
+                    p.load(&rom);
+                    while p.pc() < 0x200 + rom.len() {
+                        p.tick([false; 16]).unwrap();
+                    }
+
+                    let expected = match op {
+                        "<" => a < b,
+                        ">" => a > b,
+                        "<=" => a <= b,
+                        _ => a >= b,
+                    };
+                    assert_eq!(p.reg_v()[2] == 1, expected, "{} {} {}", a, op, rhs);
+                }
+            }
+        }
+
+        assert!(octo(": main if vf < 3 then clear").is_err());
+    }
+
+    #[test]
+    fn directives() {
+        let source = "
+            :alias x v3
+            :const SPEED 4
+            :calc DOUBLE { SPEED * 2 + 1 }   # right to left, so 4 * 3
+            :macro add-twice reg n { reg += n reg += n }
+            : main
+                x := SPEED
+                add-twice x DOUBLE
+                :next target
+                v0 := 0
+                i := target
+                :byte { 3 - 1 - 1 }
+            :org 0x210
+                SPEED
+        ";
+        assert_eq!(octo(source), Ok(vec![
+            0x63, 0x04, 0x73, 0x0c, 0x73, 0x0c, 0x60, 0x00, 0xa2, 0x07, 0x03,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x04,
+        ]));
+    }
+
+    #[test]
+    fn calc() {
+        let source = "
+            : main
+                7
+                :calc A { ( 2 * 3 ) + 1 }
+                :calc B { HERE - 0x200 }
+                :calc C { @ 0x200 }
+                :calc D { 10 - 3 - 2 }
+                :calc F { floor 7 / 2 }
+                A B C D F
+        ";
+        assert_eq!(octo(source), Ok(vec![7, 7, 1, 7, 9, 3]));
+        assert_eq!(error(": main :calc A { 1 + }").2, "unknown value '}'");
+        assert_eq!(octo(": main :calc A { 1 << 4 } :calc B { 0x80 >> 7 } A B"), Ok(vec![16, 1]));
+        assert_eq!(error(": main :calc X { 1 << 64 }").2, "shift out of range");
+        assert_eq!(error(": main :calc X { 1 >> -1 }").2, "shift out of range");
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error(": main\n  v0 := 300"), (2, 9, String::from("300 doesn't fit in a byte")));
+        assert_eq!(error(": main\n  v0 ** 3"), (2, 6, String::from("unknown operator '**'")));
+        assert_eq!(error(": main\n  sprite v0 q 1"), (2, 13, String::from("expected a register, found 'q'")));
+        assert_eq!(error(": main : main"), (1, 10, String::from("'main' is already defined")));
+        assert_eq!(error(": main\nend"), (2, 1, String::from("'end' without 'if ... begin'")));
+        assert_eq!(error(": main\n  if v0 == 1 begin"), (2, 3, String::from("'begin' without 'end'")));
+        assert_eq!(error(": main\n  v0 :="), (2, 6, String::from("unexpected end of file")));
+        assert_eq!(error(":macro m { m }\n: main m").2, "too many macro expansions, is a macro recursive?");
+    }
+}
@@ -1,12 +1,29 @@
-use crate::font::FONT_SET;
-use std::time::Duration;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::audio::{AudioPattern, DEFAULT_PITCH, PATTERN_SIZE};
+use crate::error::{ErrorKind, ExecutionError, SnapshotError};
+use crate::font::{BIG_FONT_SET, FONT_SET};
+use crate::instruction::{decode, Instruction};
+use crate::quirks::Quirks;
+use crate::timers::Timers;
+use crate::trace::{TraceEntry, Tracer};
 
 const CHIP8_OPCODE_SIZE :usize = 2;
 const CHIP8_REG_V :usize = 16;
 const CHIP8_STACK :usize = 16;
-const CHIP8_RAM :usize = 4096;
+pub const CHIP8_RAM :usize = 0x1000;
+pub const XO_CHIP_RAM :usize = 0x10000;
 pub const CHIP8_WIDTH: usize = 64;
 pub const CHIP8_HEIGHT: usize = 32;
+pub const SCHIP_WIDTH: usize = 128;
+pub const SCHIP_HEIGHT: usize = 64;
+const BIG_FONT_ADDR: usize = 0x50;
+pub const RPL_FLAGS: usize = 16;
+pub const PROGRAM_START: usize = 0x200;
+
+const SNAPSHOT_MAGIC: &[u8; 4] = b"C8SS";
+const SNAPSHOT_VERSION: u16 = 1;
 
 enum ProgramCounter {
     Next,
@@ -14,164 +31,590 @@ enum ProgramCounter {
     Jump(usize)
 }
 
+enum KeyWait {
+    None,
+    Press(usize),
+    Release(usize, u8)
+}
+
+/*
+ * Every vram pixel holds one bit per XO-CHIP bitplane, so its value is
+ * a colour index 0-3. Plain CHIP-8 programs only ever use plane 1.
+ */
 pub struct OutputState<'a> {
-    pub vram: &'a[[u8; CHIP8_WIDTH]; CHIP8_HEIGHT],
-    pub vram_changed: bool
+    pub vram: &'a[[u8; SCHIP_WIDTH]; SCHIP_HEIGHT],
+    pub width: usize,
+    pub height: usize,
+    pub vram_changed: bool,
+    pub exited: bool,
+    pub sound_active: bool,
+    pub audio_pattern: Option<AudioPattern>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write
+}
+
+/*
+ * A data access to RAM made by the instruction at pc. Opcode fetches
+ * are not recorded.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub pc: usize,
+    pub access: Access,
+    pub addr: usize,
+    pub value: u8
 }
 
 pub struct Processor {
-    ram:    [u8; CHIP8_RAM],
-    vram:   [[u8; CHIP8_WIDTH]; CHIP8_HEIGHT],
+    ram:    Vec<u8>,
+    vram:   [[u8; SCHIP_WIDTH]; SCHIP_HEIGHT],
     vram_changed: bool,
+    hires:  bool,
+    planes: u8,
+    exited: bool,
     reg_v:  [u8; CHIP8_REG_V],
     stack:  [usize; CHIP8_STACK],
     reg_i:  usize,
     reg_pc: usize,
     reg_sp: usize,
-    reg_dt: u8,
-    reg_st: u8,
-    timer_cycle: Duration,
-    keypad: [bool; 16]
+    timers: Timers,
+    audio_buffer: Option<[u8; PATTERN_SIZE]>,
+    pitch: u8,
+    rpl: [u8; RPL_FLAGS],
+    keypad: [bool; 16],
+    key_wait: KeyWait,
+    rom_hash: [u8; 20],
+    quirks: Quirks,
+    track_memory: bool,
+    accesses: Vec<MemoryAccess>,
+    tracer: Option<Tracer>,
+    rng: StdRng
 }
 
 impl Processor {
 
-    pub fn new() -> Self {
+    pub fn new(quirks: Quirks) -> Self {
 
-        let mut ram = [0; CHIP8_RAM];
-        for (i, &byte) in FONT_SET.iter().enumerate() {
-            ram[i] = byte;
-        }
+        let mut ram = vec![0; quirks.memory_size()];
+        ram[..FONT_SET.len()].copy_from_slice(&FONT_SET);
+        ram[BIG_FONT_ADDR..BIG_FONT_ADDR + BIG_FONT_SET.len()].copy_from_slice(&BIG_FONT_SET);
 
         Processor {
             ram,
-            vram: [[0; CHIP8_WIDTH]; CHIP8_HEIGHT],
+            vram: [[0; SCHIP_WIDTH]; SCHIP_HEIGHT],
             vram_changed: false,
+            hires: false,
+            planes: 1,
+            exited: false,
             reg_v: [0; CHIP8_REG_V],
-            reg_pc: 0x200,
+            reg_pc: PROGRAM_START,
             reg_sp: 0,
             reg_i: 0,
             stack: [0; CHIP8_STACK],
-            reg_dt: 0,
-            reg_st: 0,
-            timer_cycle: Duration::ZERO,
-            keypad: [false,false,false,false,false,false,false,false,false,false,false,false,false,false,false,false]
+            timers: Timers::new(),
+            audio_buffer: None,
+            pitch: DEFAULT_PITCH,
+            rpl: [0; RPL_FLAGS],
+            keypad: [false; 16],
+            key_wait: KeyWait::None,
+            rom_hash: [0; 20],
+            quirks,
+            track_memory: false,
+            accesses: Vec::new(),
+            tracer: None,
+            rng: StdRng::from_entropy()
         }
     }
 
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    /*
+     * Change how ambiguous opcodes behave from the next instruction on.
+     * Memory was sized when the processor was made, so extended_memory
+     * keeps its value.
+     */
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = Quirks { extended_memory: self.quirks.extended_memory, ..quirks };
+    }
+
     pub fn _reset_pc(&mut self) {
-        self.reg_pc = 0x200;
+        self.reg_pc = PROGRAM_START;
         self.reg_sp = 0;
     }
 
     pub fn load(&mut self, data: &[u8]) {
+        self.rom_hash = sha1_smol::Sha1::from(data).digest().bytes();
+
         for (i, &byte) in data.iter().enumerate() {
-            let addr = 0x200 + i;
-            if addr < CHIP8_RAM {
-                self.ram[0x200 + i] = byte;
+            let addr = PROGRAM_START + i;
+            if addr < self.ram.len() {
+                self.ram[addr] = byte;
             } else {
                 break;
             }
         }
     }
 
-    pub fn tick(&mut self, delta: Duration, keypad:[bool; 16]) -> OutputState {
+    /*
+     * Execute a single instruction. Timers are not touched here, they
+     * are stepped by the scheduler at 60 Hz.
+     */
+    pub fn tick(&mut self, keypad:[bool; 16]) -> Result<(), ExecutionError> {
         
         self.keypad = keypad;
+        self.accesses.clear();
+
+        if self.exited {
+            return Ok(());
+        }
+
+        if self.update_key_wait() {
+            if self.tracer.is_none() {
+                return self.step();
+            }
+
+            let pc = self.reg_pc;
+            let opcode = self.opcode_at(pc).unwrap_or_default();
+            let operand = if opcode == 0xf000 { self.opcode_at(pc + CHIP8_OPCODE_SIZE) } else { None };
+            let before = self.reg_v;
+            self.step()?;
+
+            let entry = TraceEntry { operand, ..TraceEntry::new(pc, opcode, &before, &self.reg_v, self.reg_i) };
+            if let Some(tracer) = self.tracer.as_mut() {
+                tracer.record(&entry);
+            }
+        }
+
+        Ok(())
+    }
+
+    /*
+     * The state a frontend needs to present, vram_changed covers all
+     * instructions executed since the previous call.
+     */
+    pub fn output(&mut self) -> OutputState<'_> {
+        let vram_changed = self.vram_changed;
         self.vram_changed = false;
-        self.update_timers(delta);
 
-        let opcode: u16 = self.read_opcode();
-        let pc: ProgramCounter = self.run_opcode(opcode);
+        let (width, height) = self.resolution();
+
+        OutputState {
+            vram: &self.vram,
+            width,
+            height,
+            vram_changed,
+            exited: self.exited,
+            sound_active: self.timers.sound > 0,
+            audio_pattern: self.audio_buffer.map(|buffer| AudioPattern { buffer, pitch: self.pitch })
+        }
+    }
+
+    fn step(&mut self) -> Result<(), ExecutionError> {
+
+        let opcode: u16 = self.read_opcode().map_err(|kind| ExecutionError {
+            pc: self.reg_pc,
+            opcode: 0,
+            kind
+        })?;
+
+        let pc: ProgramCounter = self.run_opcode(opcode).map_err(|kind| ExecutionError {
+            pc: self.reg_pc,
+            opcode,
+            kind
+        })?;
 
         match pc {
             ProgramCounter::Next => self.reg_pc += CHIP8_OPCODE_SIZE,
-            ProgramCounter::Skip => self.reg_pc += CHIP8_OPCODE_SIZE * 2,
+            ProgramCounter::Skip => self.reg_pc += CHIP8_OPCODE_SIZE + self.instruction_size(self.reg_pc + CHIP8_OPCODE_SIZE),
             ProgramCounter::Jump(addr) => self.reg_pc = addr,
         }
-       
-        OutputState {
-            vram: &self.vram,
-            vram_changed: self.vram_changed
-        }
+
+        Ok(())
     }
 
-    fn update_timers(&mut self, delta: Duration) {
+    /*
+     * Serialise the complete machine state. The format starts with a
+     * magic and version, followed by the SHA-1 of the loaded rom so a
+     * state can't be restored into a different game.
+     */
+    pub fn snapshot(&self) -> Vec<u8> {
+        use byteorder::{BigEndian, WriteBytesExt};
+
+        let mut out = Vec::with_capacity(self.ram.len() + SCHIP_WIDTH * SCHIP_HEIGHT + 256);
 
-        let chip8_timer_period :Duration = Duration::from_secs_f32(1.0/60.0);
-        self.timer_cycle += delta;
+        out.extend_from_slice(SNAPSHOT_MAGIC);
+        out.write_u16::<BigEndian>(SNAPSHOT_VERSION).unwrap();
+        out.extend_from_slice(&self.rom_hash);
 
-        if self.timer_cycle >= chip8_timer_period {
-            if self.reg_dt > 0 {
-                self.reg_dt -= 1;
-            }
+        out.write_u32::<BigEndian>(self.ram.len() as u32).unwrap();
+        out.extend_from_slice(&self.ram);
+        for row in self.vram.iter() {
+            out.extend_from_slice(row);
+        }
+        out.push(self.hires as u8);
+        out.push(self.planes);
+        out.push(self.exited as u8);
 
-            if self.reg_st > 0 {
-                self.reg_st -= 1;
-            } 
+        out.extend_from_slice(&self.reg_v);
+        for &addr in self.stack.iter() {
+            out.write_u32::<BigEndian>(addr as u32).unwrap();
         }
+        out.write_u32::<BigEndian>(self.reg_i as u32).unwrap();
+        out.write_u32::<BigEndian>(self.reg_pc as u32).unwrap();
+        out.push(self.reg_sp as u8);
+
+        out.push(self.timers.delay);
+        out.push(self.timers.sound);
+        out.push(self.audio_buffer.is_some() as u8);
+        out.extend_from_slice(&self.audio_buffer.unwrap_or([0; PATTERN_SIZE]));
+        out.push(self.pitch);
+        out.extend_from_slice(&self.rpl);
+
+        out.extend(self.keypad.iter().map(|&pressed| pressed as u8));
+        match self.key_wait {
+            KeyWait::None => out.extend_from_slice(&[0, 0, 0]),
+            KeyWait::Press(vx) => out.extend_from_slice(&[1, vx as u8, 0]),
+            KeyWait::Release(vx, key) => out.extend_from_slice(&[2, vx as u8, key]),
+        }
+
+        out
     }
 
-    fn read_opcode(&self) -> u16 {
+    /*
+     * Restore a state created by snapshot(). The processor is left
+     * untouched when the state can't be restored.
+     */
+    pub fn restore(&mut self, data: &[u8]) -> Result<(), SnapshotError> {
+        use std::io::{Cursor, Read};
+        use byteorder::{BigEndian, ReadBytesExt};
+
+        let mut rdr = Cursor::new(data);
+        let mut magic = [0; 4];
+        rdr.read_exact(&mut magic).map_err(|_| SnapshotError::InvalidFormat)?;
+        if &magic != SNAPSHOT_MAGIC {
+            return Err(SnapshotError::InvalidFormat);
+        }
+
+        let version = rdr.read_u16::<BigEndian>().map_err(|_| SnapshotError::InvalidFormat)?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let mut rom_hash = [0; 20];
+        rdr.read_exact(&mut rom_hash).map_err(|_| SnapshotError::InvalidFormat)?;
+        if rom_hash != self.rom_hash {
+            return Err(SnapshotError::RomMismatch);
+        }
+
+        let ram_size = rdr.read_u32::<BigEndian>().map_err(|_| SnapshotError::InvalidFormat)?;
+        if ram_size as usize != self.ram.len() {
+            return Err(SnapshotError::MemorySizeMismatch);
+        }
+
+        // parse into a copy so a truncated state leaves self untouched
+        let mut p = Processor::new(self.quirks);
+        p.rom_hash = rom_hash;
+        p.read_snapshot_body(&mut rdr).map_err(|_| SnapshotError::InvalidFormat)?;
+        if p.reg_sp > CHIP8_STACK || p.planes > 3 || p.key_wait_invalid() {
+            return Err(SnapshotError::InvalidFormat);
+        }
+
+        // watchpoints and tracing keep working across load states and rewinding
+        p.vram_changed = true;
+        // high scores are kept per rom, an older state mustn't take them back
+        p.rpl = self.rpl;
+        p.track_memory = self.track_memory;
+        p.tracer = self.tracer.take();
+        // the random generator isn't machine state, a seeded run stays repeatable
+        p.rng = self.rng.clone();
+        *self = p;
+        Ok(())
+    }
 
-        use std::io::Cursor;
-        use std::io::SeekFrom;
-        use std::io::Seek;
+    fn read_snapshot_body<R: std::io::Read>(&mut self, rdr: &mut R) -> std::io::Result<()> {
         use byteorder::{BigEndian, ReadBytesExt};
 
-        let mut rdr = Cursor::new(self.ram);
-        rdr.seek(SeekFrom::Start(self.reg_pc as u64)).unwrap();
-        rdr.read_u16::<BigEndian>().unwrap()
-    }
-
-    fn run_opcode(&mut self, opcode:u16) -> ProgramCounter {
-
-        // unpack the opcode into 4 bit hex digits (nibbles)
-        let hex_digits = (
-            (opcode & 0xF000) >> 12 as u8,
-            (opcode & 0x0F00) >> 8 as u8,
-            (opcode & 0x00F0) >> 4 as u8,
-            (opcode & 0x000F) as u8,
-        );
-
-        let kk = (opcode & 0x00FF) as u8;
-        let vx = hex_digits.1 as usize;
-        let vy = hex_digits.2 as usize;
-        let n = hex_digits.3 as u8;
-        let addr = (opcode & 0x0FFF) as usize;
-
-        //println!("pc: {:x}, {:x}", self.reg_pc, opcode);
-
-        return match hex_digits {
-            (0x00, 0x00, 0x0e, 0x0e) => self.op_00ee(),
-            (0x00, 0x00, 0x0e, 0x00) => self.op_00e0(),
-            (0x01, _, _, _) => self.op_1nnn(addr),
-            (0x02, _, _, _) => self.op_2nnn(addr),
-            (0x03, _, _, _) => self.op_3xkk(vx, kk),
-            (0x04, _, _, _) => self.op_4xkk(vx, kk),
-            (0x06, _, _, _) => self.op_6xkk(vx, kk),
-            (0x07, _, _, _) => self.op_7xkk(vx, kk),
-            (0x08, _, _, 0x00) => self.op_8xy0(vx, vy),
-            (0x08, _, _, 0x02) => self.op_8xy2(vx, vy),
-            (0x08, _, _, 0x0e) => self.op_8xye(vx, vy),
-            (0x08, _, _, 0x04) => self.op_8xy4(vx, vy),
-            (0x08, _, _, 0x05) => self.op_8xy5(vx, vy),
-            (0x08, _, _, 0x06) => self.op_8xy6(vx, vy),
-            (0x0a, _, _, _) => self.op_annn(addr),
-            (0x0d, _, _, _) => self.op_dxyn(vx, vy, n),
-            (0x0e, _, 0x0a, 0x01) => self.op_exa1(vx),
-            (0x0e, _, 0x09, 0x0e) => self.op_ex9e(vx),
-            (0x0c, _, _, _) => self.op_cxkk(vx, kk),
-            (0x0f, _, 0x01, 0x05) => self.op_fx15(vx),
-            (0x0f, _, 0x01, 0x08) => self.op_fx18(vx),
-            (0x0f, _, 0x01, 0x0E) => self.op_fx1e(vx),
-            (0x0f, _, 0x02, 0x09) => self.op_fx29(vx),
-            (0x0f, _, 0x03, 0x03) => self.op_fx33(vx),
-            (0x0f, _, 0x06, 0x05) => self.op_fx65(vx),
-            (0x0f, _, 0x00, 0x07) => self.op_fx07(vx),
-
-            _ => panic!("unexpected opcode {:#4X}", opcode)
+        rdr.read_exact(&mut self.ram)?;
+        for row in self.vram.iter_mut() {
+            rdr.read_exact(row)?;
+        }
+        self.hires = rdr.read_u8()? != 0;
+        self.planes = rdr.read_u8()?;
+        self.exited = rdr.read_u8()? != 0;
+
+        rdr.read_exact(&mut self.reg_v)?;
+        for addr in self.stack.iter_mut() {
+            *addr = rdr.read_u32::<BigEndian>()? as usize;
+        }
+        self.reg_i = rdr.read_u32::<BigEndian>()? as usize;
+        self.reg_pc = rdr.read_u32::<BigEndian>()? as usize;
+        self.reg_sp = rdr.read_u8()? as usize;
+
+        self.timers.delay = rdr.read_u8()?;
+        self.timers.sound = rdr.read_u8()?;
+        let has_pattern = rdr.read_u8()? != 0;
+        let mut pattern = [0; PATTERN_SIZE];
+        rdr.read_exact(&mut pattern)?;
+        self.audio_buffer = if has_pattern { Some(pattern) } else { None };
+        self.pitch = rdr.read_u8()?;
+        rdr.read_exact(&mut self.rpl)?;
+
+        for pressed in self.keypad.iter_mut() {
+            *pressed = rdr.read_u8()? != 0;
+        }
+        let mut key_wait = [0; 3];
+        rdr.read_exact(&mut key_wait)?;
+        self.key_wait = match key_wait {
+            [1, vx, _] => KeyWait::Press(vx as usize),
+            [2, vx, key] => KeyWait::Release(vx as usize, key),
+            _ => KeyWait::None,
         };
+
+        Ok(())
+    }
+
+    fn key_wait_invalid(&self) -> bool {
+        match self.key_wait {
+            KeyWait::None => false,
+            KeyWait::Press(vx) => vx >= CHIP8_REG_V,
+            KeyWait::Release(vx, key) => vx >= CHIP8_REG_V || key as usize >= self.keypad.len(),
+        }
+    }
+
+    fn resolution(&self) -> (usize, usize) {
+        if self.hires {
+            (SCHIP_WIDTH, SCHIP_HEIGHT)
+        } else {
+            (CHIP8_WIDTH, CHIP8_HEIGHT)
+        }
+    }
+
+    pub fn pc(&self) -> usize {
+        self.reg_pc
+    }
+
+    pub fn reg_i(&self) -> usize {
+        self.reg_i
+    }
+
+    pub fn reg_v(&self) -> &[u8; CHIP8_REG_V] {
+        &self.reg_v
+    }
+
+    /*
+     * Return addresses of the active subroutine calls, innermost last.
+     */
+    pub fn stack(&self) -> &[usize] {
+        &self.stack[..self.reg_sp]
+    }
+
+    pub fn timers(&self) -> Timers {
+        self.timers
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.ram
+    }
+
+    pub fn opcode_at(&self, addr: usize) -> Option<u16> {
+        use byteorder::{BigEndian, ByteOrder};
+
+        self.ram.get(addr..addr + CHIP8_OPCODE_SIZE).map(BigEndian::read_u16)
+    }
+
+    /*
+     * Record the RAM reads and writes of every instruction, for memory
+     * watchpoints. Off by default to keep plain execution cheap.
+     */
+    pub fn track_memory(&mut self, enabled: bool) {
+        self.track_memory = enabled;
+        self.accesses.clear();
+    }
+
+    /*
+     * The RAM accesses made by the most recently executed instruction.
+     */
+    pub fn memory_accesses(&self) -> &[MemoryAccess] {
+        &self.accesses
+    }
+
+    /*
+     * Make Cxkk deterministic, so a run can be repeated exactly.
+     */
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    /*
+     * Write every executed instruction to a trace, or stop tracing with None.
+     */
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    pub fn take_tracer(&mut self) -> Option<Tracer> {
+        self.tracer.take()
+    }
+
+    /*
+     * The SUPER-CHIP RPL user flags, for the frontend to persist.
+     */
+    pub fn rpl_flags(&self) -> [u8; RPL_FLAGS] {
+        self.rpl
+    }
+
+    pub fn set_rpl_flags(&mut self, flags: [u8; RPL_FLAGS]) {
+        self.rpl = flags;
+    }
+
+    /*
+     * Count the delay and sound timers down by one 60 Hz period.
+     */
+    pub fn step_timers(&mut self) {
+        self.timers.step_frame();
+    }
+
+    /*
+     * Resolve a pending Fx0A. Like the COSMAC VIP the key is only
+     * accepted once it has been pressed and released again.
+     * Returns true when execution can continue.
+     */
+    fn update_key_wait(&mut self) -> bool {
+        match self.key_wait {
+            KeyWait::None => true,
+            KeyWait::Press(vx) => {
+                if let Some(key) = self.keypad.iter().position(|&pressed| pressed) {
+                    self.key_wait = KeyWait::Release(vx, key as u8);
+                }
+                false
+            }
+            KeyWait::Release(vx, key) => {
+                if self.keypad[key as usize] {
+                    return false;
+                }
+                self.reg_v[vx] = key;
+                self.key_wait = KeyWait::None;
+                true
+            }
+        }
+    }
+
+    fn read_opcode(&self) -> Result<u16, ErrorKind> {
+        self.opcode_at(self.reg_pc).ok_or(ErrorKind::PcOutOfRange)
+    }
+
+    /*
+     * Size in bytes of the instruction at addr, F000 NNNN takes 4 bytes.
+     */
+    fn instruction_size(&self, addr: usize) -> usize {
+        match (self.ram.get(addr), self.ram.get(addr + 1)) {
+            (Some(0xf0), Some(0x00)) => CHIP8_OPCODE_SIZE * 2,
+            _ => CHIP8_OPCODE_SIZE,
+        }
+    }
+
+    /*
+     * Fail unless the `len` bytes from `addr` are all in memory, so an
+     * instruction can check before it changes anything.
+     */
+    fn check_ram(&self, addr: usize, len: usize) -> Result<(), ErrorKind> {
+        if addr + len > self.ram.len() {
+            return Err(ErrorKind::MemoryOutOfBounds(addr.max(self.ram.len())));
+        }
+        Ok(())
+    }
+
+    fn read_ram(&mut self, addr: usize) -> Result<u8, ErrorKind> {
+        let value = self.ram.get(addr).copied().ok_or(ErrorKind::MemoryOutOfBounds(addr))?;
+        self.record(Access::Read, addr, value);
+        Ok(value)
+    }
+
+    fn write_ram(&mut self, addr: usize, value: u8) -> Result<(), ErrorKind> {
+        let byte = self.ram.get_mut(addr).ok_or(ErrorKind::MemoryOutOfBounds(addr))?;
+        *byte = value;
+        self.record(Access::Write, addr, value);
+        Ok(())
+    }
+
+    fn record(&mut self, access: Access, addr: usize, value: u8) {
+        if self.track_memory {
+            self.accesses.push(MemoryAccess { pc: self.reg_pc, access, addr, value });
+        }
+    }
+
+    fn run_opcode(&mut self, opcode:u16) -> Result<ProgramCounter, ErrorKind> {
+
+        let instruction = decode(opcode).ok_or(ErrorKind::UnknownOpcode)?;
+
+        match instruction {
+            Instruction::Ret => self.op_00ee(),
+            Instruction::Cls => Ok(self.op_00e0()),
+            Instruction::ScrollDown(n) => Ok(self.op_00cn(n)),
+            Instruction::ScrollUp(n) => Ok(self.op_00dn(n)),
+            Instruction::ScrollRight => Ok(self.op_00fb()),
+            Instruction::ScrollLeft => Ok(self.op_00fc()),
+            Instruction::Exit => Ok(self.op_00fd()),
+            Instruction::Low => Ok(self.op_00fe()),
+            Instruction::High => Ok(self.op_00ff()),
+            Instruction::Sys(addr) => Ok(self.op_0nnn(addr)),
+            Instruction::Jp(addr) => Ok(self.op_1nnn(addr)),
+            Instruction::Call(addr) => self.op_2nnn(addr),
+            Instruction::SeByte(vx, kk) => Ok(self.op_3xkk(vx, kk)),
+            Instruction::SneByte(vx, kk) => Ok(self.op_4xkk(vx, kk)),
+            Instruction::SeReg(vx, vy) => Ok(self.op_5xy0(vx, vy)),
+            Instruction::Save(vx, vy) => self.op_5xy2(vx, vy),
+            Instruction::Load(vx, vy) => self.op_5xy3(vx, vy),
+            Instruction::LdByte(vx, kk) => Ok(self.op_6xkk(vx, kk)),
+            Instruction::AddByte(vx, kk) => Ok(self.op_7xkk(vx, kk)),
+            Instruction::LdReg(vx, vy) => Ok(self.op_8xy0(vx, vy)),
+            Instruction::Or(vx, vy) => Ok(self.op_8xy1(vx, vy)),
+            Instruction::And(vx, vy) => Ok(self.op_8xy2(vx, vy)),
+            Instruction::Xor(vx, vy) => Ok(self.op_8xy3(vx, vy)),
+            Instruction::Shl(vx, vy) => Ok(self.op_8xye(vx, vy)),
+            Instruction::AddReg(vx, vy) => Ok(self.op_8xy4(vx, vy)),
+            Instruction::Sub(vx, vy) => Ok(self.op_8xy5(vx, vy)),
+            Instruction::Shr(vx, vy) => Ok(self.op_8xy6(vx, vy)),
+            Instruction::Subn(vx, vy) => Ok(self.op_8xy7(vx, vy)),
+            Instruction::SneReg(vx, vy) => Ok(self.op_9xy0(vx, vy)),
+            Instruction::LdI(addr) => Ok(self.op_annn(addr)),
+            Instruction::JpV0(addr) => Ok(self.op_bnnn(addr)),
+            Instruction::Drw(vx, vy, n) => self.op_dxyn(vx, vy, n),
+            Instruction::Sknp(vx) => Ok(self.op_exa1(vx)),
+            Instruction::Skp(vx) => Ok(self.op_ex9e(vx)),
+            Instruction::Rnd(vx, kk) => Ok(self.op_cxkk(vx, kk)),
+            Instruction::LdILong => self.op_f000(),
+            Instruction::Plane(n) => Ok(self.op_fn01(n as usize)),
+            Instruction::Audio => self.op_f002(),
+            Instruction::LdVxK(vx) => Ok(self.op_fx0a(vx)),
+            Instruction::LdDtVx(vx) => Ok(self.op_fx15(vx)),
+            Instruction::LdStVx(vx) => Ok(self.op_fx18(vx)),
+            Instruction::AddI(vx) => Ok(self.op_fx1e(vx)),
+            Instruction::LdF(vx) => Ok(self.op_fx29(vx)),
+            Instruction::LdHf(vx) => Ok(self.op_fx30(vx)),
+            Instruction::LdB(vx) => self.op_fx33(vx),
+            Instruction::Pitch(vx) => Ok(self.op_fx3a(vx)),
+            Instruction::LdRVx(vx) => Ok(self.op_fx75(vx)),
+            Instruction::LdVxR(vx) => Ok(self.op_fx85(vx)),
+            Instruction::LdIVx(vx) => self.op_fx55(vx),
+            Instruction::LdVxI(vx) => self.op_fx65(vx),
+            Instruction::LdVxDt(vx) => Ok(self.op_fx07(vx)),
+        }
+    }
+
+    /*
+     * SYS addr
+     * Jump to a machine code routine at addr. Only the original
+     * interpreters could do this, so it is ignored.
+     */
+    fn op_0nnn(&mut self, _addr:usize) -> ProgramCounter {
+        ProgramCounter::Next
     }
 
     /*
@@ -185,22 +628,30 @@ impl Processor {
     /*
      * Call addr
      */
-     fn op_2nnn(&mut self, addr:usize) -> ProgramCounter {
+     fn op_2nnn(&mut self, addr:usize) -> Result<ProgramCounter, ErrorKind> {
+        if self.reg_sp >= CHIP8_STACK {
+            return Err(ErrorKind::StackOverflow);
+        }
+
         self.stack[self.reg_sp] = self.reg_pc + CHIP8_OPCODE_SIZE;
         self.reg_sp += 1;
-        ProgramCounter::Jump(addr)
+        Ok(ProgramCounter::Jump(addr))
     }
 
     /*
      * RET
      * Return from a subroutine.
      */
-     fn op_00ee(&mut self) -> ProgramCounter {
+     fn op_00ee(&mut self) -> Result<ProgramCounter, ErrorKind> {
+        if self.reg_sp == 0 {
+            return Err(ErrorKind::StackUnderflow);
+        }
+
         self.reg_sp -= 1;
-        let addr = self.stack[self.reg_sp] as usize;
+        let addr = self.stack[self.reg_sp];
         self.stack[self.reg_sp] = 0;
 
-        ProgramCounter::Jump(addr)
+        Ok(ProgramCounter::Jump(addr))
     }
 
     /*
@@ -221,53 +672,120 @@ impl Processor {
 
     /*
      * DRW Vx, Vy, nibble
-     * Display n-byte sprite starting at memory location I at (Vx, Vy)
+     * Display n-byte sprite starting at memory location I at (Vx, Vy).
+     * With n = 0 a 16x16 sprite of 32 bytes is drawn (SUPER-CHIP).
      */
-    fn op_dxyn(&mut self, vx:usize, vy:usize, n:u8) -> ProgramCounter {
+    fn op_dxyn(&mut self, vx:usize, vy:usize, n:u8) -> Result<ProgramCounter, ErrorKind> {
+
+        let (width, height) = self.resolution();
+        let (cols, rows) = if n == 0 { (16, 16) } else { (8, n as usize) };
+        let bytes_per_row = cols / 8;
+        let planes = (self.planes & 1) as usize + (self.planes >> 1 & 1) as usize;
+        self.check_ram(self.reg_i, rows * bytes_per_row * planes)?;
 
         self.reg_v[0xf] = 0;
 
-        for byte in 0..n {
-            let y : usize = ((self.reg_v[vy].wrapping_add(byte))).into();
-            for bit in 0..8 {
-                let x : usize = ((self.reg_v[vx].wrapping_add(bit))).into();
+        // the starting position always wraps, the sprite itself either clips or wraps
+        let x0 = self.reg_v[vx] as usize % width;
+        let y0 = self.reg_v[vy] as usize % height;
+
+        // each selected plane takes its own copy of the sprite data, one after the other
+        let mut addr = self.reg_i;
+
+        for plane in [1, 2] {
+            if self.planes & plane == 0 {
+                continue;
+            }
+
+            for row in 0..rows {
+                let mut sprite: u16 = 0;
+                for _ in 0..bytes_per_row {
+                    sprite = (sprite << 8) | self.read_ram(addr)? as u16;
+                    addr += 1;
+                }
 
-                if x < CHIP8_WIDTH && y < CHIP8_HEIGHT {
-                    let color = (self.ram[self.reg_i + byte as usize] >> (7 - bit)) & 1;
-                    self.reg_v[0xf] |= color & self.vram[y][x];
-                    self.vram[y][x] ^= color;
+                for bit in 0..cols {
+                    let (mut x, mut y) = (x0 + bit, y0 + row);
+
+                    if x >= width || y >= height {
+                        if self.quirks.clip_sprites {
+                            continue;
+                        }
+                        x %= width;
+                        y %= height;
+                    }
+
+                    if (sprite >> (cols - 1 - bit)) & 1 == 1 {
+                        if self.vram[y][x] & plane != 0 {
+                            self.reg_v[0xf] = 1;
+                        }
+                        self.vram[y][x] ^= plane;
+                    }
                 }
             }
         }
 
         self.vram_changed = true;
-        ProgramCounter::Next
+        Ok(ProgramCounter::Next)
     }
 
     /*
      * LD B, Vx
      * Store BCD representation of Vx in memory locations I, I+1, and I+2.
      */
-    fn op_fx33(&mut self, vx:usize) -> ProgramCounter {
+    fn op_fx33(&mut self, vx:usize) -> Result<ProgramCounter, ErrorKind> {
         let x = self.reg_v[vx];
-        
-        self.ram[self.reg_i] = x / 100;
-        self.ram[self.reg_i + 1] = (x % 100) / 10;
-        self.ram[self.reg_i + 2] = x % 10;
+        self.check_ram(self.reg_i, 3)?;
 
-        ProgramCounter::Next
+        self.write_ram(self.reg_i, x / 100)?;
+        self.write_ram(self.reg_i + 1, (x % 100) / 10)?;
+        self.write_ram(self.reg_i + 2, x % 10)?;
+
+        Ok(ProgramCounter::Next)
     }
     
     /*
      * LD Vx, [I]
      * Read registers V0 through Vx from memory starting at location I.
      */
-    fn op_fx65(&mut self, vx:usize) -> ProgramCounter {
+    fn op_fx65(&mut self, vx:usize) -> Result<ProgramCounter, ErrorKind> {
+        self.check_ram(self.reg_i, vx + 1)?;
+
+        for i in 0..vx + 1 {
+            self.reg_v[i] = self.read_ram(self.reg_i + i)?;
+        }
+
+        if self.quirks.load_store_increments_i {
+            self.reg_i += vx + 1;
+        }
+
+        Ok(ProgramCounter::Next)
+    }
+
+    /*
+     * LD [I], Vx
+     * Store registers V0 through Vx in memory starting at location I.
+     */
+    fn op_fx55(&mut self, vx:usize) -> Result<ProgramCounter, ErrorKind> {
+        self.check_ram(self.reg_i, vx + 1)?;
 
         for i in 0..vx + 1 {
-            self.reg_v[i] = self.ram[self.reg_i + i];
+            self.write_ram(self.reg_i + i, self.reg_v[i])?;
         }
 
+        if self.quirks.load_store_increments_i {
+            self.reg_i += vx + 1;
+        }
+
+        Ok(ProgramCounter::Next)
+    }
+
+    /*
+     * LD Vx, K
+     * Wait for a key press, store the value of the key in Vx.
+     */
+    fn op_fx0a(&mut self, vx:usize) -> ProgramCounter {
+        self.key_wait = KeyWait::Press(vx);
         ProgramCounter::Next
     }
 
@@ -276,7 +794,7 @@ impl Processor {
      * Set I = location of sprite for digit Vx.
      */
     fn op_fx29(&mut self, vx:usize) -> ProgramCounter {
-        self.reg_i = (self.reg_v[vx] * 5).into();
+        self.reg_i = (self.reg_v[vx] & 0xf) as usize * 5;
         ProgramCounter::Next
     }
      
@@ -296,10 +814,8 @@ impl Processor {
      * Clear the vram
      */
     fn op_00e0(&mut self) -> ProgramCounter {
-        for y in 0..CHIP8_HEIGHT {
-            for x in 00..CHIP8_HEIGHT {
-                self.vram[y][x] = 0;
-            }
+        for pixel in self.vram.iter_mut().flatten() {
+            *pixel &= !self.planes;
         }
 
         self.vram_changed = true;
@@ -308,131 +824,327 @@ impl Processor {
     }
 
     /*
-     * LD DT, Vx
-     * Set delay timer = Vx.
+     * Move the selected planes by (dx, dy) pixels, pixels scrolled in are blank.
      */
-    fn op_fx15(&mut self, vx: usize) -> ProgramCounter {
-        self.reg_dt = self.reg_v[vx];
-        ProgramCounter::Next
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let (width, height) = self.resolution();
+        let old = self.vram;
+
+        for y in 0..height {
+            for x in 0..width {
+                let sx = x as isize - dx;
+                let sy = y as isize - dy;
+
+                let moved = if sx >= 0 && sy >= 0 && (sx as usize) < width && (sy as usize) < height {
+                    old[sy as usize][sx as usize] & self.planes
+                } else {
+                    0
+                };
+
+                self.vram[y][x] = (old[y][x] & !self.planes) | moved;
+            }
+        }
+
+        self.vram_changed = true;
     }
 
     /*
-     * LD ST, Vx
-     * Set sound timer = Vx.
+     * SCD nibble
+     * Scroll the display down n pixels.
      */
-    fn op_fx18(&mut self, vx: usize) -> ProgramCounter {
-        self.reg_st = self.reg_v[vx];
+    fn op_00cn(&mut self, n:u8) -> ProgramCounter {
+        self.scroll(0, n as isize);
         ProgramCounter::Next
     }
 
     /*
-     * ADD I, Vx
-     * Set I = I + Vx.
+     * SCU nibble
+     * Scroll the display up n pixels (XO-CHIP).
      */
-    fn op_fx1e(&mut self, vx: usize) -> ProgramCounter {
-        
-        let x = self.reg_v[vx] as u32;
-        let i = self.reg_i as u32;
-        let r = i + x;
-
-        self.reg_i = r as usize;
-
+    fn op_00dn(&mut self, n:u8) -> ProgramCounter {
+        self.scroll(0, -(n as isize));
         ProgramCounter::Next
     }
 
     /*
-     * LD Vx, DT
-     * Set Vx = delay timer value.
+     * SCR
+     * Scroll the display right 4 pixels.
      */
-    fn op_fx07(&mut self, vx: usize) -> ProgramCounter {
-        self.reg_v[vx] = self.reg_dt;
+    fn op_00fb(&mut self) -> ProgramCounter {
+        self.scroll(4, 0);
         ProgramCounter::Next
     }
 
     /*
-     * SE Vx, byte
-     * Skip next instruction if Vx == kk.
+     * SCL
+     * Scroll the display left 4 pixels.
      */
-    fn op_3xkk(&mut self, vx:usize, kk:u8) -> ProgramCounter {
-        if self.reg_v[vx] == kk {
-            ProgramCounter::Skip
-        } else {
-            ProgramCounter::Next
-        }
+    fn op_00fc(&mut self) -> ProgramCounter {
+        self.scroll(-4, 0);
+        ProgramCounter::Next
     }
 
     /*
-     * SNE Vx, byte
-     * Skip next instruction if Vx != kk.
+     * EXIT
+     * Stop the interpreter.
      */
-    fn op_4xkk(&mut self, vx:usize, kk:u8) -> ProgramCounter {
-        if self.reg_v[vx] != kk {
-            ProgramCounter::Skip
-        } else {
-            ProgramCounter::Next
-        }
+    fn op_00fd(&mut self) -> ProgramCounter {
+        self.exited = true;
+        ProgramCounter::Jump(self.reg_pc)
     }
-    
+
     /*
-     * RND Vx, byte
-     * Set Vx = random byte AND kk.
+     * LOW
+     * Switch to the 64x32 display, clearing it.
      */
-    fn op_cxkk(&mut self, vx:usize, kk:u8) -> ProgramCounter {
-        self.reg_v[vx] = rand::random::<u8>() & kk;
+    fn op_00fe(&mut self) -> ProgramCounter {
+        self.hires = false;
+        self.vram = [[0; SCHIP_WIDTH]; SCHIP_HEIGHT];
+        self.vram_changed = true;
         ProgramCounter::Next
     }
 
     /*
-     * SKNP Vx
-     * Skip next instruction if key with the value of Vx is not pressed.
+     * HIGH
+     * Switch to the 128x64 display, clearing it.
      */
-    fn op_exa1(&mut self, vx:usize) -> ProgramCounter {
-        if self.keypad[self.reg_v[vx] as usize] == false {
-            ProgramCounter::Skip
-        } else {
-            ProgramCounter::Next
-        }
-    }    
-    
+    fn op_00ff(&mut self) -> ProgramCounter {
+        self.hires = true;
+        self.vram = [[0; SCHIP_WIDTH]; SCHIP_HEIGHT];
+        self.vram_changed = true;
+        ProgramCounter::Next
+    }
+
     /*
-     * SKP Vx
-     * Skip next instruction if key with the value of Vx is pressed.
+     * LD HF, Vx
+     * Set I = location of the 10 byte sprite for digit Vx.
      */
-    fn op_ex9e(&mut self, vx:usize) -> ProgramCounter {
-        if self.keypad[self.reg_v[vx] as usize] == true {
-            ProgramCounter::Skip
-        } else {
+    fn op_fx30(&mut self, vx:usize) -> ProgramCounter {
+        self.reg_i = BIG_FONT_ADDR + (self.reg_v[vx] & 0xf) as usize * 10;
+        ProgramCounter::Next
+    }
+
+    /*
+     * LD DT, Vx
+     * Set delay timer = Vx.
+     */
+    fn op_fx15(&mut self, vx: usize) -> ProgramCounter {
+        self.timers.delay = self.reg_v[vx];
+        ProgramCounter::Next
+    }
+
+    /*
+     * LD ST, Vx
+     * Set sound timer = Vx.
+     */
+    fn op_fx18(&mut self, vx: usize) -> ProgramCounter {
+        self.timers.sound = self.reg_v[vx];
+        ProgramCounter::Next
+    }
+
+    /*
+     * LD I, long NNNN
+     * Set I = the 16 bit address stored in the next word (XO-CHIP).
+     */
+    fn op_f000(&mut self) -> Result<ProgramCounter, ErrorKind> {
+        // the operand is part of the instruction, so it's a fetch rather than a data read
+        let addr = self.opcode_at(self.reg_pc + 2).ok_or(ErrorKind::MemoryOutOfBounds(self.reg_pc + 2))?;
+
+        self.reg_i = addr as usize;
+        Ok(ProgramCounter::Jump(self.reg_pc + CHIP8_OPCODE_SIZE * 2))
+    }
+
+    /*
+     * PLANE n
+     * Select the bitplanes used for drawing, clearing and scrolling (XO-CHIP).
+     */
+    fn op_fn01(&mut self, n:usize) -> ProgramCounter {
+        self.planes = (n & 0b11) as u8;
+        ProgramCounter::Next
+    }
+
+    /*
+     * SAVE Vx - Vy
+     * Store registers Vx through Vy in memory starting at location I,
+     * in either order. I is left unchanged (XO-CHIP).
+     */
+    fn op_5xy2(&mut self, vx:usize, vy:usize) -> Result<ProgramCounter, ErrorKind> {
+        let registers: Vec<usize> = if vx <= vy { (vx..=vy).collect() } else { (vy..=vx).rev().collect() };
+        self.check_ram(self.reg_i, registers.len())?;
+
+        for (i, &r) in registers.iter().enumerate() {
+            self.write_ram(self.reg_i + i, self.reg_v[r])?;
+        }
+
+        Ok(ProgramCounter::Next)
+    }
+
+    /*
+     * LOAD Vx - Vy
+     * Read registers Vx through Vy from memory starting at location I,
+     * in either order. I is left unchanged (XO-CHIP).
+     */
+    fn op_5xy3(&mut self, vx:usize, vy:usize) -> Result<ProgramCounter, ErrorKind> {
+        let registers: Vec<usize> = if vx <= vy { (vx..=vy).collect() } else { (vy..=vx).rev().collect() };
+        self.check_ram(self.reg_i, registers.len())?;
+
+        for (i, &r) in registers.iter().enumerate() {
+            self.reg_v[r] = self.read_ram(self.reg_i + i)?;
+        }
+
+        Ok(ProgramCounter::Next)
+    }
+
+    /*
+     * LD R, Vx
+     * Store V0 through Vx in the RPL user flags (SUPER-CHIP).
+     */
+    fn op_fx75(&mut self, vx:usize) -> ProgramCounter {
+        self.rpl[..=vx].copy_from_slice(&self.reg_v[..=vx]);
+        ProgramCounter::Next
+    }
+
+    /*
+     * LD Vx, R
+     * Read V0 through Vx from the RPL user flags (SUPER-CHIP).
+     */
+    fn op_fx85(&mut self, vx:usize) -> ProgramCounter {
+        self.reg_v[..=vx].copy_from_slice(&self.rpl[..=vx]);
+        ProgramCounter::Next
+    }
+
+    /*
+     * AUDIO
+     * Load the 16 byte XO-CHIP audio pattern from memory starting at location I.
+     */
+    fn op_f002(&mut self) -> Result<ProgramCounter, ErrorKind> {
+        let mut buffer = [0; PATTERN_SIZE];
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            *byte = self.read_ram(self.reg_i + i)?;
+        }
+
+        self.audio_buffer = Some(buffer);
+        Ok(ProgramCounter::Next)
+    }
+
+    /*
+     * PITCH Vx
+     * Set the XO-CHIP audio playback pitch = Vx.
+     */
+    fn op_fx3a(&mut self, vx: usize) -> ProgramCounter {
+        self.pitch = self.reg_v[vx];
+        ProgramCounter::Next
+    }
+
+    /*
+     * ADD I, Vx
+     * Set I = I + Vx.
+     */
+    fn op_fx1e(&mut self, vx: usize) -> ProgramCounter {
+        
+        let x = self.reg_v[vx] as u32;
+        let i = self.reg_i as u32;
+        let r = i + x;
+
+        self.reg_i = r as usize;
+
+        if self.quirks.add_i_sets_vf {
+            self.reg_v[0xf] = if r > 0xfff { 1 } else { 0 };
+        }
+
+        ProgramCounter::Next
+    }
+
+    /*
+     * LD Vx, DT
+     * Set Vx = delay timer value.
+     */
+    fn op_fx07(&mut self, vx: usize) -> ProgramCounter {
+        self.reg_v[vx] = self.timers.delay;
+        ProgramCounter::Next
+    }
+
+    /*
+     * SE Vx, byte
+     * Skip next instruction if Vx == kk.
+     */
+    fn op_3xkk(&mut self, vx:usize, kk:u8) -> ProgramCounter {
+        if self.reg_v[vx] == kk {
+            ProgramCounter::Skip
+        } else {
             ProgramCounter::Next
         }
     }
 
     /*
-     *  SHL Vx {, Vy}
-     *  Set Vx = Vx << 1 or Vy if > 0.
+     * SNE Vx, byte
+     * Skip next instruction if Vx != kk.
      */
-    fn op_8xye(&mut self, vx:usize, vy:usize) -> ProgramCounter {
+    fn op_4xkk(&mut self, vx:usize, kk:u8) -> ProgramCounter {
+        if self.reg_v[vx] != kk {
+            ProgramCounter::Skip
+        } else {
+            ProgramCounter::Next
+        }
+    }
+    
+    /*
+     * RND Vx, byte
+     * Set Vx = random byte AND kk.
+     */
+    fn op_cxkk(&mut self, vx:usize, kk:u8) -> ProgramCounter {
+        self.reg_v[vx] = self.rng.gen::<u8>() & kk;
+        ProgramCounter::Next
+    }
 
-        self.reg_v[0xf] = (self.reg_v[vx] & 0b1000_0000) >> 7; 
-        if vx == vy {
-            self.reg_v[vx] = self.reg_v[vx] << 1
+    /*
+     * SKNP Vx
+     * Skip next instruction if key with the value of Vx is not pressed.
+     */
+    fn op_exa1(&mut self, vx:usize) -> ProgramCounter {
+        if !self.keypad[(self.reg_v[vx] & 0xf) as usize] {
+            ProgramCounter::Skip
+        } else {
+            ProgramCounter::Next
+        }
+    }    
+    
+    /*
+     * SKP Vx
+     * Skip next instruction if key with the value of Vx is pressed.
+     */
+    fn op_ex9e(&mut self, vx:usize) -> ProgramCounter {
+        if self.keypad[(self.reg_v[vx] & 0xf) as usize] {
+            ProgramCounter::Skip
         } else {
-            self.reg_v[vx] = self.reg_v[vx] << self.reg_v[vy]
+            ProgramCounter::Next
         }
+    }
+
+    /*
+     *  SHL Vx {, Vy}
+     *  Set Vx = Vx << 1, or Vy << 1 on the COSMAC VIP.
+     */
+    fn op_8xye(&mut self, vx:usize, vy:usize) -> ProgramCounter {
+
+        let value = if self.quirks.shift_uses_vy { self.reg_v[vy] } else { self.reg_v[vx] };
+
+        self.reg_v[vx] = value << 1;
+        self.reg_v[0xf] = (value & 0b1000_0000) >> 7;
 
         ProgramCounter::Next
     }
 
     /*
      *  SHR Vx {, Vy}
-     *  Set Vx = Vx >> 1 or Vy if > 0.
+     *  Set Vx = Vx >> 1, or Vy >> 1 on the COSMAC VIP.
     */
     fn op_8xy6(&mut self, vx:usize, vy:usize) -> ProgramCounter {
-        self.reg_v[0xf] = self.reg_v[vx] & 0b0000_0001; 
-        if vx == vy {
-            self.reg_v[vx] = self.reg_v[vx] >> 1
-        } else {
-            self.reg_v[vx] = self.reg_v[vx] >> self.reg_v[vy]
-        }
+
+        let value = if self.quirks.shift_uses_vy { self.reg_v[vy] } else { self.reg_v[vx] };
+
+        self.reg_v[vx] = value >> 1;
+        self.reg_v[0xf] = value & 0b0000_0001;
 
         ProgramCounter::Next
     }
@@ -443,6 +1155,9 @@ impl Processor {
      */
     fn op_8xy2(&mut self, vx:usize, vy:usize) -> ProgramCounter {
         self.reg_v[vx] &= self.reg_v[vy]; 
+        if self.quirks.logic_resets_vf {
+            self.reg_v[0xf] = 0;
+        }
         ProgramCounter::Next
     }
 
@@ -465,8 +1180,8 @@ impl Processor {
         let y = self.reg_v[vy] as u16;
         let r = x + y;
 
-        self.reg_v[0xf] = if r > 0xff { 1 } else { 0 };
         self.reg_v[vx] = r as u8; 
+        self.reg_v[0xf] = if r > 0xff { 1 } else { 0 };
 
         ProgramCounter::Next
     }
@@ -480,11 +1195,81 @@ impl Processor {
         let x = self.reg_v[vx];
         let y = self.reg_v[vy];
 
-        self.reg_v[0xf] = if x > y { 0 } else { 1 };
         self.reg_v[vx] = x.wrapping_sub(y);
+        self.reg_v[0xf] = if x >= y { 1 } else { 0 };
         ProgramCounter::Next
     }
 
+    /*
+     * SUBN Vx, Vy
+     * Set Vx = Vy - Vx, set VF = NOT borrow.
+     */
+    fn op_8xy7(&mut self, vx:usize, vy:usize) -> ProgramCounter {
+
+        let x = self.reg_v[vx];
+        let y = self.reg_v[vy];
+
+        self.reg_v[vx] = y.wrapping_sub(x);
+        self.reg_v[0xf] = if y >= x { 1 } else { 0 };
+        ProgramCounter::Next
+    }
+
+    /*
+     * OR Vx, Vy
+     * Set Vx = Vx OR Vy.
+     */
+    fn op_8xy1(&mut self, vx:usize, vy:usize) -> ProgramCounter {
+        self.reg_v[vx] |= self.reg_v[vy];
+        if self.quirks.logic_resets_vf {
+            self.reg_v[0xf] = 0;
+        }
+        ProgramCounter::Next
+    }
+
+    /*
+     * XOR Vx, Vy
+     * Set Vx = Vx XOR Vy.
+     */
+    fn op_8xy3(&mut self, vx:usize, vy:usize) -> ProgramCounter {
+        self.reg_v[vx] ^= self.reg_v[vy];
+        if self.quirks.logic_resets_vf {
+            self.reg_v[0xf] = 0;
+        }
+        ProgramCounter::Next
+    }
+
+    /*
+     * SE Vx, Vy
+     * Skip next instruction if Vx == Vy.
+     */
+    fn op_5xy0(&mut self, vx:usize, vy:usize) -> ProgramCounter {
+        if self.reg_v[vx] == self.reg_v[vy] {
+            ProgramCounter::Skip
+        } else {
+            ProgramCounter::Next
+        }
+    }
+
+    /*
+     * SNE Vx, Vy
+     * Skip next instruction if Vx != Vy.
+     */
+    fn op_9xy0(&mut self, vx:usize, vy:usize) -> ProgramCounter {
+        if self.reg_v[vx] != self.reg_v[vy] {
+            ProgramCounter::Skip
+        } else {
+            ProgramCounter::Next
+        }
+    }
+
+    /*
+     * JP V0, addr
+     * Jump to location addr + V0, or xnn + Vx on CHIP-48 and SUPER-CHIP.
+     */
+    fn op_bnnn(&mut self, addr:usize) -> ProgramCounter {
+        let offset = if self.quirks.jump_uses_vx { self.reg_v[addr >> 8] } else { self.reg_v[0] };
+        ProgramCounter::Jump(addr + offset as usize)
+    }
 
 }
 
@@ -492,20 +1277,25 @@ impl Processor {
 mod test {
 
     use super::*;
+    use crate::trace::{TraceFilter, TraceFormat};
 
     #[test]
     fn test_initial_state() {
-        let p = Processor::new();
+        let p = Processor::new(Quirks::default());
         
         assert_eq!(p.reg_pc, 0x200);
         assert_eq!(p.ram[0..80], FONT_SET);
-        assert_eq!(p.vram_changed, false);
+        assert_eq!(p.ram[BIG_FONT_ADDR..BIG_FONT_ADDR + 160], BIG_FONT_SET);
+        assert!(!p.hires);
+        assert_eq!(p.planes, 1);
+        assert_eq!(p.ram.len(), CHIP8_RAM);
+        assert!(!p.vram_changed);
     }
 
     #[test]
     fn test_load() {
         let data = [1, 2, 3, 4, 5];
-        let mut p = Processor::new();
+        let mut p = Processor::new(Quirks::default());
         p.load(&data);
 
         assert_eq!(p.ram[0x200..0x205], [1, 2, 3, 4, 5]);
@@ -513,7 +1303,7 @@ mod test {
 
     #[test]
     fn op_6xkk() {
-        let mut p = Processor::new();
+        let mut p = Processor::new(Quirks::default());
         for n in 0..15 {
             p.op_6xkk(n as usize, n);
             
@@ -523,7 +1313,7 @@ mod test {
 
     #[test]
     fn op_annn() {
-        let mut p = Processor::new();
+        let mut p = Processor::new(Quirks::default());
         p.op_annn(0x123);
         
         assert_eq!(p.reg_i, 0x123);
@@ -531,54 +1321,137 @@ mod test {
     
     #[test]
     fn op_2nnn() {
-        let mut p = Processor::new();
-        let pc = p.op_2nnn(0x123);
+        let mut p = Processor::new(Quirks::default());
+        let pc = p.op_2nnn(0x123).unwrap();
         
         assert_eq!(p.stack[0], 0x202);
         assert_eq!(p.reg_sp, 1);
         assert!(matches!(pc, ProgramCounter::Jump(0x123)));
     }
 
+    #[test]
+    fn op_cxkk() {
+        let mut a = Processor::new(Quirks::default());
+        let mut b = Processor::new(Quirks::default());
+        a.seed_rng(42);
+        b.seed_rng(42);
+
+        for _ in 0..16 {
+            a.op_cxkk(0, 0xff);
+            b.op_cxkk(0, 0xff);
+            assert_eq!(a.reg_v[0], b.reg_v[0]);
+
+            a.op_cxkk(1, 0x0f);
+            b.op_cxkk(1, 0x0f);
+            assert_eq!(a.reg_v[1] & 0xf0, 0);
+        }
+    }
+
     #[test]
     fn op_dxyn() {
-        let mut p = Processor::new();
+        let mut p = Processor::new(Quirks::default());
         let data = [0b10101010];
         p.load(&data);
         p.reg_v[0] = 10;
         p.reg_v[1] = 20;
         p.reg_i = 0x200;
-        p.op_dxyn(0, 1, 1);
+        p.op_dxyn(0, 1, 1).unwrap();
         
-        assert_eq!(p.vram_changed, true);
+        assert!(p.vram_changed);
         assert_eq!(p.vram[20][10..18], [1,0,1,0,1,0,1,0]);
     }
 
     #[test]
     fn op_fx33() {
-        let mut p = Processor::new();
+        let mut p = Processor::new(Quirks::default());
         p.reg_i = 100;
         p.reg_v[0] = 145;
-        p.op_fx33(0);
+        p.op_fx33(0).unwrap();
 
         assert_eq!(p.ram[100], 1);
         assert_eq!(p.ram[101], 4);
         assert_eq!(p.ram[102], 5);
     }
 
+    #[test]
+    fn memory_accesses() {
+        let mut p = Processor::new(Quirks::default());
+        // LD I, 0x300; LD B, V0; LD I, long 0x0300
+        p.load(&[0xa3, 0x00, 0xf0, 0x33, 0xf0, 0x00, 0x03, 0x00]);
+        p.reg_v[0] = 145;
+
+        // not recorded until asked for
+        p.tick([false; 16]).unwrap();
+        p.tick([false; 16]).unwrap();
+        assert!(p.memory_accesses().is_empty());
+
+        p.track_memory(true);
+        p._reset_pc();
+        p.tick([false; 16]).unwrap();
+        assert!(p.memory_accesses().is_empty());
+        p.tick([false; 16]).unwrap();
+        assert_eq!(p.memory_accesses(), [
+            MemoryAccess { pc: 0x202, access: Access::Write, addr: 0x300, value: 1 },
+            MemoryAccess { pc: 0x202, access: Access::Write, addr: 0x301, value: 4 },
+            MemoryAccess { pc: 0x202, access: Access::Write, addr: 0x302, value: 5 },
+        ]);
+
+        // the long operand is fetched, not read
+        p.tick([false; 16]).unwrap();
+        assert!(p.memory_accesses().is_empty());
+        assert_eq!(p.reg_i, 0x300);
+    }
+
+    #[test]
+    fn restore_keeps_memory_tracking() {
+        let mut p = Processor::new(Quirks::default());
+        // LD I, 0x300; LD B, V0
+        p.load(&[0xa3, 0x00, 0xf0, 0x33]);
+        p.track_memory(true);
+        p.tick([false; 16]).unwrap();
+
+        let state = p.snapshot();
+        p.restore(&state).unwrap();
+        p.tick([false; 16]).unwrap();
+        assert_eq!(p.memory_accesses().len(), 3);
+    }
+
+    #[test]
+    fn restore_keeps_rpl_flags() {
+        let mut p = Processor::new(Quirks::SUPER_CHIP);
+        p.load(&[0x12, 0x00]);
+        let state = p.snapshot();
+
+        p.set_rpl_flags([1; RPL_FLAGS]);
+        p.restore(&state).unwrap();
+        assert_eq!(p.rpl_flags(), [1; RPL_FLAGS]);
+    }
+
+    #[test]
+    fn restore_keeps_tracer() {
+        let mut p = Processor::new(Quirks::default());
+        p.load(&[0x12, 0x00]);
+        p.set_tracer(Some(Tracer::new(Box::new(std::io::sink()), TraceFormat::Text, TraceFilter::default()).unwrap()));
+
+        let state = p.snapshot();
+        p.restore(&state).unwrap();
+        assert!(p.take_tracer().is_some());
+    }
+
     #[test]
     fn op_fx65() {
-        let mut p = Processor::new();
+        let mut p = Processor::new(Quirks::default());
         let data = [1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16];
         p.load(&data);
         p.reg_i = 0x200;
-        p.op_fx65(0xf);
+        p.op_fx65(0xf).unwrap();
 
         assert_eq!(p.reg_v, [1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16]);
     }
 
     #[test]
     fn op_fx29() {
-        let mut p = Processor::new();
+        let mut p = Processor::new(Quirks::default());
         p.reg_v[3] = 5;
         p.op_fx29(3);
 
@@ -587,7 +1460,7 @@ mod test {
     
     #[test]
     fn op_7xkk() {
-        let mut p = Processor::new();
+        let mut p = Processor::new(Quirks::default());
         p.reg_v[3] = 5;
         p.op_7xkk(3, 15);
 
@@ -601,9 +1474,9 @@ mod test {
 
     #[test]
     fn op_00ee() {
-        let mut p = Processor::new();
-        p.op_2nnn(0x100);
-        let pc = p.op_00ee();
+        let mut p = Processor::new(Quirks::default());
+        p.op_2nnn(0x100).unwrap();
+        let pc = p.op_00ee().unwrap();
 
         assert!(matches!(pc, ProgramCounter::Jump(0x202)));
         assert_eq!(p.reg_sp, 0);
@@ -611,56 +1484,64 @@ mod test {
 
     #[test]
     fn op_00e0() {
-        let mut p = Processor::new();
+        let mut p = Processor::new(Quirks::default());
 
         p.vram[1][1] = 1;
         p.op_00e0();
         assert_eq!(p.vram[1][1] ,0);
-        assert_eq!(p.vram_changed, true);
+        assert!(p.vram_changed);
     }
 
     #[test]
     fn op_1nnn() {
-        let mut p = Processor::new();
+        let mut p = Processor::new(Quirks::default());
         let pc = p.op_1nnn(0x123);
         assert!(matches!(pc, ProgramCounter::Jump(0x123)));
     }
 
     #[test]
     fn op_fx15() {
-        let mut p = Processor::new();
+        let mut p = Processor::new(Quirks::default());
         p.reg_v[0x1] = 15;
         p.op_fx15(0x1);
 
-        assert_eq!(p.reg_dt, 15);
+        assert_eq!(p.timers.delay, 15);
     }
 
     #[test]
     fn op_fx07() {
-        let mut p = Processor::new();
-        p.reg_dt = 15;
+        let mut p = Processor::new(Quirks::default());
+        p.timers.delay = 15;
         p.op_fx07(0x1);
         assert_eq!(p.reg_v[0x1], 15);
     }
 
+    #[test]
+    fn op_fx18() {
+        let mut p = Processor::new(Quirks::default());
+        p.reg_v[0x1] = 15;
+        p.op_fx18(0x1);
+
+        assert_eq!(p.timers.sound, 15);
+    }
+
     #[test]
     fn delay_timers() {
-        let mut p = Processor::new();
+        let mut p = Processor::new(Quirks::default());
         
-        p.reg_dt = 100;
-        p.reg_st = 200;
+        p.timers.delay = 100;
+        p.timers.sound = 200;
         for _ in 0..60 {
-            p.update_timers(Duration::from_secs_f32(1.0/60.0));
+            p.step_timers();
         }
 
-        assert_eq!(p.timer_cycle.as_secs(), Duration::from_secs(1).as_secs());
-        assert_eq!(p.reg_dt, 40);
-        assert_eq!(p.reg_st, 140);
+        assert_eq!(p.timers.delay, 40);
+        assert_eq!(p.timers.sound, 140);
     }
 
     #[test]
     fn op_3xkk() {
-        let mut p = Processor::new();
+        let mut p = Processor::new(Quirks::default());
         p.reg_v[0x1] = 15;
 
         let pc1 = p.op_3xkk(0x1, 10);
@@ -674,7 +1555,7 @@ mod test {
     
     #[test]
     fn op_4xkk() {
-        let mut p = Processor::new();
+        let mut p = Processor::new(Quirks::default());
         p.reg_v[0x1] = 15;
 
         let pc1 = p.op_4xkk(0x1, 10);
@@ -688,11 +1569,11 @@ mod test {
 
     #[test]
     fn op_exa1() {
-        let mut p = Processor::new();
+        let mut p = Processor::new(Quirks::default());
         p.load(&[0x00, 0xe0]);
 
         let keymap = [ true, false, false, false, false, false, false, false, false, false, false, false, false, false, false, false];
-        p.tick(Duration::ZERO, keymap);
+        p.tick(keymap).unwrap();
         p.reg_v[0x0] = 0;
         p.reg_v[0x1] = 1;
 
@@ -705,11 +1586,11 @@ mod test {
 
     #[test]
     fn op_ex9e() {
-        let mut p = Processor::new();
+        let mut p = Processor::new(Quirks::default());
         p.load(&[0x00, 0xe0]);
 
         let keymap = [ true, false, false, false, false, false, false, false, false, false, false, false, false, false, false, false];
-        p.tick(Duration::ZERO, keymap);
+        p.tick(keymap).unwrap();
         p.reg_v[0x0] = 0;
         p.reg_v[0x1] = 1;
 
@@ -722,59 +1603,65 @@ mod test {
 
     #[test]
     fn op_8xye(){
-        let mut p = Processor::new();
+        let mut p = Processor::new(Quirks::COSMAC_VIP);
 
         p.reg_v[0] = 0b0000_0001;
-        p.reg_v[1] = 4;
+        p.reg_v[1] = 0b1000_0100;
         p.op_8xye(0, 1);
-        assert_eq!(p.reg_v[0], 0b0001_0000);
+        assert_eq!(p.reg_v[0], 0b0000_1000);
+        assert_eq!(p.reg_v[0xf], 1);
 
         p.reg_v[1] = 0b0000_0001;
         p.op_8xye(1, 1);
         assert_eq!(p.reg_v[1], 0b0000_0010);
+        assert_eq!(p.reg_v[0xf], 0);
 
-        p.reg_v[0] = 0b0000_0001;
-        p.op_8xye(0, 0);
-        assert_eq!(p.reg_v[0], 0b0000_0010);
+        let mut p = Processor::new(Quirks::SUPER_CHIP);
 
         p.reg_v[0] = 0b0000_0001;
-        p.op_8xye(0, 0);
+        p.reg_v[1] = 0b1000_0100;
+        p.op_8xye(0, 1);
+        assert_eq!(p.reg_v[0], 0b0000_0010);
         assert_eq!(p.reg_v[0xf], 0);
 
         p.reg_v[0] = 0b1000_0000;
-        p.op_8xye(0, 0);
+        p.op_8xye(0, 1);
+        assert_eq!(p.reg_v[0], 0);
         assert_eq!(p.reg_v[0xf], 1);
     }
 
     #[test]
     fn op_8xy6(){
-        let mut p = Processor::new();
+        let mut p = Processor::new(Quirks::COSMAC_VIP);
 
         p.reg_v[0] = 0b1000_0000;
-        p.reg_v[1] = 4;
+        p.reg_v[1] = 0b0010_0001;
         p.op_8xy6(0, 1);
-        assert_eq!(p.reg_v[0], 0b0000_1000);
+        assert_eq!(p.reg_v[0], 0b0001_0000);
+        assert_eq!(p.reg_v[0xf], 1);
 
         p.reg_v[1] = 0b1000_0000;
         p.op_8xy6(1, 1);
         assert_eq!(p.reg_v[1], 0b0100_0000);
+        assert_eq!(p.reg_v[0xf], 0);
 
-        p.reg_v[0] = 0b1000_0000;
-        p.op_8xy6(0, 0);
-        assert_eq!(p.reg_v[0], 0b0100_0000);
+        let mut p = Processor::new(Quirks::SUPER_CHIP);
 
         p.reg_v[0] = 0b1000_0000;
-        p.op_8xy6(0, 0);
+        p.reg_v[1] = 0b0010_0001;
+        p.op_8xy6(0, 1);
+        assert_eq!(p.reg_v[0], 0b0100_0000);
         assert_eq!(p.reg_v[0xf], 0);
 
         p.reg_v[0] = 0b0000_0001;
-        p.op_8xy6(0, 0);
+        p.op_8xy6(0, 1);
+        assert_eq!(p.reg_v[0], 0);
         assert_eq!(p.reg_v[0xf], 1);
     }
 
     #[test]
     fn op_8xy2(){
-        let mut p = Processor::new();
+        let mut p = Processor::new(Quirks::default());
 
         p.reg_v[0x0] = 0b1010_1010;
         p.reg_v[0x1] = 0b1010_1010;
@@ -785,7 +1672,7 @@ mod test {
     
     #[test]
     fn op_8xy4(){
-        let mut p = Processor::new();
+        let mut p = Processor::new(Quirks::default());
 
         p.reg_v[0x0] = 10;
         p.reg_v[0x1] = 20;
@@ -804,26 +1691,26 @@ mod test {
 
     #[test]
     fn op_8xy5(){
-        let mut p = Processor::new();
+        let mut p = Processor::new(Quirks::default());
 
         p.reg_v[0x0] = 20;
         p.reg_v[0x1] = 10;
         p.op_8xy5(0x0, 0x1);
         
-        assert_eq!(p.reg_v[0xf], 0);
+        assert_eq!(p.reg_v[0xf], 1);
         assert_eq!(p.reg_v[0x0], 10);
 
         p.reg_v[0x0] = 10;
         p.reg_v[0x1] = 20;
         p.op_8xy5(0x0, 0x1);
         
-        assert_eq!(p.reg_v[0xf], 1);
+        assert_eq!(p.reg_v[0xf], 0);
         assert_eq!(p.reg_v[0x0], 246);
     }
 
     #[test]
     fn op_8xy0(){
-        let mut p = Processor::new();
+        let mut p = Processor::new(Quirks::default());
 
         p.reg_v[0x1] = 10;
         p.op_8xy0(0x0, 0x1);
@@ -833,7 +1720,7 @@ mod test {
 
     #[test]
     fn op_fx1e(){
-        let mut p = Processor::new();
+        let mut p = Processor::new(Quirks::default());
 
         p.reg_v[0] = 10;
         p.reg_i = 10;
@@ -842,4 +1729,639 @@ mod test {
         assert_eq!(p.reg_i, 20);
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn op_0nnn() {
+        let mut p = Processor::new(Quirks::default());
+        let pc = p.op_0nnn(0x123);
+        assert!(matches!(pc, ProgramCounter::Next));
+    }
+
+    #[test]
+    fn op_5xy0() {
+        let mut p = Processor::new(Quirks::default());
+        p.reg_v[0x0] = 15;
+        p.reg_v[0x1] = 15;
+        p.reg_v[0x2] = 20;
+
+        let pc1 = p.op_5xy0(0x0, 0x1);
+        let pc2 = p.op_5xy0(0x0, 0x2);
+
+        assert!(matches!(pc1, ProgramCounter::Skip));
+        assert!(matches!(pc2, ProgramCounter::Next));
+    }
+
+    #[test]
+    fn op_9xy0() {
+        let mut p = Processor::new(Quirks::default());
+        p.reg_v[0x0] = 15;
+        p.reg_v[0x1] = 15;
+        p.reg_v[0x2] = 20;
+
+        let pc1 = p.op_9xy0(0x0, 0x1);
+        let pc2 = p.op_9xy0(0x0, 0x2);
+
+        assert!(matches!(pc1, ProgramCounter::Next));
+        assert!(matches!(pc2, ProgramCounter::Skip));
+    }
+
+    #[test]
+    fn op_8xy1(){
+        let mut p = Processor::new(Quirks::default());
+
+        p.reg_v[0x0] = 0b1010_0000;
+        p.reg_v[0x1] = 0b0000_1010;
+        p.op_8xy1(0x0, 0x1);
+
+        assert_eq!(p.reg_v[0x0], 0b1010_1010);
+    }
+
+    #[test]
+    fn op_8xy3(){
+        let mut p = Processor::new(Quirks::default());
+
+        p.reg_v[0x0] = 0b1010_1010;
+        p.reg_v[0x1] = 0b1111_0000;
+        p.op_8xy3(0x0, 0x1);
+
+        assert_eq!(p.reg_v[0x0], 0b0101_1010);
+    }
+
+    #[test]
+    fn op_8xy7(){
+        let mut p = Processor::new(Quirks::default());
+
+        p.reg_v[0x0] = 10;
+        p.reg_v[0x1] = 20;
+        p.op_8xy7(0x0, 0x1);
+
+        assert_eq!(p.reg_v[0xf], 1);
+        assert_eq!(p.reg_v[0x0], 10);
+
+        p.reg_v[0x0] = 20;
+        p.reg_v[0x1] = 10;
+        p.op_8xy7(0x0, 0x1);
+
+        assert_eq!(p.reg_v[0xf], 0);
+        assert_eq!(p.reg_v[0x0], 246);
+    }
+
+    #[test]
+    fn op_bnnn() {
+        let mut p = Processor::new(Quirks::default());
+        p.reg_v[0x0] = 0x10;
+        let pc = p.op_bnnn(0x300);
+        assert!(matches!(pc, ProgramCounter::Jump(0x310)));
+    }
+
+    #[test]
+    fn op_fx55() {
+        let mut p = Processor::new(Quirks::default());
+        p.reg_v = [1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16];
+        p.reg_i = 0x300;
+        p.op_fx55(0x3).unwrap();
+
+        assert_eq!(p.ram[0x300..0x305], [1,2,3,4,0]);
+    }
+
+    #[test]
+    fn op_fx0a() {
+        let mut p = Processor::new(Quirks::default());
+        // LD V5, K; LD V0, 0xff
+        p.load(&[0xf5, 0x0a, 0x60, 0xff]);
+
+        let mut keymap = [false; 16];
+        p.tick(keymap).unwrap();
+        assert!(matches!(p.key_wait, KeyWait::Press(0x5)));
+        assert_eq!(p.reg_pc, 0x202);
+
+        p.tick(keymap).unwrap();
+        assert_eq!(p.reg_pc, 0x202);
+
+        keymap[0xb] = true;
+        p.tick(keymap).unwrap();
+        assert!(matches!(p.key_wait, KeyWait::Release(0x5, 0xb)));
+        assert_eq!(p.reg_pc, 0x202);
+
+        keymap[0xb] = false;
+        p.tick(keymap).unwrap();
+        assert!(matches!(p.key_wait, KeyWait::None));
+        assert_eq!(p.reg_v[0x5], 0xb);
+        assert_eq!(p.reg_v[0x0], 0xff);
+        assert_eq!(p.reg_pc, 0x204);
+    }
+
+    #[test]
+    fn stack_overflow() {
+        let mut p = Processor::new(Quirks::default());
+        for _ in 0..CHIP8_STACK {
+            p.op_2nnn(0x200).unwrap();
+        }
+
+        assert!(matches!(p.op_2nnn(0x200), Err(ErrorKind::StackOverflow)));
+        assert_eq!(p.reg_sp, CHIP8_STACK);
+    }
+
+    #[test]
+    fn stack_underflow() {
+        let mut p = Processor::new(Quirks::default());
+        assert!(matches!(p.op_00ee(), Err(ErrorKind::StackUnderflow)));
+        assert_eq!(p.reg_sp, 0);
+    }
+
+    #[test]
+    fn memory_out_of_bounds() {
+        let mut p = Processor::new(Quirks::default());
+        p.reg_i = CHIP8_RAM - 1;
+
+        assert!(matches!(p.op_fx33(0), Err(ErrorKind::MemoryOutOfBounds(CHIP8_RAM))));
+        assert!(matches!(p.op_fx65(0x1), Err(ErrorKind::MemoryOutOfBounds(CHIP8_RAM))));
+        assert!(matches!(p.op_dxyn(0, 0, 2), Err(ErrorKind::MemoryOutOfBounds(CHIP8_RAM))));
+    }
+
+    #[test]
+    fn faulting_instruction_changes_nothing() {
+        let mut p = Processor::new(Quirks::default());
+        p.reg_i = CHIP8_RAM - 2;
+        p.reg_v[..4].copy_from_slice(&[1, 2, 3, 4]);
+        p.reg_v[0xf] = 7;
+
+        assert!(p.op_fx55(3).is_err());
+        assert!(p.op_5xy2(0, 3).is_err());
+        assert_eq!(p.ram[CHIP8_RAM - 2..], [0, 0]);
+
+        assert!(p.op_fx65(3).is_err());
+        assert!(p.op_5xy3(0, 3).is_err());
+        assert_eq!(p.reg_v[..4], [1, 2, 3, 4]);
+
+        p.ram[CHIP8_RAM - 2] = 0xff;
+        assert!(p.op_dxyn(0, 0, 3).is_err());
+        assert_eq!(p.reg_v[0xf], 7);
+        assert_eq!(p.vram[0][0], 0);
+        assert_eq!(p.reg_i, CHIP8_RAM - 2);
+    }
+
+    #[test]
+    fn unknown_opcode() {
+        let mut p = Processor::new(Quirks::default());
+        p.load(&[0x50, 0x01]);
+
+        let err = p.tick([false; 16]).err().unwrap();
+        assert_eq!(err, ExecutionError { pc: 0x200, opcode: 0x5001, kind: ErrorKind::UnknownOpcode });
+        assert_eq!(p.reg_pc, 0x200);
+    }
+
+    #[test]
+    fn pc_out_of_range() {
+        let mut p = Processor::new(Quirks::default());
+        p.reg_pc = CHIP8_RAM - 1;
+
+        let err = p.tick([false; 16]).err().unwrap();
+        assert_eq!(err.kind, ErrorKind::PcOutOfRange);
+        assert_eq!(err.pc, CHIP8_RAM - 1);
+    }
+
+    #[test]
+    fn quirk_load_store_increments_i() {
+        let mut p = Processor::new(Quirks::COSMAC_VIP);
+        p.reg_i = 0x300;
+        p.op_fx55(0x3).unwrap();
+        assert_eq!(p.reg_i, 0x304);
+        p.op_fx65(0x1).unwrap();
+        assert_eq!(p.reg_i, 0x306);
+
+        let mut p = Processor::new(Quirks::SUPER_CHIP);
+        p.reg_i = 0x300;
+        p.op_fx55(0x3).unwrap();
+        p.op_fx65(0x3).unwrap();
+        assert_eq!(p.reg_i, 0x300);
+    }
+
+    #[test]
+    fn quirk_add_i_sets_vf() {
+        let mut p = Processor::new(Quirks { add_i_sets_vf: true, ..Quirks::default() });
+        p.reg_v[0] = 0x10;
+        p.reg_i = 0xff8;
+        p.op_fx1e(0x0);
+        assert_eq!(p.reg_i, 0x1008);
+        assert_eq!(p.reg_v[0xf], 1);
+
+        let mut p = Processor::new(Quirks::default());
+        p.reg_v[0] = 0x10;
+        p.reg_i = 0xff8;
+        p.op_fx1e(0x0);
+        assert_eq!(p.reg_v[0xf], 0);
+    }
+
+    #[test]
+    fn quirk_clip_sprites() {
+        let mut p = Processor::new(Quirks::COSMAC_VIP);
+        p.load(&[0xff, 0xff]);
+        p.reg_v[0] = 60;
+        p.reg_v[1] = 31;
+        p.reg_i = 0x200;
+        p.op_dxyn(0, 1, 2).unwrap();
+
+        assert_eq!(p.vram[31][60..64], [1,1,1,1]);
+        assert_eq!(p.vram[31][0..4], [0,0,0,0]);
+        assert_eq!(p.vram[0][60..64], [0,0,0,0]);
+
+        let mut p = Processor::new(Quirks::XO_CHIP);
+        p.load(&[0xff, 0xff]);
+        p.reg_v[0] = 60;
+        p.reg_v[1] = 31;
+        p.reg_i = 0x200;
+        p.op_dxyn(0, 1, 2).unwrap();
+
+        assert_eq!(p.vram[31][0..4], [1,1,1,1]);
+        assert_eq!(p.vram[0][60..64], [1,1,1,1]);
+        assert_eq!(p.vram[0][0..4], [1,1,1,1]);
+    }
+
+    #[test]
+    fn quirk_sprite_position_wraps() {
+        let mut p = Processor::new(Quirks::COSMAC_VIP);
+        p.load(&[0x80]);
+        p.reg_v[0] = 64 + 3;
+        p.reg_v[1] = 32 + 2;
+        p.reg_i = 0x200;
+        p.op_dxyn(0, 1, 1).unwrap();
+
+        assert_eq!(p.vram[2][3], 1);
+    }
+
+    #[test]
+    fn quirk_logic_resets_vf() {
+        let mut p = Processor::new(Quirks::COSMAC_VIP);
+        p.reg_v[0xf] = 1;
+        p.op_8xy1(0x0, 0x1);
+        assert_eq!(p.reg_v[0xf], 0);
+
+        let mut p = Processor::new(Quirks::SUPER_CHIP);
+        p.reg_v[0xf] = 1;
+        p.op_8xy3(0x0, 0x1);
+        assert_eq!(p.reg_v[0xf], 1);
+    }
+
+    #[test]
+    fn quirk_jump_uses_vx() {
+        let mut p = Processor::new(Quirks::SUPER_CHIP);
+        p.reg_v[0x0] = 0x01;
+        p.reg_v[0x3] = 0x10;
+        let pc = p.op_bnnn(0x320);
+        assert!(matches!(pc, ProgramCounter::Jump(0x330)));
+    }
+
+    #[test]
+    fn quirk_presets() {
+        assert_eq!(Quirks::preset("SCHIP"), Some(Quirks::SUPER_CHIP));
+        assert_eq!(Quirks::preset("vip"), Some(Quirks::COSMAC_VIP));
+        assert_eq!(Quirks::preset("amiga"), None);
+    }
+
+    #[test]
+    fn output_sound_active() {
+        let mut p = Processor::new(Quirks::default());
+        assert!(!p.output().sound_active);
+
+        p.reg_v[0x0] = 1;
+        p.op_fx18(0x0);
+        assert!(p.output().sound_active);
+
+        p.step_timers();
+        assert!(!p.output().sound_active);
+    }
+
+    #[test]
+    fn op_f002() {
+        let mut p = Processor::new(Quirks::default());
+        let data = [1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16];
+        p.load(&data);
+        p.reg_i = 0x200;
+
+        assert_eq!(p.output().audio_pattern, None);
+        p.op_f002().unwrap();
+
+        let pattern = p.output().audio_pattern.unwrap();
+        assert_eq!(pattern.buffer, data);
+        assert_eq!(pattern.pitch, DEFAULT_PITCH);
+    }
+
+    #[test]
+    fn op_fx3a() {
+        let mut p = Processor::new(Quirks::default());
+        p.reg_v[0x2] = 112;
+        p.op_fx3a(0x2);
+
+        assert_eq!(p.pitch, 112);
+    }
+
+    #[test]
+    fn op_00fe_00ff() {
+        let mut p = Processor::new(Quirks::SUPER_CHIP);
+        p.vram[1][1] = 1;
+
+        p.op_00ff();
+        let state = p.output();
+        assert_eq!((state.width, state.height), (SCHIP_WIDTH, SCHIP_HEIGHT));
+        assert!(state.vram_changed);
+        assert_eq!(state.vram[1][1], 0);
+
+        p.op_00fe();
+        let state = p.output();
+        assert_eq!((state.width, state.height), (CHIP8_WIDTH, CHIP8_HEIGHT));
+    }
+
+    #[test]
+    fn op_dxy0() {
+        let mut p = Processor::new(Quirks::SUPER_CHIP);
+        let mut data = [0u8; 32];
+        data[0] = 0b1000_0000;
+        data[1] = 0b0000_0001;
+        data[31] = 0b0000_0001;
+        p.load(&data);
+        p.op_00ff();
+        p.reg_v[0] = 100;
+        p.reg_v[1] = 40;
+        p.reg_i = 0x200;
+        p.op_dxyn(0, 1, 0).unwrap();
+
+        assert_eq!(p.vram[40][100], 1);
+        assert_eq!(p.vram[40][115], 1);
+        assert_eq!(p.vram[55][115], 1);
+        assert_eq!(p.vram[55][100], 0);
+        assert_eq!(p.reg_v[0xf], 0);
+
+        p.op_dxyn(0, 1, 0).unwrap();
+        assert_eq!(p.vram[40][100], 0);
+        assert_eq!(p.reg_v[0xf], 1);
+    }
+
+    #[test]
+    fn op_dxyn_hires_clips() {
+        let mut p = Processor::new(Quirks::SUPER_CHIP);
+        p.load(&[0xff]);
+        p.op_00ff();
+        p.reg_v[0] = 124;
+        p.reg_v[1] = 63;
+        p.reg_i = 0x200;
+        p.op_dxyn(0, 1, 1).unwrap();
+
+        assert_eq!(p.vram[63][124..128], [1,1,1,1]);
+        assert_eq!(p.vram[63][0..4], [0,0,0,0]);
+    }
+
+    #[test]
+    fn op_00cn() {
+        let mut p = Processor::new(Quirks::SUPER_CHIP);
+        p.vram[0][5] = 1;
+        p.vram[30][5] = 1;
+        p.op_00cn(3);
+
+        assert_eq!(p.vram[0][5], 0);
+        assert_eq!(p.vram[3][5], 1);
+        assert_eq!(p.vram[31][5], 0);
+        assert_eq!(p.vram[33][5], 0);
+        assert!(p.vram_changed);
+    }
+
+    #[test]
+    fn op_00fb() {
+        let mut p = Processor::new(Quirks::SUPER_CHIP);
+        p.vram[2][0] = 1;
+        p.vram[2][62] = 1;
+        p.op_00fb();
+
+        assert_eq!(p.vram[2][0..5], [0,0,0,0,1]);
+        assert_eq!(p.vram[2][66], 0);
+    }
+
+    #[test]
+    fn op_00fc() {
+        let mut p = Processor::new(Quirks::SUPER_CHIP);
+        p.op_00ff();
+        p.vram[2][2] = 1;
+        p.vram[2][127] = 1;
+        p.op_00fc();
+
+        assert_eq!(p.vram[2][0..3], [0,0,0]);
+        assert_eq!(p.vram[2][123], 1);
+        assert_eq!(p.vram[2][127], 0);
+    }
+
+    #[test]
+    fn op_00fd() {
+        let mut p = Processor::new(Quirks::SUPER_CHIP);
+        // EXIT; LD V0, 0xff
+        p.load(&[0x00, 0xfd, 0x60, 0xff]);
+
+        p.tick([false; 16]).unwrap();
+        p.tick([false; 16]).unwrap();
+
+        assert!(p.output().exited);
+        assert_eq!(p.reg_pc, 0x200);
+        assert_eq!(p.reg_v[0], 0);
+    }
+
+    #[test]
+    fn op_fx30() {
+        let mut p = Processor::new(Quirks::SUPER_CHIP);
+        p.reg_v[3] = 9;
+        p.op_fx30(3);
+
+        assert_eq!(p.reg_i, BIG_FONT_ADDR + 90);
+    }
+
+    #[test]
+    fn extended_memory() {
+        let mut p = Processor::new(Quirks::XO_CHIP);
+        assert_eq!(p.ram.len(), XO_CHIP_RAM);
+
+        p.reg_i = 0xfffe;
+        p.reg_v[0] = 0xaa;
+        p.op_fx55(0).unwrap();
+        assert_eq!(p.ram[0xfffe], 0xaa);
+    }
+
+    #[test]
+    fn op_f000() {
+        let mut p = Processor::new(Quirks::XO_CHIP);
+        // LD I, long 0x1234; LD V0, 0xff
+        p.load(&[0xf0, 0x00, 0x12, 0x34, 0x60, 0xff]);
+
+        p.tick([false; 16]).unwrap();
+        assert_eq!(p.reg_i, 0x1234);
+        assert_eq!(p.reg_pc, 0x204);
+
+        p.tick([false; 16]).unwrap();
+        assert_eq!(p.reg_v[0], 0xff);
+    }
+
+    #[test]
+    fn skip_over_f000() {
+        let mut p = Processor::new(Quirks::XO_CHIP);
+        // SE V0, 0; LD I, long 0x1234; LD V1, 0xff
+        p.load(&[0x30, 0x00, 0xf0, 0x00, 0x12, 0x34, 0x61, 0xff]);
+
+        p.tick([false; 16]).unwrap();
+        assert_eq!(p.reg_pc, 0x206);
+    }
+
+    #[test]
+    fn op_fn01() {
+        let mut p = Processor::new(Quirks::XO_CHIP);
+        p.op_fn01(3);
+        assert_eq!(p.planes, 3);
+        p.op_fn01(0);
+        assert_eq!(p.planes, 0);
+    }
+
+    #[test]
+    fn op_5xy2() {
+        let mut p = Processor::new(Quirks::XO_CHIP);
+        p.reg_v = [0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15];
+        p.reg_i = 0x300;
+
+        p.op_5xy2(2, 4).unwrap();
+        assert_eq!(p.ram[0x300..0x304], [2,3,4,0]);
+
+        p.op_5xy2(7, 5).unwrap();
+        assert_eq!(p.ram[0x300..0x304], [7,6,5,0]);
+        assert_eq!(p.reg_i, 0x300);
+    }
+
+    #[test]
+    fn op_5xy3() {
+        let mut p = Processor::new(Quirks::XO_CHIP);
+        p.load(&[10, 11, 12]);
+        p.reg_i = 0x200;
+
+        p.op_5xy3(1, 3).unwrap();
+        assert_eq!(p.reg_v[1..4], [10, 11, 12]);
+
+        p.op_5xy3(9, 7).unwrap();
+        assert_eq!(p.reg_v[7..10], [12, 11, 10]);
+        assert_eq!(p.reg_i, 0x200);
+    }
+
+    #[test]
+    fn draw_planes() {
+        let mut p = Processor::new(Quirks::XO_CHIP);
+        // plane 1 data followed by plane 2 data
+        p.load(&[0b1100_0000, 0b1010_0000]);
+        p.reg_i = 0x200;
+
+        p.op_fn01(3);
+        p.op_dxyn(0, 0, 1).unwrap();
+        assert_eq!(p.vram[0][0..4], [3, 1, 2, 0]);
+        assert_eq!(p.reg_v[0xf], 0);
+
+        // with only plane 2 selected the first sprite is used for it
+        p.op_fn01(2);
+        p.op_dxyn(0, 0, 1).unwrap();
+        assert_eq!(p.vram[0][0..4], [1, 3, 2, 0]);
+        assert_eq!(p.reg_v[0xf], 1);
+    }
+
+    #[test]
+    fn clear_and_scroll_selected_planes() {
+        let mut p = Processor::new(Quirks::XO_CHIP);
+        p.vram[4][4] = 3;
+
+        p.op_fn01(2);
+        p.op_00dn(2);
+        assert_eq!(p.vram[4][4], 1);
+        assert_eq!(p.vram[2][4], 2);
+
+        p.op_00e0();
+        assert_eq!(p.vram[2][4], 0);
+        assert_eq!(p.vram[4][4], 1);
+    }
+
+    #[test]
+    fn op_fx75() {
+        let mut p = Processor::new(Quirks::SUPER_CHIP);
+        p.reg_v = [1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16];
+        p.op_fx75(0x7);
+
+        assert_eq!(p.rpl_flags(), [1,2,3,4,5,6,7,8,0,0,0,0,0,0,0,0]);
+    }
+
+    #[test]
+    fn op_fx85() {
+        let mut p = Processor::new(Quirks::SUPER_CHIP);
+        p.set_rpl_flags([9,8,7,6,5,4,3,2,1,0,1,2,3,4,5,6]);
+        p.op_fx85(0x2);
+
+        assert_eq!(p.reg_v[0..4], [9,8,7,0]);
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        let rom = [0x60, 0x05, 0xf0, 0x15, 0x12, 0x04];
+        let mut p = Processor::new(Quirks::XO_CHIP);
+        p.load(&rom);
+        p.tick([false; 16]).unwrap();
+        p.tick([false; 16]).unwrap();
+        p.op_2nnn(0x300).unwrap();
+        p.op_00ff();
+        p.vram[10][100] = 3;
+        p.reg_i = 0xabcd;
+        p.rpl[3] = 7;
+        p.audio_buffer = Some([0xf0; PATTERN_SIZE]);
+        p.key_wait = KeyWait::Release(4, 0xa);
+
+        let state = p.snapshot();
+
+        let mut q = Processor::new(Quirks::XO_CHIP);
+        q.load(&rom);
+        q.set_rpl_flags(p.rpl_flags());
+        q.restore(&state).unwrap();
+
+        assert_eq!(q.ram, p.ram);
+        assert_eq!(q.vram, p.vram);
+        assert!(q.hires);
+        assert_eq!(q.reg_v, p.reg_v);
+        assert_eq!(q.stack, p.stack);
+        assert_eq!(q.reg_sp, 1);
+        assert_eq!(q.reg_i, 0xabcd);
+        assert_eq!(q.reg_pc, p.reg_pc);
+        assert_eq!(q.timers.delay, 5);
+        assert_eq!(q.rpl, p.rpl);
+        assert_eq!(q.audio_buffer, p.audio_buffer);
+        assert!(matches!(q.key_wait, KeyWait::Release(4, 0xa)));
+        assert!(q.vram_changed);
+        assert_eq!(q.snapshot(), state);
+    }
+
+    #[test]
+    fn restore_errors() {
+        let mut p = Processor::new(Quirks::default());
+        p.load(&[0x12, 0x00]);
+        let state = p.snapshot();
+
+        let mut other = Processor::new(Quirks::default());
+        other.load(&[0x12, 0x02]);
+        assert_eq!(other.restore(&state), Err(SnapshotError::RomMismatch));
+
+        let mut xo = Processor::new(Quirks::XO_CHIP);
+        xo.load(&[0x12, 0x00]);
+        assert_eq!(xo.restore(&state), Err(SnapshotError::MemorySizeMismatch));
+
+        let mut newer = state.clone();
+        newer[5] = 2;
+        assert_eq!(p.restore(&newer), Err(SnapshotError::UnsupportedVersion(2)));
+
+        assert_eq!(p.restore(&state[..state.len() - 1]), Err(SnapshotError::InvalidFormat));
+        assert_eq!(p.restore(b"nope"), Err(SnapshotError::InvalidFormat));
+    }
+
+    #[test]
+    fn set_quirks() {
+        let mut p = Processor::new(Quirks::COSMAC_VIP);
+        p.set_quirks(Quirks::XO_CHIP);
+        assert!(!p.quirks().clip_sprites);
+        assert!(!p.quirks().extended_memory);
+        assert_eq!(p.memory().len(), CHIP8_RAM);
+    }
+
+}
@@ -0,0 +1,142 @@
+use crate::processor::{CHIP8_RAM, XO_CHIP_RAM};
+
+/*
+ * Behaviour that differs between CHIP-8 interpreters. Each ambiguous
+ * opcode checks the relevant flag, so a ROM can be run the way the
+ * interpreter it was written for would have run it.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    // 8xy6 / 8xyE shift Vy into Vx instead of shifting Vx in place
+    pub shift_uses_vy: bool,
+    // Fx55 / Fx65 leave I pointing past the last register
+    pub load_store_increments_i: bool,
+    // Fx1E sets VF when I overflows past 0xFFF
+    pub add_i_sets_vf: bool,
+    // Dxyn clips sprites at the screen edge instead of wrapping them
+    pub clip_sprites: bool,
+    // 8xy1 / 8xy2 / 8xy3 reset VF to 0
+    pub logic_resets_vf: bool,
+    // Bnnn jumps to xnn + Vx instead of nnn + V0
+    pub jump_uses_vx: bool,
+    // 64 KiB of memory as on XO-CHIP instead of 4 KiB
+    pub extended_memory: bool
+}
+
+impl Quirks {
+
+    pub const COSMAC_VIP: Quirks = Quirks {
+        shift_uses_vy: true,
+        load_store_increments_i: true,
+        add_i_sets_vf: false,
+        clip_sprites: true,
+        logic_resets_vf: true,
+        jump_uses_vx: false,
+        extended_memory: false
+    };
+
+    pub const CHIP48: Quirks = Quirks {
+        shift_uses_vy: false,
+        load_store_increments_i: false,
+        add_i_sets_vf: false,
+        clip_sprites: true,
+        logic_resets_vf: false,
+        jump_uses_vx: true,
+        extended_memory: false
+    };
+
+    pub const SUPER_CHIP: Quirks = Quirks {
+        shift_uses_vy: false,
+        load_store_increments_i: false,
+        add_i_sets_vf: false,
+        clip_sprites: true,
+        logic_resets_vf: false,
+        jump_uses_vx: true,
+        extended_memory: false
+    };
+
+    pub const XO_CHIP: Quirks = Quirks {
+        shift_uses_vy: true,
+        load_store_increments_i: true,
+        add_i_sets_vf: false,
+        clip_sprites: false,
+        logic_resets_vf: false,
+        jump_uses_vx: false,
+        extended_memory: true
+    };
+
+    pub const PRESETS: [(&'static str, Quirks); 4] = [
+        ("vip", Quirks::COSMAC_VIP),
+        ("chip48", Quirks::CHIP48),
+        ("schip", Quirks::SUPER_CHIP),
+        ("xochip", Quirks::XO_CHIP)
+    ];
+
+    /*
+     * Size of the address space in bytes.
+     */
+    pub fn memory_size(&self) -> usize {
+        if self.extended_memory { XO_CHIP_RAM } else { CHIP8_RAM }
+    }
+
+    /*
+     * Look up a preset by its short name, e.g. "schip".
+     */
+    pub fn preset(name: &str) -> Option<Quirks> {
+        Quirks::PRESETS
+            .iter()
+            .find(|(preset, _)| preset.eq_ignore_ascii_case(name))
+            .map(|&(_, quirks)| quirks)
+    }
+
+    // the quirks that can change while a program runs, the memory size can't
+    pub const FLAGS: [&'static str; 6] = [
+        "shift_uses_vy",
+        "load_store_increments_i",
+        "add_i_sets_vf",
+        "clip_sprites",
+        "logic_resets_vf",
+        "jump_uses_vx"
+    ];
+
+    fn flag_mut(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "shift_uses_vy" => Some(&mut self.shift_uses_vy),
+            "load_store_increments_i" => Some(&mut self.load_store_increments_i),
+            "add_i_sets_vf" => Some(&mut self.add_i_sets_vf),
+            "clip_sprites" => Some(&mut self.clip_sprites),
+            "logic_resets_vf" => Some(&mut self.logic_resets_vf),
+            "jump_uses_vx" => Some(&mut self.jump_uses_vx),
+            "extended_memory" => Some(&mut self.extended_memory),
+            _ => None,
+        }
+    }
+
+    /*
+     * A single quirk by its field name, None when there is no such quirk.
+     */
+    pub fn get(&self, name: &str) -> Option<bool> {
+        let mut quirks = *self;
+        quirks.flag_mut(name).map(|flag| *flag)
+    }
+
+    /*
+     * Set a single quirk by its field name, e.g. "clip_sprites". Returns
+     * false when there is no such quirk.
+     */
+    pub fn set(&mut self, name: &str, enabled: bool) -> bool {
+        match self.flag_mut(name) {
+            Some(flag) => {
+                *flag = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks::COSMAC_VIP
+    }
+}
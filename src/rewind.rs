@@ -0,0 +1,266 @@
+use std::collections::VecDeque;
+
+pub const DEFAULT_REWIND_SECONDS: usize = 10;
+pub const DEFAULT_MEMORY_BUDGET: usize = 16 * 1024 * 1024;
+
+/*
+ * A ring buffer of processor snapshots for stepping a game backwards.
+ *
+ * Only the newest snapshot is kept in full. Every older frame is stored
+ * as the XOR of itself and the frame after it, run-length encoded, so
+ * frames that only touch a few bytes of ram cost a few bytes. Stepping
+ * back XORs the newest snapshot with the most recent delta.
+ */
+pub struct Rewind {
+    max_frames: usize,
+    memory_budget: usize,
+    current: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>,
+    // bytes taken by the deltas
+    delta_bytes: usize
+}
+
+impl Rewind {
+
+    pub fn new(max_frames: usize, memory_budget: usize) -> Self {
+        Rewind {
+            max_frames,
+            memory_budget,
+            current: None,
+            deltas: VecDeque::new(),
+            delta_bytes: 0
+        }
+    }
+
+    /*
+     * Record the state of a new frame.
+     */
+    pub fn push(&mut self, snapshot: Vec<u8>) {
+        if let Some(current) = self.current.take() {
+            if current.len() == snapshot.len() {
+                let delta = encode(&xor(&current, &snapshot));
+                self.delta_bytes += delta.len();
+                self.deltas.push_back(delta);
+            } else {
+                self.clear();
+            }
+        }
+        self.current = Some(snapshot);
+
+        while self.deltas.len() > self.max_frames || self.memory_used() > self.memory_budget {
+            match self.deltas.pop_front() {
+                Some(delta) => self.delta_bytes -= delta.len(),
+                None => break,
+            }
+        }
+    }
+
+    /*
+     * Step back one frame, returning the snapshot to restore.
+     */
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let delta = self.deltas.pop_back()?;
+        self.delta_bytes -= delta.len();
+
+        let current = self.current.as_ref()?;
+        let previous = xor(current, &decode(&delta, current.len()));
+        self.current = Some(previous.clone());
+
+        Some(previous)
+    }
+
+    /*
+     * Number of frames that can be stepped back.
+     */
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    /*
+     * Bytes taken by the deltas and the newest full snapshot. A budget
+     * smaller than one snapshot leaves nothing to step back to.
+     */
+    pub fn memory_used(&self) -> usize {
+        self.delta_bytes + self.current.as_ref().map_or(0, Vec::len)
+    }
+
+    pub fn clear(&mut self) {
+        self.current = None;
+        self.deltas.clear();
+        self.delta_bytes = 0;
+    }
+}
+
+impl Default for Rewind {
+    fn default() -> Self {
+        Rewind::new(DEFAULT_REWIND_SECONDS * 60, DEFAULT_MEMORY_BUDGET)
+    }
+}
+
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter().zip(b).map(|(x, y)| x ^ y).collect()
+}
+
+/*
+ * Run-length encode the zero runs of a delta as pairs of
+ * (zero count, literal count) varints, each followed by the literals.
+ */
+fn encode(delta: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+
+    while i < delta.len() {
+        let zeros = delta[i..].iter().take_while(|&&b| b == 0).count();
+        i += zeros;
+        let literals = delta[i..].iter().take_while(|&&b| b != 0).count();
+
+        write_varint(&mut out, zeros);
+        write_varint(&mut out, literals);
+        out.extend_from_slice(&delta[i..i + literals]);
+        i += literals;
+    }
+
+    out
+}
+
+fn decode(data: &[u8], len: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(len);
+    let mut i = 0;
+
+    while i < data.len() {
+        let zeros = read_varint(data, &mut i);
+        let literals = read_varint(data, &mut i);
+
+        out.resize(out.len() + zeros, 0);
+        out.extend_from_slice(&data[i..i + literals]);
+        i += literals;
+    }
+
+    out.resize(len, 0);
+    out
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], i: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+
+    loop {
+        let byte = data[*i];
+        *i += 1;
+        value |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn frame(n: u8) -> Vec<u8> {
+        let mut data = vec![0; 4096];
+        data[10] = n;
+        data[2000] = n.wrapping_mul(3);
+        data[4095] = 0xff;
+        data
+    }
+
+    #[test]
+    fn encode_decode() {
+        let data = [0, 0, 0, 1, 2, 0, 3, 0, 0];
+        assert_eq!(decode(&encode(&data), data.len()), data);
+
+        let mut long = vec![0; 1000];
+        long[999] = 7;
+        let encoded = encode(&long);
+        assert_eq!(encoded.len(), 4);
+        assert_eq!(decode(&encoded, long.len()), long);
+    }
+
+    #[test]
+    fn step_back() {
+        let mut r = Rewind::default();
+        for n in 0..10 {
+            r.push(frame(n));
+        }
+        assert_eq!(r.len(), 9);
+
+        for n in (0..9).rev() {
+            assert_eq!(r.pop(), Some(frame(n)));
+        }
+        assert_eq!(r.pop(), None);
+        assert!(r.is_empty());
+    }
+
+    #[test]
+    fn resume_after_rewind() {
+        let mut r = Rewind::default();
+        for n in 0..5 {
+            r.push(frame(n));
+        }
+        r.pop();
+        r.pop();
+        r.push(frame(42));
+
+        assert_eq!(r.pop(), Some(frame(2)));
+        assert_eq!(r.pop(), Some(frame(1)));
+    }
+
+    #[test]
+    fn deltas_are_compact() {
+        let mut r = Rewind::default();
+        for n in 0..60 {
+            r.push(frame(n));
+        }
+
+        assert!(r.memory_used() < 4096 + 60 * 16);
+    }
+
+    #[test]
+    fn limits() {
+        let mut r = Rewind::new(3, DEFAULT_MEMORY_BUDGET);
+        for n in 0..10 {
+            r.push(frame(n));
+        }
+        assert_eq!(r.len(), 3);
+        assert_eq!(r.pop(), Some(frame(8)));
+
+        // the newest snapshot counts against the budget too
+        let mut r = Rewind::new(100, 4096 + 20);
+        for n in 1..10 {
+            r.push(frame(n));
+        }
+        assert!(r.memory_used() <= 4096 + 20);
+        assert!(r.len() < 8);
+
+        let mut r = Rewind::new(100, 4000);
+        r.push(frame(1));
+        r.push(frame(2));
+        assert!(r.is_empty());
+    }
+
+    #[test]
+    fn size_change_clears() {
+        let mut r = Rewind::default();
+        r.push(frame(1));
+        r.push(frame(2));
+        r.push(vec![0; 10]);
+
+        assert!(r.is_empty());
+    }
+}
@@ -0,0 +1,307 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+
+use crate::quirks::Quirks;
+use crate::scheduler::Speed;
+use crate::settings::{parse_palette, Keymap, Palette};
+use crate::storage::Storage;
+
+const BUNDLED: &str = include_str!("../data/romdb.ini");
+
+// user additions and overrides, in the storage directory
+pub const USER_FILE: &str = "romdb.ini";
+
+/*
+ * What is known about a ROM. Unset fields leave the choice to the
+ * command line or the defaults.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct RomInfo {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub platform: Option<String>,
+    // quirk name and value, applied on top of the platform preset
+    pub quirks: Vec<(String, bool)>,
+    pub speed: Option<Speed>,
+    // keypad key and host key name
+    pub keys: Vec<(String, String)>,
+    pub palette: Option<Palette>
+}
+
+impl RomInfo {
+
+    /*
+     * The platform preset with the quirk changes applied, None when
+     * the entry says nothing about quirks.
+     */
+    pub fn quirks(&self) -> Option<Quirks> {
+        if self.platform.is_none() && self.quirks.is_empty() {
+            return None;
+        }
+
+        let mut quirks = self.platform.as_deref().and_then(Quirks::preset).unwrap_or_default();
+        for (name, enabled) in &self.quirks {
+            quirks.set(name, *enabled);
+        }
+        Some(quirks)
+    }
+
+    pub fn apply_keys(&self, keymap: &mut Keymap) {
+        for (key, name) in &self.keys {
+            // checked when the entry was parsed
+            let _ = keymap.set(key, name);
+        }
+    }
+
+    /*
+     * Take every field `other` sets.
+     */
+    fn merge(&mut self, other: RomInfo) {
+        self.title = other.title.or(self.title.take());
+        self.author = other.author.or(self.author.take());
+        self.platform = other.platform.or(self.platform.take());
+        self.speed = other.speed.or(self.speed);
+        self.palette = other.palette.or(self.palette);
+        if !other.quirks.is_empty() {
+            self.quirks = other.quirks;
+        }
+        if !other.keys.is_empty() {
+            self.keys = other.keys;
+        }
+    }
+
+    fn set(&mut self, field: &str, value: &str) -> Result<(), String> {
+        let list = || value.split(',').map(str::trim).filter(|item| !item.is_empty());
+
+        match field {
+            "title" => self.title = Some(String::from(value)),
+            "author" => self.author = Some(String::from(value)),
+            "platform" => {
+                Quirks::preset(value).ok_or_else(|| format!("unknown platform '{}'", value))?;
+                self.platform = Some(value.to_ascii_lowercase());
+            }
+            "quirks" => {
+                self.quirks = list()
+                    .map(|item| {
+                        let (name, enabled) = match item.strip_prefix('!') {
+                            Some(name) => (name, false),
+                            None => (item, true),
+                        };
+                        match Quirks::default().set(name, enabled) {
+                            true => Ok((String::from(name), enabled)),
+                            false => Err(format!("unknown quirk '{}'", name)),
+                        }
+                    })
+                    .collect::<Result<_, _>>()?;
+            }
+            "cpu-hz" => self.speed = Some(value.parse()?),
+            "keys" => {
+                self.keys = list()
+                    .map(|item| {
+                        let (key, name) = item.split_once(':').ok_or_else(|| format!("expected KEY:NAME, got '{}'", item))?;
+                        Keymap::default().set(key, name)?;
+                        Ok((String::from(key.trim()), String::from(name.trim())))
+                    })
+                    .collect::<Result<_, String>>()?;
+            }
+            "palette" => self.palette = Some(parse_palette(value)?),
+            _ => return Err(format!("unknown field '{}'", field)),
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for RomInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.title.as_deref().unwrap_or("untitled"))?;
+        if let Some(author) = &self.author {
+            write!(f, " by {}", author)?;
+        }
+        Ok(())
+    }
+}
+
+/*
+ * Per-game settings keyed by the SHA-1 of the ROM, so a known game
+ * runs with the right quirks, speed and controls without any options.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct RomDatabase {
+    entries: HashMap<String, RomInfo>
+}
+
+impl RomDatabase {
+
+    /*
+     * An ini style file, a `[sha1]` line starts the entry for a ROM and
+     * is followed by `field = value` lines. Lines starting with '#' are
+     * comments.
+     */
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut database = RomDatabase::default();
+        let mut current: Option<(String, RomInfo)> = None;
+
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |message: String| format!("line {}: {}", n + 1, message);
+
+            if let Some(hash) = line.strip_prefix('[').and_then(|line| line.strip_suffix(']')) {
+                let hash = hash.trim().to_ascii_lowercase();
+                if hash.len() != 40 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(error(format!("'{}' is not a SHA-1", hash)));
+                }
+                if let Some((hash, info)) = current.replace((hash, RomInfo::default())) {
+                    database.insert(hash, info);
+                }
+                continue;
+            }
+
+            let (field, value) = line.split_once('=').ok_or_else(|| error(String::from("expected field = value")))?;
+            let (_, info) = current.as_mut().ok_or_else(|| error(String::from("field outside of a [sha1] entry")))?;
+            info.set(field.trim(), value.trim()).map_err(error)?;
+        }
+
+        if let Some((hash, info)) = current {
+            database.insert(hash, info);
+        }
+        Ok(database)
+    }
+
+    fn insert(&mut self, hash: String, info: RomInfo) {
+        self.entries.entry(hash).or_default().merge(info);
+    }
+
+    /*
+     * Add the entries of `other`, its fields win where both set them.
+     */
+    pub fn merge(&mut self, other: RomDatabase) {
+        for (hash, info) in other.entries {
+            self.insert(hash, info);
+        }
+    }
+
+    pub fn bundled() -> Self {
+        RomDatabase::parse(BUNDLED).expect("the bundled rom database is valid")
+    }
+
+    /*
+     * The bundled database with the user file merged in. A missing user
+     * file is fine, a broken one is an error.
+     */
+    pub fn load(storage: &Storage) -> Result<Self, String> {
+        let mut database = RomDatabase::bundled();
+        let path = storage.file(USER_FILE);
+
+        match fs::read_to_string(&path) {
+            Ok(text) => {
+                let user = RomDatabase::parse(&text).map_err(|err| format!("{}: {}", path.display(), err))?;
+                database.merge(user);
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(format!("could not read {}: {}", path.display(), err)),
+        }
+        Ok(database)
+    }
+
+    pub fn get(&self, hash: &str) -> Option<&RomInfo> {
+        self.entries.get(&hash.to_ascii_lowercase())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    const HASH: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    #[test]
+    fn parse() {
+        let text = format!(
+            "# comment\n[{}]\ntitle = Some Game\nauthor = Someone\nplatform = SCHIP\nquirks = !clip_sprites, shift_uses_vy\ncpu-hz = 1000\nkeys = 5:Up, 8 : Down\npalette = #000000, ffffff\n",
+            HASH.to_ascii_uppercase()
+        );
+        let database = RomDatabase::parse(&text).unwrap();
+        let info = database.get(HASH).unwrap();
+
+        assert_eq!(info.to_string(), "Some Game by Someone");
+        assert_eq!(info.speed, Some(Speed::Hz(1000)));
+        assert_eq!(info.palette.unwrap()[1], (0xff, 0xff, 0xff));
+
+        let quirks = info.quirks().unwrap();
+        assert!(!quirks.clip_sprites);
+        assert!(quirks.shift_uses_vy);
+        assert!(quirks.jump_uses_vx);
+
+        let mut keymap = Keymap::default();
+        info.apply_keys(&mut keymap);
+        assert_eq!(keymap.keys[5], "Up");
+        assert_eq!(keymap.keys[8], "Down");
+        assert_eq!(keymap.keys[0], "1");
+
+        assert!(database.get("ffffffffffffffffffffffffffffffffffffffff").is_none());
+        assert_eq!(RomDatabase::parse(&format!("[{}]\ntitle = x", HASH)).unwrap().get(HASH).unwrap().quirks(), None);
+    }
+
+    #[test]
+    fn bundled() {
+        let database = RomDatabase::bundled();
+        for info in database.entries.values() {
+            assert!(info.platform.as_deref().is_none_or(|platform| Quirks::preset(platform).is_some()), "{}", info);
+            assert!(info.quirks.iter().all(|(name, _)| Quirks::default().get(name).is_some()), "{}", info);
+        }
+
+        // the commented out example is a valid entry
+        let example: Vec<&str> = BUNDLED
+            .lines()
+            .skip_while(|line| !line.starts_with("# ["))
+            .map(|line| line.trim_start_matches('#').trim())
+            .collect();
+        let example = RomDatabase::parse(&example.join("\n")).unwrap();
+        let info = example.get("0123456789abcdef0123456789abcdef01234567").unwrap();
+        assert!(!info.quirks().unwrap().jump_uses_vx);
+        assert_eq!(info.speed, Some(Speed::Hz(1000)));
+    }
+
+    #[test]
+    fn merge() {
+        let mut database = RomDatabase::parse(&format!("[{}]\ntitle = Game\nplatform = schip\ncpu-hz = 1000", HASH)).unwrap();
+        let user = RomDatabase::parse(&format!("[{}]\ncpu-hz = unlimited\n[{}]\ntitle = Other", HASH, "f".repeat(40))).unwrap();
+        database.merge(user);
+
+        let info = database.get(HASH).unwrap();
+        assert_eq!(info.title.as_deref(), Some("Game"));
+        assert_eq!(info.platform.as_deref(), Some("schip"));
+        assert_eq!(info.speed, Some(Speed::Unlimited));
+        assert_eq!(database.len(), 2);
+    }
+
+    #[test]
+    fn errors() {
+        let error = |text: &str| RomDatabase::parse(&format!("[{}]\n{}", HASH, text)).err().unwrap();
+
+        assert_eq!(RomDatabase::parse("[1234]").err().unwrap(), "line 1: '1234' is not a SHA-1");
+        assert_eq!(RomDatabase::parse("title = x").err().unwrap(), "line 1: field outside of a [sha1] entry");
+        assert_eq!(error("title"), "line 2: expected field = value");
+        assert_eq!(error("rating = 5"), "line 2: unknown field 'rating'");
+        assert_eq!(error("platform = nes"), "line 2: unknown platform 'nes'");
+        assert_eq!(error("quirks = fast"), "line 2: unknown quirk 'fast'");
+        assert_eq!(error("keys = 5"), "line 2: expected KEY:NAME, got '5'");
+        assert_eq!(error("keys = 10:X"), "line 2: '10' is not a keypad key 0-F");
+        assert!(error("cpu-hz = fast").starts_with("line 2: invalid cpu speed"));
+        assert!(error("palette = 000000").starts_with("line 2: expected 2 or 4"));
+    }
+}
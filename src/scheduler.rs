@@ -0,0 +1,230 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::error::ExecutionError;
+use crate::processor::Processor;
+use crate::timers::TIMER_FREQUENCY;
+
+// a frame is one timer period
+pub const FRAME_RATE: u32 = TIMER_FREQUENCY;
+pub const DEFAULT_CPU_HZ: u32 = 700;
+
+// never try to catch up more than this many frames after a stall
+const MAX_FRAMES_BEHIND: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Speed {
+    Hz(u32),
+    Unlimited
+}
+
+impl Default for Speed {
+    fn default() -> Self {
+        Speed::Hz(DEFAULT_CPU_HZ)
+    }
+}
+
+impl FromStr for Speed {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("unlimited") {
+            return Ok(Speed::Unlimited);
+        }
+
+        match s.parse::<u32>() {
+            Ok(hz) if hz > 0 => Ok(Speed::Hz(hz)),
+            _ => Err(format!("invalid cpu speed '{}', expected a frequency in Hz or 'unlimited'", s)),
+        }
+    }
+}
+
+impl fmt::Display for Speed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Speed::Hz(hz) => write!(f, "{} Hz", hz),
+            Speed::Unlimited => write!(f, "unlimited"),
+        }
+    }
+}
+
+/*
+ * Runs the processor in 60 Hz frames. Each frame executes a fixed batch of
+ * instructions derived from the cpu speed and then steps the timers once,
+ * so the game runs at the same pace no matter how often the host loop spins.
+ */
+pub struct Scheduler {
+    speed: Speed,
+    frame_period: Duration,
+    lag: Duration,
+    cycle_remainder: u32
+}
+
+impl Scheduler {
+
+    pub fn new(speed: Speed) -> Self {
+        Scheduler {
+            speed,
+            frame_period: Duration::from_secs(1) / FRAME_RATE,
+            lag: Duration::ZERO,
+            cycle_remainder: 0
+        }
+    }
+
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    /*
+     * Change the cpu speed from the next frame on.
+     */
+    pub fn set_speed(&mut self, speed: Speed) {
+        self.speed = speed;
+        self.cycle_remainder = 0;
+    }
+
+    /*
+     * Account for elapsed wall-clock time and return the number of
+     * frames that are due.
+     */
+    pub fn advance(&mut self, elapsed: Duration) -> u32 {
+        self.lag += elapsed;
+
+        let mut frames = 0;
+        while self.lag >= self.frame_period {
+            self.lag -= self.frame_period;
+            frames += 1;
+        }
+
+        if frames > MAX_FRAMES_BEHIND {
+            frames = MAX_FRAMES_BEHIND;
+        }
+
+        frames
+    }
+
+    /*
+     * Time left until the next frame is due.
+     */
+    pub fn time_until_next_frame(&self) -> Duration {
+        self.frame_period.saturating_sub(self.lag)
+    }
+
+    /*
+     * Number of instructions in the next frame, carrying the remainder so
+     * that e.g. 500 Hz runs 8 or 9 instructions per frame and exactly 500
+     * per second. None when the speed is unlimited.
+     */
+    pub(crate) fn cycles_for_frame(&mut self) -> Option<u32> {
+        match self.speed {
+            Speed::Hz(hz) => {
+                let total = hz + self.cycle_remainder;
+                self.cycle_remainder = total % FRAME_RATE;
+                Some(total / FRAME_RATE)
+            }
+            Speed::Unlimited => None,
+        }
+    }
+
+    pub fn run_frame(&mut self, processor: &mut Processor, keypad: [bool; 16]) -> Result<(), ExecutionError> {
+        self.run_frame_with(processor, keypad, |_| true).map(|_| ())
+    }
+
+    /*
+     * Like run_frame, but `before` is called ahead of every instruction
+     * and can cut the frame short by returning false, e.g. on a breakpoint.
+     * Returns whether the frame ran to completion.
+     */
+    pub fn run_frame_with<F>(&mut self, processor: &mut Processor, keypad: [bool; 16], mut before: F) -> Result<bool, ExecutionError>
+    where
+        F: FnMut(&Processor) -> bool
+    {
+        match self.cycles_for_frame() {
+            Some(cycles) => {
+                for _ in 0..cycles {
+                    if !before(processor) {
+                        return Ok(false);
+                    }
+                    processor.tick(keypad)?;
+                }
+            }
+            None => {
+                // leave some of the frame for rendering and input handling
+                let deadline = Instant::now() + self.frame_period * 3 / 4;
+                while Instant::now() < deadline {
+                    for _ in 0..100 {
+                        if !before(processor) {
+                            return Ok(false);
+                        }
+                        processor.tick(keypad)?;
+                    }
+                }
+            }
+        }
+
+        processor.step_timers();
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::quirks::Quirks;
+
+    #[test]
+    fn cycles_per_second() {
+        let mut s = Scheduler::new(Speed::Hz(500));
+
+        let cycles: Vec<u32> = (0..FRAME_RATE).map(|_| s.cycles_for_frame().unwrap()).collect();
+
+        assert_eq!(cycles.iter().sum::<u32>(), 500);
+        assert!(cycles.iter().all(|&c| c == 8 || c == 9));
+    }
+
+    #[test]
+    fn unlimited_speed() {
+        let mut s = Scheduler::new(Speed::Unlimited);
+        assert_eq!(s.cycles_for_frame(), None);
+    }
+
+    #[test]
+    fn advance_frames() {
+        let mut s = Scheduler::new(Speed::default());
+
+        assert_eq!(s.advance(Duration::from_millis(10)), 0);
+        assert_eq!(s.advance(Duration::from_millis(10)), 1);
+        assert_eq!(s.advance(Duration::from_millis(30)), 2);
+        assert!(s.time_until_next_frame() < Duration::from_millis(17));
+        assert_eq!(s.advance(Duration::from_secs(1)), MAX_FRAMES_BEHIND);
+    }
+
+    #[test]
+    fn run_frame() {
+        // ten times ADD V0, 1 followed by an invalid opcode
+        let mut rom = [0x70, 0x01].repeat(10);
+        rom.extend([0xff, 0xff]);
+
+        let mut p = Processor::new(Quirks::default());
+        p.load(&rom);
+        let mut s = Scheduler::new(Speed::Hz(600));
+        assert!(s.run_frame(&mut p, [false; 16]).is_ok());
+
+        let mut p = Processor::new(Quirks::default());
+        p.load(&rom);
+        let mut s = Scheduler::new(Speed::Hz(660));
+        let err = s.run_frame(&mut p, [false; 16]).err().unwrap();
+        assert_eq!(err.pc, 0x214);
+    }
+
+    #[test]
+    fn parse_speed() {
+        assert_eq!("1000".parse::<Speed>(), Ok(Speed::Hz(1000)));
+        assert_eq!("unlimited".parse::<Speed>(), Ok(Speed::Unlimited));
+        assert!("0".parse::<Speed>().is_err());
+        assert!("fast".parse::<Speed>().is_err());
+        assert_eq!(Speed::Hz(700).to_string(), "700 Hz");
+    }
+}
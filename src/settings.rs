@@ -0,0 +1,269 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::quirks::Quirks;
+use crate::romdb::RomInfo;
+use crate::scheduler::Speed;
+
+// colours for pixel values 0-3, one bit per XO-CHIP bitplane
+pub type Palette = [(u8, u8, u8); 4];
+
+pub const DEFAULT_PALETTE: Palette = [
+    (0, 0, 0),
+    (0, 255, 0),
+    (255, 102, 0),
+    (255, 204, 0)
+];
+
+// named palettes, the first being the default
+pub const PALETTES: [(&str, Palette); 5] = [
+    ("default", DEFAULT_PALETTE),
+    ("mono", [(0, 0, 0), (255, 255, 255), (170, 170, 170), (85, 85, 85)]),
+    ("amber", [(16, 8, 0), (255, 176, 0), (170, 102, 0), (255, 221, 136)]),
+    ("lcd", [(155, 188, 15), (15, 56, 15), (48, 98, 48), (139, 172, 15)]),
+    ("octo", [(153, 102, 0), (255, 204, 0), (255, 102, 0), (102, 34, 0)])
+];
+
+/*
+ * The name of a palette from PALETTES, None for any other.
+ */
+pub fn palette_name(palette: &Palette) -> Option<&'static str> {
+    PALETTES.iter().find(|(_, named)| named == palette).map(|&(name, _)| name)
+}
+
+pub const DEFAULT_SCALE: u32 = 20;
+
+/*
+ * Parse a palette name, e.g. "amber", or a comma separated list of hex
+ * colours, e.g. "000000,ffffff". Two colours set the background and
+ * foreground, the XO-CHIP plane colours keep their defaults. Four colours
+ * set all of them.
+ */
+pub fn parse_palette(s: &str) -> Result<Palette, String> {
+    if let Some(&(_, palette)) = PALETTES.iter().find(|(name, _)| name.eq_ignore_ascii_case(s.trim())) {
+        return Ok(palette);
+    }
+
+    let colours = s
+        .split(',')
+        .map(|colour| {
+            let hex = colour.trim().trim_start_matches('#');
+            match u32::from_str_radix(hex, 16) {
+                Ok(rgb) if hex.len() == 6 => Ok(((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8)),
+                _ => Err(format!("invalid colour '{}', expected 6 hex digits like ff6600", colour.trim())),
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut palette = DEFAULT_PALETTE;
+    match colours.len() {
+        2 | 4 => palette[..colours.len()].copy_from_slice(&colours),
+        n => return Err(format!("expected 2 or 4 colours, got {}", n)),
+    }
+    Ok(palette)
+}
+
+/*
+ * Host key names for the 16 keypad keys, as understood by SDL, e.g.
+ * "Q", "1" or "Space". The default maps the left side of a QWERTY
+ * keyboard in rows of four.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Keymap {
+    pub keys: [String; 16]
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        let keys = [
+            "1", "2", "3", "4",
+            "Q", "W", "E", "R",
+            "A", "S", "D", "F",
+            "Z", "X", "C", "V"
+        ];
+        Keymap { keys: keys.map(String::from) }
+    }
+}
+
+impl Keymap {
+
+    /*
+     * One `KEY = NAME` pair per line, KEY being the keypad key as a hex
+     * digit. '#' starts a comment and unlisted keys keep their default.
+     */
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut keymap = Keymap::default();
+
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            line.split_once('=')
+                .ok_or_else(|| String::from("expected KEY = NAME"))
+                .and_then(|(key, name)| keymap.set(key, name))
+                .map_err(|err| format!("line {}: {}", n + 1, err))?;
+        }
+
+        Ok(keymap)
+    }
+
+    /*
+     * Map keypad key `key`, a hex digit, to the host key `name`.
+     */
+    pub fn set(&mut self, key: &str, name: &str) -> Result<(), String> {
+        let key = u8::from_str_radix(key.trim(), 16)
+            .ok()
+            .filter(|&key| key < 16)
+            .ok_or_else(|| format!("'{}' is not a keypad key 0-F", key.trim()))?;
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(String::from("missing key name"));
+        }
+
+        self.keys[key as usize] = String::from(name);
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|err| format!("could not read {}: {}", path.display(), err))?;
+        Keymap::parse(&text).map_err(|err| format!("{}: {}", path.display(), err))
+    }
+}
+
+/*
+ * What the command line sets for every ROM. Anything left unset comes
+ * from the ROM database, or the defaults when it doesn't know the ROM.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Overrides {
+    pub quirks: Option<Quirks>,
+    pub speed: Option<Speed>,
+    pub palette: Option<Palette>,
+    pub keymap: Option<Keymap>,
+    pub seed: Option<u64>
+}
+
+impl Overrides {
+
+    pub fn quirks(&self, info: Option<&RomInfo>) -> Quirks {
+        self.quirks.or(info.and_then(RomInfo::quirks)).unwrap_or_default()
+    }
+
+    pub fn speed(&self, info: Option<&RomInfo>) -> Speed {
+        self.speed.or(info.and_then(|info| info.speed)).unwrap_or_default()
+    }
+
+    pub fn palette(&self, info: Option<&RomInfo>) -> Palette {
+        self.palette.or(info.and_then(|info| info.palette)).unwrap_or(DEFAULT_PALETTE)
+    }
+
+    /*
+     * A keymap file replaces the database's key layout as a whole.
+     */
+    pub fn keymap(&self, info: Option<&RomInfo>) -> Keymap {
+        if let Some(keymap) = &self.keymap {
+            return keymap.clone();
+        }
+
+        let mut keymap = Keymap::default();
+        if let Some(info) = info {
+            info.apply_keys(&mut keymap);
+        }
+        keymap
+    }
+}
+
+/*
+ * How the window frontend presents a game.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WindowSettings {
+    pub scale: u32,
+    pub palette: Palette,
+    pub muted: bool,
+    pub keymap: Keymap,
+    // where the menu's rom browser starts
+    pub rom_dir: PathBuf,
+    // for roms opened from the menu
+    pub overrides: Overrides
+}
+
+impl Default for WindowSettings {
+    fn default() -> Self {
+        WindowSettings {
+            scale: DEFAULT_SCALE,
+            palette: DEFAULT_PALETTE,
+            muted: false,
+            keymap: Keymap::default(),
+            rom_dir: PathBuf::from("."),
+            overrides: Overrides::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn palette() {
+        let palette = parse_palette("#101010, ffffff").unwrap();
+        assert_eq!(palette[0], (0x10, 0x10, 0x10));
+        assert_eq!(palette[1], (0xff, 0xff, 0xff));
+        assert_eq!(palette[2..], DEFAULT_PALETTE[2..]);
+
+        assert_eq!(parse_palette("000000,ff0000,00ff00,0000ff").unwrap()[3], (0, 0, 0xff));
+        assert!(parse_palette("000000").is_err());
+        assert!(parse_palette("000000,fff").is_err());
+        assert!(parse_palette("000000,gggggg").is_err());
+
+        assert_eq!(parse_palette("Amber").unwrap(), PALETTES[2].1);
+        assert_eq!(palette_name(&parse_palette("000000,ffffff,aaaaaa,555555").unwrap()), Some("mono"));
+        assert_eq!(palette_name(&palette), None);
+    }
+
+    #[test]
+    fn keymap() {
+        let keymap = Keymap::parse("# arrows\n5 = Up\n8=Down # comment\n\nf = Space\n").unwrap();
+        assert_eq!(keymap.keys[5], "Up");
+        assert_eq!(keymap.keys[8], "Down");
+        assert_eq!(keymap.keys[0xf], "Space");
+        assert_eq!(keymap.keys[0], "1");
+
+        assert_eq!(Keymap::parse("1 = Q\n10 = X").err().unwrap(), "line 2: '10' is not a keypad key 0-F");
+        assert_eq!(Keymap::parse("1 Q").err().unwrap(), "line 1: expected KEY = NAME");
+        assert!(Keymap::parse("1 =").is_err());
+    }
+
+    #[test]
+    fn overrides() {
+        let info = RomInfo {
+            platform: Some(String::from("schip")),
+            speed: Some(Speed::Hz(1000)),
+            keys: vec![(String::from("5"), String::from("Up"))],
+            ..RomInfo::default()
+        };
+
+        let defaults = Overrides::default();
+        assert_eq!(defaults.quirks(None), Quirks::default());
+        assert_eq!(defaults.speed(None), Speed::default());
+        assert_eq!(defaults.palette(None), DEFAULT_PALETTE);
+        assert_eq!(defaults.keymap(None), Keymap::default());
+        assert_eq!(defaults.quirks(Some(&info)), Quirks::SUPER_CHIP);
+        assert_eq!(defaults.speed(Some(&info)), Speed::Hz(1000));
+        assert_eq!(defaults.keymap(Some(&info)).keys[5], "Up");
+
+        let overrides = Overrides {
+            quirks: Some(Quirks::XO_CHIP),
+            keymap: Some(Keymap::default()),
+            ..Overrides::default()
+        };
+        assert_eq!(overrides.quirks(Some(&info)), Quirks::XO_CHIP);
+        assert_eq!(overrides.speed(Some(&info)), Speed::Hz(1000));
+        assert_eq!(overrides.keymap(Some(&info)), Keymap::default());
+    }
+}
@@ -0,0 +1,62 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/*
+ * Per-ROM files kept between runs, named after the ROM hash so they
+ * follow the game rather than the file name.
+ */
+pub struct Storage {
+    dir: PathBuf
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Storage::new()
+    }
+}
+
+impl Storage {
+
+    /*
+     * $CHIP8_DATA_DIR, or chip8-emu in the XDG data directory.
+     */
+    pub fn new() -> Self {
+        let dir = if let Some(dir) = env::var_os("CHIP8_DATA_DIR") {
+            PathBuf::from(dir)
+        } else if let Some(dir) = env::var_os("XDG_DATA_HOME") {
+            PathBuf::from(dir).join("chip8-emu")
+        } else if let Some(home) = env::var_os("HOME") {
+            PathBuf::from(home).join(".local/share/chip8-emu")
+        } else {
+            PathBuf::from(".chip8-emu")
+        };
+
+        Storage { dir }
+    }
+
+    /*
+     * A file shared by all ROMs, e.g. user settings.
+     */
+    pub fn file(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    fn path(&self, rom_hash: &str, extension: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", rom_hash, extension))
+    }
+
+    pub fn read(&self, rom_hash: &str, extension: &str) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.path(rom_hash, extension)) {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn write(&self, rom_hash: &str, extension: &str, data: &[u8]) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        fs::write(self.path(rom_hash, extension), data)
+    }
+}
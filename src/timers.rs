@@ -0,0 +1,124 @@
+use std::time::Duration;
+
+pub const TIMER_FREQUENCY: u32 = 60;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/*
+ * The delay and sound timers, both counting down at 60 Hz.
+ *
+ * Elapsed time is accumulated in nanoseconds scaled by the timer
+ * frequency, so a period of exactly 1/60 s can be tracked without
+ * rounding errors no matter how irregular the deltas are.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Timers {
+    pub delay: u8,
+    pub sound: u8,
+    elapsed: u128
+}
+
+impl Timers {
+
+    pub fn new() -> Self {
+        Timers::default()
+    }
+
+    /*
+     * Advance by a wall-clock delta, decrementing once for every whole
+     * 1/60 s that has elapsed and carrying the remainder.
+     */
+    pub fn update(&mut self, delta: Duration) {
+        self.elapsed += delta.as_nanos() * TIMER_FREQUENCY as u128;
+
+        while self.elapsed >= NANOS_PER_SEC {
+            self.elapsed -= NANOS_PER_SEC;
+            self.step_frame();
+        }
+    }
+
+    /*
+     * Advance by exactly one 60 Hz period.
+     */
+    pub fn step_frame(&mut self) {
+        self.delay = self.delay.saturating_sub(1);
+        self.sound = self.sound.saturating_sub(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn timers(delay: u8, sound: u8) -> Timers {
+        Timers { delay, sound, ..Timers::new() }
+    }
+
+    #[test]
+    fn step_frame() {
+        let mut t = timers(2, 1);
+
+        t.step_frame();
+        assert_eq!((t.delay, t.sound), (1, 0));
+
+        t.step_frame();
+        assert_eq!((t.delay, t.sound), (0, 0));
+    }
+
+    #[test]
+    fn update_one_second() {
+        let mut t = timers(100, 200);
+
+        t.update(Duration::from_secs(1));
+        assert_eq!((t.delay, t.sound), (40, 140));
+    }
+
+    #[test]
+    fn update_carries_remainder() {
+        let mut t = timers(100, 100);
+
+        t.update(Duration::from_millis(10));
+        assert_eq!(t.delay, 100);
+
+        // 10 + 10 ms crosses the first period
+        t.update(Duration::from_millis(10));
+        assert_eq!(t.delay, 99);
+
+        // 20 + 13 ms is still short of two periods (33.3 ms)
+        t.update(Duration::from_millis(13));
+        assert_eq!(t.delay, 99);
+
+        t.update(Duration::from_micros(400));
+        assert_eq!(t.delay, 98);
+    }
+
+    #[test]
+    fn update_irregular_deltas() {
+        let mut t = timers(255, 255);
+        let deltas = [3, 17, 40, 1, 9, 250, 16, 17, 33, 1000, 2, 112];
+
+        let mut total = 0;
+        for ms in deltas {
+            total += ms;
+            t.update(Duration::from_millis(ms));
+
+            let expected = 255 - (total * 60 / 1000).min(255) as u8;
+            assert_eq!(t.delay, expected, "after {} ms", total);
+        }
+    }
+
+    #[test]
+    fn update_never_drifts() {
+        let mut t = timers(255, 0);
+        let period = Duration::from_secs(1) / TIMER_FREQUENCY;
+
+        // 16_666_666 ns per step falls a little short of a real period
+        for _ in 0..120 {
+            t.update(period);
+        }
+        t.update(Duration::from_nanos(80));
+
+        assert_eq!(t.delay, 255 - 120);
+    }
+}
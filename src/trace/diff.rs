@@ -0,0 +1,431 @@
+use std::fmt;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use super::{TRACE_MAGIC, TRACE_VERSION};
+use crate::error::{ExecutionError, TraceError};
+use crate::instruction::decode;
+use crate::processor::Processor;
+use crate::scheduler::{Scheduler, Speed, DEFAULT_CPU_HZ, FRAME_RATE};
+
+// pc, opcode, I and the changed mask
+const BINARY_ENTRY_SIZE: usize = 14;
+
+/*
+ * Whether the registers in a reference trace hold the state before the
+ * instruction at PC runs, or the state it leaves behind.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Timing {
+    Before,
+    #[default]
+    After
+}
+
+/*
+ * One instruction of a reference trace. Anything the other emulator
+ * doesn't log is None and isn't compared.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ReferenceStep {
+    pub line: usize,
+    pub pc: Option<usize>,
+    pub opcode: Option<u16>,
+    pub v: [Option<u8>; 16],
+    pub i: Option<usize>,
+    pub sp: Option<usize>,
+    pub dt: Option<u8>,
+    pub st: Option<u8>
+}
+
+/*
+ * The machine state compared against a reference step, pc and opcode
+ * are those of the instruction the step belongs to.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MachineState {
+    pub pc: usize,
+    pub opcode: u16,
+    pub v: [u8; 16],
+    pub i: usize,
+    pub stack: Vec<usize>,
+    pub dt: u8,
+    pub st: u8
+}
+
+impl MachineState {
+
+    pub fn capture(processor: &Processor) -> Self {
+        let pc = processor.pc();
+        let timers = processor.timers();
+
+        MachineState {
+            pc,
+            opcode: processor.opcode_at(pc).unwrap_or_default(),
+            v: *processor.reg_v(),
+            i: processor.reg_i(),
+            stack: processor.stack().to_vec(),
+            dt: timers.delay,
+            st: timers.sound
+        }
+    }
+}
+
+impl ReferenceStep {
+
+    /*
+     * Names of the fields that differ from the emulator's state.
+     */
+    pub fn mismatches(&self, state: &MachineState) -> Vec<String> {
+        fn differs<T: PartialEq>(expected: Option<T>, actual: T) -> bool {
+            expected.is_some_and(|expected| expected != actual)
+        }
+
+        let mut fields = Vec::new();
+        if differs(self.pc, state.pc) {
+            fields.push(String::from("PC"));
+        }
+        if differs(self.opcode, state.opcode) {
+            fields.push(String::from("opcode"));
+        }
+        for (n, &expected) in self.v.iter().enumerate() {
+            if differs(expected, state.v[n]) {
+                fields.push(format!("V{:X}", n));
+            }
+        }
+        if differs(self.i, state.i) {
+            fields.push(String::from("I"));
+        }
+        if differs(self.sp, state.stack.len()) {
+            fields.push(String::from("SP"));
+        }
+        if differs(self.dt, state.dt) {
+            fields.push(String::from("DT"));
+        }
+        if differs(self.st, state.st) {
+            fields.push(String::from("ST"));
+        }
+        fields
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let key = key.to_ascii_uppercase();
+        let parse = |max: usize| {
+            usize::from_str_radix(value.trim_start_matches("0x").trim_start_matches("0X"), 16)
+                .ok()
+                .filter(|&value| value <= max)
+                .ok_or_else(|| format!("invalid value '{}' for {}", value, key))
+        };
+
+        match key.as_str() {
+            "PC" => self.pc = Some(parse(0xffff)?),
+            "OP" | "OPCODE" => self.opcode = Some(parse(0xffff)? as u16),
+            "I" => self.i = Some(parse(0xffff)?),
+            "SP" => self.sp = Some(parse(0xff)?),
+            "DT" => self.dt = Some(parse(0xff)? as u8),
+            "ST" => self.st = Some(parse(0xff)? as u8),
+            _ => {
+                let register = key.strip_prefix('V').and_then(|n| u8::from_str_radix(n, 16).ok()).filter(|&n| n < 16);
+                if let Some(n) = register {
+                    self.v[n as usize] = Some(parse(0xff)? as u8);
+                }
+                // anything else, e.g. a cycle count, is ignored
+            }
+        }
+        Ok(())
+    }
+
+    /*
+     * `KEY=VALUE` or `KEY:VALUE` fields in hex, in any order, e.g.
+     * "PC=0204 OP=8016 V0=01 VF=00 I=300". Like the trace text format, a
+     * line may also start with the bare pc and opcode.
+     */
+    fn parse_text(line: usize, text: &str) -> Result<Self, TraceError> {
+        let mut step = ReferenceStep { line, ..ReferenceStep::default() };
+        let error = |message: String| TraceError { line, message };
+
+        let mut bare = 0;
+        let tokens = text.split(|c: char| c.is_whitespace() || c == ',').filter(|t| !t.is_empty());
+        for (index, token) in tokens.enumerate() {
+            match token.split_once(['=', ':']) {
+                Some((key, value)) => step.set(key, value).map_err(error)?,
+                None if index == bare && bare < 2 && token.chars().all(|c| c.is_ascii_hexdigit()) => {
+                    step.set(if bare == 0 { "PC" } else { "OP" }, token).map_err(error)?;
+                    bare += 1;
+                }
+                // the mnemonic
+                None => {}
+            }
+        }
+
+        if step.pc.is_none() {
+            return Err(error(String::from("no PC on this line")));
+        }
+        Ok(step)
+    }
+}
+
+/*
+ * Read a reference trace, either the binary trace format or text with
+ * one instruction per line. Blank lines and lines starting with '#' or
+ * ';' are skipped.
+ */
+pub fn parse_reference(data: &[u8]) -> Result<Vec<ReferenceStep>, TraceError> {
+    if data.starts_with(TRACE_MAGIC) {
+        return parse_binary(data);
+    }
+
+    let text = std::str::from_utf8(data).map_err(|_| TraceError { line: 1, message: String::from("not a text or binary trace") })?;
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with(['#', ';']))
+        .map(|(n, line)| ReferenceStep::parse_text(n + 1, line))
+        .collect()
+}
+
+/*
+ * Binary traces only store the registers that changed, so the full set
+ * is rebuilt by applying every entry in turn.
+ */
+fn parse_binary(data: &[u8]) -> Result<Vec<ReferenceStep>, TraceError> {
+    let header = TRACE_MAGIC.len() + 2;
+    let truncated = |line| TraceError { line, message: String::from("truncated binary trace") };
+
+    let version = data.get(TRACE_MAGIC.len()..header).map(BigEndian::read_u16).ok_or_else(|| truncated(1))?;
+    if version != TRACE_VERSION {
+        return Err(TraceError { line: 1, message: format!("unsupported trace version {}", version) });
+    }
+
+    let mut steps = Vec::new();
+    let mut v = [0u8; 16];
+    let mut offset = header;
+
+    while offset < data.len() {
+        let line = steps.len() + 1;
+        let entry = data.get(offset..offset + BINARY_ENTRY_SIZE).ok_or_else(|| truncated(line))?;
+        let changed = BigEndian::read_u16(&entry[12..]);
+        offset += BINARY_ENTRY_SIZE;
+
+        for n in (0..16).filter(|n| changed & 1 << n != 0) {
+            v[n] = *data.get(offset).ok_or_else(|| truncated(line))?;
+            offset += 1;
+        }
+
+        steps.push(ReferenceStep {
+            line,
+            pc: Some(BigEndian::read_u32(&entry[0..]) as usize),
+            opcode: Some(BigEndian::read_u16(&entry[4..])),
+            v: v.map(Some),
+            i: Some(BigEndian::read_u32(&entry[8..]) as usize),
+            ..ReferenceStep::default()
+        });
+    }
+
+    Ok(steps)
+}
+
+/*
+ * The first step where the emulator and the reference disagree, or where
+ * the emulator faulted before the reference ran out.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub step: usize,
+    pub expected: ReferenceStep,
+    pub actual: MachineState,
+    pub mismatches: Vec<String>,
+    pub error: Option<ExecutionError>
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffOutcome {
+    Matched(usize),
+    Diverged(Box<Divergence>)
+}
+
+/*
+ * Run the processor against a reference trace one instruction at a time,
+ * stepping the timers once a frame as the scheduler would.
+ */
+pub fn diff(processor: &mut Processor, reference: &[ReferenceStep], speed: Speed, timing: Timing) -> DiffOutcome {
+    let mut scheduler = Scheduler::new(speed);
+    let mut step = 0;
+
+    while step < reference.len() {
+        let cycles = scheduler.cycles_for_frame().unwrap_or(DEFAULT_CPU_HZ / FRAME_RATE);
+
+        for _ in 0..cycles {
+            let Some(expected) = reference.get(step) else { break };
+            let before = MachineState::capture(processor);
+            let diverged = |actual: MachineState, error| {
+                let mismatches = expected.mismatches(&actual);
+                DiffOutcome::Diverged(Box::new(Divergence { step, expected: expected.clone(), actual, mismatches, error }))
+            };
+
+            if timing == Timing::Before && !expected.mismatches(&before).is_empty() {
+                return diverged(before, None);
+            }
+
+            if let Err(err) = processor.tick([false; 16]) {
+                return diverged(before, Some(err));
+            }
+
+            if timing == Timing::After {
+                let after = MachineState { pc: before.pc, opcode: before.opcode, ..MachineState::capture(processor) };
+                if !expected.mismatches(&after).is_empty() {
+                    return diverged(after, None);
+                }
+            }
+
+            step += 1;
+        }
+
+        processor.step_timers();
+    }
+
+    DiffOutcome::Matched(step)
+}
+
+impl fmt::Display for Divergence {
+
+    /*
+     * Both sides of the divergence field by field, differences marked.
+     */
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let actual = &self.actual;
+        let expected = &self.expected;
+
+        match self.error {
+            Some(err) => writeln!(f, "emulator halted at step {} (reference line {}): {}", self.step + 1, expected.line, err)?,
+            None => writeln!(f, "diverged at step {} (reference line {}): {} differ", self.step + 1, expected.line, self.mismatches.join(", "))?,
+        }
+        let mnemonic = decode(actual.opcode).map_or_else(|| String::from("???"), |instruction| instruction.to_string());
+        writeln!(f, "instruction {:04X}  {}", actual.opcode, mnemonic)?;
+        writeln!(f)?;
+        writeln!(f, "{:<8}{:<10}reference", "", "emulator")?;
+
+        let mut row = |name: &str, actual: String, expected: Option<String>| {
+            let marker = if self.mismatches.iter().any(|field| field == name) { "  <" } else { "" };
+            writeln!(f, "{:<8}{:<10}{}{}", name, actual, expected.unwrap_or_else(|| String::from("-")), marker)
+        };
+
+        row("PC", format!("{:04X}", actual.pc), expected.pc.map(|pc| format!("{:04X}", pc)))?;
+        row("opcode", format!("{:04X}", actual.opcode), expected.opcode.map(|op| format!("{:04X}", op)))?;
+        for n in 0..16 {
+            row(&format!("V{:X}", n), format!("{:02X}", actual.v[n]), expected.v[n].map(|v| format!("{:02X}", v)))?;
+        }
+        row("I", format!("{:03X}", actual.i), expected.i.map(|i| format!("{:03X}", i)))?;
+        row("SP", actual.stack.len().to_string(), expected.sp.map(|sp| sp.to_string()))?;
+        row("DT", format!("{:02X}", actual.dt), expected.dt.map(|dt| format!("{:02X}", dt)))?;
+        row("ST", format!("{:02X}", actual.st), expected.st.map(|st| format!("{:02X}", st)))?;
+
+        let stack: Vec<String> = actual.stack.iter().map(|addr| format!("{:03X}", addr)).collect();
+        write!(f, "{:<8}{}", "stack", if stack.is_empty() { String::from("-") } else { stack.join(" ") })
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::quirks::Quirks;
+    use crate::trace::{TraceFilter, TraceFormat, Tracer};
+
+    // LD V0, 3; LD V1, 1; SHR V0, V1; LD I, 0x300; ADD V2, 1
+    const ROM: [u8; 10] = [0x60, 0x03, 0x61, 0x01, 0x80, 0x16, 0xa3, 0x00, 0x72, 0x01];
+
+    fn run(reference: &str, quirks: Quirks, timing: Timing) -> DiffOutcome {
+        let reference = parse_reference(reference.as_bytes()).unwrap();
+        let mut p = Processor::new(quirks);
+        p.load(&ROM);
+        diff(&mut p, &reference, Speed::default(), timing)
+    }
+
+    #[test]
+    fn parse_text() {
+        let steps = parse_reference(b"# header\nPC=0204 OP=8016 v0=00, VF:01 I=0x300 SP=0 cycle=5\n\n0206  A300  LD I, 0x300  I=300 V1=01\n").unwrap();
+
+        assert_eq!(steps.len(), 2);
+        assert_eq!(steps[0].line, 2);
+        assert_eq!(steps[0].pc, Some(0x204));
+        assert_eq!(steps[0].opcode, Some(0x8016));
+        assert_eq!(steps[0].v[0], Some(0));
+        assert_eq!(steps[0].v[0xf], Some(1));
+        assert_eq!(steps[0].v[1], None);
+        assert_eq!(steps[0].i, Some(0x300));
+        assert_eq!(steps[0].sp, Some(0));
+        assert_eq!(steps[1].pc, Some(0x206));
+        assert_eq!(steps[1].opcode, Some(0xa300));
+        assert_eq!(steps[1].v[1], Some(1));
+
+        assert_eq!(parse_reference(b"PC=0200\nV0=1").err().unwrap().line, 2);
+        assert_eq!(parse_reference(b"PC=0200 V0=100").err().unwrap().message, "invalid value '100' for V0");
+    }
+
+    #[test]
+    fn matching_trace() {
+        let reference = "PC=200 V0=03\nPC=202 V1=01\nPC=204 V0=00 VF=01\nPC=206 I=300\n";
+        assert_eq!(run(reference, Quirks::default(), Timing::After), DiffOutcome::Matched(4));
+
+        let reference = "PC=200 V0=00\nPC=202 V0=03\nPC=204 V1=01\n";
+        assert_eq!(run(reference, Quirks::default(), Timing::Before), DiffOutcome::Matched(3));
+    }
+
+    #[test]
+    fn shift_quirk_divergence() {
+        // SUPER-CHIP shifts VX in place, so V0 ends up 1 instead of 0
+        let reference = "PC=200 V0=03\nPC=202 V1=01\nPC=204 V0=00 VF=01\n";
+        let DiffOutcome::Diverged(divergence) = run(reference, Quirks::preset("schip").unwrap(), Timing::After) else {
+            panic!("expected a divergence");
+        };
+
+        assert_eq!(divergence.step, 2);
+        assert_eq!(divergence.actual.pc, 0x204);
+        assert_eq!(divergence.actual.v[0], 1);
+        assert_eq!(divergence.mismatches, ["V0"]);
+
+        let report = divergence.to_string();
+        assert!(report.starts_with("diverged at step 3 (reference line 3): V0 differ\ninstruction 8016  SHR V0, V1\n"));
+        assert!(report.contains("\nV0      01        00  <\n"));
+        assert!(report.contains("\nV1      01        -\n"));
+    }
+
+    #[test]
+    fn execution_error() {
+        let reference = parse_reference(b"PC=200\nPC=202\n").unwrap();
+        let mut p = Processor::new(Quirks::default());
+        p.load(&[0x60, 0x01, 0xff, 0xff]);
+
+        let DiffOutcome::Diverged(divergence) = diff(&mut p, &reference, Speed::default(), Timing::After) else {
+            panic!("expected a divergence");
+        };
+        assert_eq!(divergence.step, 1);
+        assert!(divergence.error.is_some());
+    }
+
+    #[test]
+    fn binary_reference() {
+        let path = std::env::temp_dir().join(format!("chip8-trace-diff-{}", std::process::id()));
+
+        let mut p = Processor::new(Quirks::default());
+        p.load(&ROM);
+        p.set_tracer(Some(Tracer::create(&path, TraceFormat::Binary, TraceFilter::default()).unwrap()));
+        for _ in 0..5 {
+            p.tick([false; 16]).unwrap();
+        }
+        p.take_tracer().unwrap().finish().unwrap();
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let reference = parse_reference(&data).unwrap();
+        assert_eq!(reference.len(), 5);
+        assert_eq!(reference[4].v[0xf], Some(1));
+        assert_eq!(reference[4].v[2], Some(1));
+
+        let mut p = Processor::new(Quirks::default());
+        p.load(&ROM);
+        assert_eq!(diff(&mut p, &reference, Speed::default(), Timing::After), DiffOutcome::Matched(5));
+
+        assert_eq!(parse_reference(&data[..data.len() - 1]).err().unwrap().message, "truncated binary trace");
+    }
+}
@@ -0,0 +1,323 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use byteorder::{BigEndian, WriteBytesExt};
+
+use crate::instruction::{decode, Instruction};
+
+mod diff;
+
+pub use self::diff::{diff, parse_reference, DiffOutcome, Divergence, MachineState, ReferenceStep, Timing};
+
+const TRACE_MAGIC: &[u8; 4] = b"C8TR";
+const TRACE_VERSION: u16 = 2;
+
+/*
+ * The state after one executed instruction. `changed` has a bit set for
+ * every V register the instruction modified, V0 in the lowest bit.
+ * `operand` is the word following an XO-CHIP F000 NNNN.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub pc: usize,
+    pub opcode: u16,
+    pub operand: Option<u16>,
+    pub v: [u8; 16],
+    pub i: usize,
+    pub changed: u16
+}
+
+impl TraceEntry {
+
+    pub fn new(pc: usize, opcode: u16, before: &[u8; 16], after: &[u8; 16], i: usize) -> Self {
+        let changed = (0..16)
+            .filter(|&n| before[n] != after[n])
+            .fold(0, |mask, n| mask | 1 << n);
+
+        TraceEntry { pc, opcode, operand: None, v: *after, i, changed }
+    }
+
+    fn deltas(&self) -> impl Iterator<Item = (usize, u8)> + '_ {
+        (0..16).filter(|n| self.changed & 1 << n != 0).map(|n| (n, self.v[n]))
+    }
+
+    /*
+     * One line per instruction: address, opcode, mnemonic, I and the
+     * registers that changed, e.g.
+     * 0204  8016  SHR V0, V1            I=000 V0=00 VF=01
+     */
+    pub fn to_text(&self) -> String {
+        let mnemonic = match (decode(self.opcode), self.operand) {
+            (Some(Instruction::LdILong), Some(operand)) => format!("LD I, {:#06X}", operand),
+            (Some(instruction), _) => instruction.to_string(),
+            (None, _) => String::from("???"),
+        };
+        let mut line = format!("{:04X}  {:04X}  {:<20}  I={:03X}", self.pc, self.opcode, mnemonic, self.i);
+
+        for (n, value) in self.deltas() {
+            line.push_str(&format!(" V{:X}={:02X}", n, value));
+        }
+        line
+    }
+
+    /*
+     * Big endian pc (32 bits), opcode, long operand or 0, I (32 bits) and
+     * the changed mask, followed by one byte for each changed register in
+     * ascending order.
+     */
+    fn write_binary(&self, out: &mut dyn Write) -> io::Result<()> {
+        out.write_u32::<BigEndian>(self.pc as u32)?;
+        out.write_u16::<BigEndian>(self.opcode)?;
+        out.write_u16::<BigEndian>(self.operand.unwrap_or_default())?;
+        out.write_u32::<BigEndian>(self.i as u32)?;
+        out.write_u16::<BigEndian>(self.changed)?;
+        for (_, value) in self.deltas() {
+            out.write_u8(value)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TraceFormat {
+    #[default]
+    Text,
+    Binary
+}
+
+impl FromStr for TraceFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(TraceFormat::Text),
+            "binary" | "bin" => Ok(TraceFormat::Binary),
+            _ => Err(format!("unknown trace format '{}', expected 'text' or 'binary'", s)),
+        }
+    }
+}
+
+/*
+ * Which instructions make it into the trace. `classes` has a bit set for
+ * every opcode class (the high nibble) to keep, and `limit` stops the
+ * trace after that many instructions have been written.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceFilter {
+    pub start: usize,
+    pub end: usize,
+    pub classes: u16,
+    pub limit: Option<u64>
+}
+
+impl Default for TraceFilter {
+    fn default() -> Self {
+        TraceFilter {
+            start: 0,
+            end: usize::MAX,
+            classes: 0xffff,
+            limit: None
+        }
+    }
+}
+
+impl TraceFilter {
+
+    fn matches(&self, entry: &TraceEntry) -> bool {
+        let class = entry.opcode >> 12;
+        (self.start..=self.end).contains(&entry.pc) && self.classes & 1 << class != 0
+    }
+
+    /*
+     * An inclusive hex address range, e.g. "200-2ff".
+     */
+    pub fn parse_range(s: &str) -> Result<(usize, usize), String> {
+        let invalid = || format!("invalid address range '{}', expected e.g. 200-2ff", s);
+        let (start, end) = s.split_once('-').ok_or_else(invalid)?;
+        let parse = |addr: &str| usize::from_str_radix(addr.trim().trim_start_matches("0x"), 16).map_err(|_| invalid());
+        let (start, end) = (parse(start)?, parse(end)?);
+
+        if start > end {
+            return Err(invalid());
+        }
+        Ok((start, end))
+    }
+
+    /*
+     * A comma separated list of opcode classes as hex digits, e.g. "8,d".
+     */
+    pub fn parse_classes(s: &str) -> Result<u16, String> {
+        s.split(',').try_fold(0, |mask, class| {
+            match u8::from_str_radix(class.trim(), 16) {
+                Ok(class) if class < 16 => Ok(mask | 1 << class),
+                _ => Err(format!("invalid opcode class '{}', expected a hex digit 0-f", class)),
+            }
+        })
+    }
+}
+
+/*
+ * Writes a TraceEntry for every instruction the processor executes. Write
+ * errors don't stop emulation, the first one is kept and returned by finish.
+ */
+pub struct Tracer {
+    out: Box<dyn Write + Send>,
+    format: TraceFormat,
+    filter: TraceFilter,
+    written: u64,
+    error: Option<io::Error>
+}
+
+impl Tracer {
+
+    pub fn new(mut out: Box<dyn Write + Send>, format: TraceFormat, filter: TraceFilter) -> io::Result<Self> {
+        if format == TraceFormat::Binary {
+            out.write_all(TRACE_MAGIC)?;
+            out.write_u16::<BigEndian>(TRACE_VERSION)?;
+        }
+
+        Ok(Tracer {
+            out,
+            format,
+            filter,
+            written: 0,
+            error: None
+        })
+    }
+
+    pub fn create<P: AsRef<Path>>(path: P, format: TraceFormat, filter: TraceFilter) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Tracer::new(Box::new(BufWriter::new(file)), format, filter)
+    }
+
+    pub fn record(&mut self, entry: &TraceEntry) {
+        if self.error.is_some() || !self.filter.matches(entry) {
+            return;
+        }
+        if self.filter.limit.is_some_and(|limit| self.written >= limit) {
+            return;
+        }
+
+        let result = match self.format {
+            TraceFormat::Text => writeln!(self.out, "{}", entry.to_text()),
+            TraceFormat::Binary => entry.write_binary(&mut self.out),
+        };
+        match result {
+            Ok(()) => self.written += 1,
+            Err(err) => self.error = Some(err),
+        }
+    }
+
+    /*
+     * Flush the trace and report any error hit while writing it.
+     */
+    pub fn finish(mut self) -> io::Result<()> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::processor::Processor;
+    use crate::quirks::Quirks;
+    use std::fs;
+
+    // LD V0, 3; LD V1, 1; SHR V0, V1; LD I, 0x300; ADD V2, 1
+    const ROM: [u8; 10] = [0x60, 0x03, 0x61, 0x01, 0x80, 0x16, 0xa3, 0x00, 0x72, 0x01];
+
+    // `name` keeps the files of tests running in parallel apart
+    fn trace(name: &str, format: TraceFormat, filter: TraceFilter) -> Vec<u8> {
+        let path = std::env::temp_dir().join(format!("chip8-trace-{}-{}", std::process::id(), name));
+
+        let mut p = Processor::new(Quirks::default());
+        p.load(&ROM);
+        p.set_tracer(Some(Tracer::create(&path, format, filter).unwrap()));
+        for _ in 0..5 {
+            p.tick([false; 16]).unwrap();
+        }
+        p.take_tracer().unwrap().finish().unwrap();
+
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        data
+    }
+
+    #[test]
+    fn entry_deltas() {
+        let before = [0; 16];
+        let mut after = [0; 16];
+        after[0] = 1;
+        after[0xf] = 1;
+
+        let entry = TraceEntry::new(0x204, 0x8016, &before, &after, 0);
+        assert_eq!(entry.changed, 0x8001);
+        assert_eq!(entry.to_text(), "0204  8016  SHR V0, V1            I=000 V0=01 VF=01");
+    }
+
+    #[test]
+    fn text_trace() {
+        let text = String::from_utf8(trace("text_trace", TraceFormat::Text, TraceFilter::default())).unwrap();
+        let lines: Vec<&str> = text.lines().map(str::trim_end).collect();
+
+        assert_eq!(lines, [
+            "0200  6003  LD V0, 0x03           I=000 V0=03",
+            "0202  6101  LD V1, 0x01           I=000 V1=01",
+            "0204  8016  SHR V0, V1            I=000 V0=00 VF=01",
+            "0206  A300  LD I, 0x300           I=300",
+            "0208  7201  ADD V2, 0x01          I=300 V2=01",
+        ]);
+    }
+
+    #[test]
+    fn binary_trace() {
+        let filter = TraceFilter { classes: TraceFilter::parse_classes("8,a").unwrap(), ..TraceFilter::default() };
+        let data = trace("binary_trace", TraceFormat::Binary, filter);
+
+        assert_eq!(&data[..6], b"C8TR\x00\x02");
+        assert_eq!(&data[6..], [
+            0x00, 0x00, 0x02, 0x04, 0x80, 0x16, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x01, 0x00, 0x01,
+            0x00, 0x00, 0x02, 0x06, 0xa3, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00,
+        ]);
+    }
+
+    #[test]
+    fn long_load() {
+        let path = std::env::temp_dir().join(format!("chip8-trace-{}-long_load", std::process::id()));
+
+        // LD I, long 0xfff0 in 64 KiB of memory
+        let mut p = Processor::new(Quirks::XO_CHIP);
+        p.load(&[0xf0, 0x00, 0xff, 0xf0]);
+        p.set_tracer(Some(Tracer::create(&path, TraceFormat::Binary, TraceFilter::default()).unwrap()));
+        p.tick([false; 16]).unwrap();
+        p.take_tracer().unwrap().finish().unwrap();
+
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(&data[6..], [0x00, 0x00, 0x02, 0x00, 0xf0, 0x00, 0xff, 0xf0, 0x00, 0x00, 0xff, 0xf0, 0x00, 0x00]);
+
+        let entry = TraceEntry { operand: Some(0xfff0), i: 0xfff0, ..TraceEntry::new(0x200, 0xf000, &[0; 16], &[0; 16], 0) };
+        assert_eq!(entry.to_text(), "0200  F000  LD I, 0xFFF0          I=FFF0");
+    }
+
+    #[test]
+    fn filters() {
+        let filter = TraceFilter { start: 0x202, end: 0x206, limit: Some(2), ..TraceFilter::default() };
+        let text = String::from_utf8(trace("filters", TraceFormat::Text, filter)).unwrap();
+
+        assert_eq!(text.lines().count(), 2);
+        assert!(text.starts_with("0202"));
+
+        assert_eq!(TraceFilter::parse_range("200-2ff"), Ok((0x200, 0x2ff)));
+        assert!(TraceFilter::parse_range("2ff-200").is_err());
+        assert!(TraceFilter::parse_range("200").is_err());
+        assert_eq!(TraceFilter::parse_classes("0,D"), Ok(0x2001));
+        assert!(TraceFilter::parse_classes("10").is_err());
+    }
+}